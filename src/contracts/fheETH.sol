@@ -40,45 +40,73 @@ contract FHEToken is ERC20 {
     /**
      * @dev Emitted when a user sends a transaction
      * @param from The address of the user who sent the transaction
+     * @param to The address of the user who receives the transaction
      * @param fhe_tx_hash The hash of the transaction that acts as the transaction id
      * @param fhe_tx_sender The sender_fhe_tx  of the transaction (this is like the return transaction and sends the tokens back to the sender)
      * @param fhe_tx_receiver The receiver_fhe_tx of the transaction
      * @param fhe_proof The proof of the transaction
+     * @param nonce The position of the transaction among the signed transactions of its sender
+     * @param fhe_signature The sender's signature over the transaction, empty if the sender sent it directly
      */
     event Send_fhe_tx(
         address indexed from,
+        address indexed to,
         bytes32 fhe_tx_hash,
         string fhe_tx_sender,
         string fhe_tx_receiver,
-        string fhe_proof
+        string fhe_proof,
+        uint256 nonce,
+        bytes fhe_signature
+    );
+
+    /**
+     * @dev Emitted when a user moves their fhe_account to a new fhe public key
+     * @param from The address of the user who rotated their key
+     * @param fhe_tx_hash The hash of the rotation that acts as its id
+     * @param fhe_pk The new fhe public key of the user
+     * @param fhe_tx_sender The encryption of the whole balance under the old key, debited by the fhe_node
+     * @param fhe_balance The encryption of the same balance under the new key
+     * @param fhe_proof The proof that both encryptions hold the same balance (verified by the fhe_node)
+     * @param nonce The position of the rotation among the signed transactions of its sender
+     * @param fhe_signature The sender's signature over the rotation, empty if the sender sent it directly
+     */
+    event Rotate_fhe_key(
+        address indexed from,
+        bytes32 fhe_tx_hash,
+        string fhe_pk,
+        string fhe_tx_sender,
+        string fhe_balance,
+        string fhe_proof,
+        uint256 nonce,
+        bytes fhe_signature
     );
 
     /**
      * @dev Emitted when a user requests for a withdrawal
      * @param to The address of the user who requested the withdrawal
      * @param amount The amount of ETH requested to be withdrawn
-     * @param fhe_pk_new The new fhe public key of the user
-     * @param fhe_sk_old The old fhe secret key of the user
+     * @param fhe_tx_hash The hash of the request that acts as its id
+     * @param fhe_tx_sender The encryption of the amount that is debited from the fhe_account
+     * @param fhe_proof The proof that fhe_tx_sender encrypts amount (verified by the fhe_node)
      */
     event Withdraw_ETH_Request(
         address indexed to,
         uint256 amount,
-        string fhe_pk_new,
-        string fhe_sk_old,
-        string fhe_new_balance
+        bytes32 fhe_tx_hash,
+        string fhe_tx_sender,
+        string fhe_proof
     );
 
     /**
      * @dev Emitted when a user withdraws ETH
      * @param to The address of the user who withdrew ETH
      * @param amount The amount of ETH withdrawn
-     * @param fhe_pk_new The new fhe public key of the user
+     * @param fhe_tx_hash The id of the request that was paid out
      */
     event Withdraw_ETH_Approved(
         address indexed to,
         uint256 amount,
-        string fhe_pk_new,
-        string fhe_new_balance
+        bytes32 fhe_tx_hash
     );
 
     address payable public owner;
@@ -122,14 +150,20 @@ contract FHEToken is ERC20 {
 
     /**
      * @dev Sends a transaction to the fhe_account
+     * @param _receiver The address whose fhe_account receives the transaction
      * @param _fhe_tx_sender The sender_fhe_tx of the transaction  (generated by the user's node)
      * @param _fhe_tx_receiver The receiver_fhe_tx of the transaction (generated by the user's node)
      * @param _fhe_proof The proof of the transaction (generated by the user's node and verified by the fhe_node)
+     * @param _nonce The nonce the sender signed the transaction with
+     * @param _fhe_signature The sender's signature, lets anyone relay the transaction (verified by the fhe_node)
      */
     function send_fhe_tx(
+        address _receiver,
         string calldata _fhe_tx_sender,
         string calldata _fhe_tx_receiver,
-        string calldata _fhe_proof
+        string calldata _fhe_proof,
+        uint256 _nonce,
+        bytes calldata _fhe_signature
     ) external payable onlyUser onlyValidFees {
         // generate the hash of the transaction
         bytes32 _fhe_tx_hash = keccak256(
@@ -143,50 +177,88 @@ contract FHEToken is ERC20 {
 
         emit Send_fhe_tx(
             msg.sender,
+            _receiver,
             _fhe_tx_hash,
             _fhe_tx_sender,
             _fhe_tx_receiver,
-            _fhe_proof
+            _fhe_proof,
+            _nonce,
+            _fhe_signature
         );
     }
 
     /**
-     * @dev Transfers the ETH from the fhe_account to the msg.sender
-     * @param _amount The amount of ETH to be withdrawn. This needs to be equal to the tokens owner in the fhe_account with _sk as the secret key
-     * @param _fhe_sk The secret key used to encrypt the new public key
-     * @param _new_fhe_pk The new public key to be used for future transactions
-     * @param _fhe_new_balance The new balance of the user in the fhe_account
+     * @dev Moves the msg.sender's fhe_account to a new fhe public key, keeping its balance
+     * @param _fhe_pk The new fhe public key of the user
+     * @param _fhe_tx_sender The encryption of the whole balance under the old key (generated by the user's node)
+     * @param _fhe_balance The encryption of the same balance under _fhe_pk (generated by the user's node)
+     * @param _fhe_proof The proof that both encryptions hold the same balance (verified by the fhe_node)
+     * @param _nonce The nonce the sender signed the rotation with
+     * @param _fhe_signature The sender's signature, lets anyone relay the rotation (verified by the fhe_node)
+     */
+    function rotate_fhe_key(
+        string calldata _fhe_pk,
+        string calldata _fhe_tx_sender,
+        string calldata _fhe_balance,
+        string calldata _fhe_proof,
+        uint256 _nonce,
+        bytes calldata _fhe_signature
+    ) external payable onlyUser onlyValidFees {
+        // generate the hash of the rotation
+        bytes32 _fhe_tx_hash = keccak256(
+            abi.encodePacked(
+                msg.sender,
+                _fhe_tx_sender,
+                _fhe_balance,
+                block.number
+            )
+        );
+
+        emit Rotate_fhe_key(
+            msg.sender,
+            _fhe_tx_hash,
+            _fhe_pk,
+            _fhe_tx_sender,
+            _fhe_balance,
+            _fhe_proof,
+            _nonce,
+            _fhe_signature
+        );
+    }
+
+    /**
+     * @dev Requests ETH out of the msg.sender's fhe_account
+     * @param _amount The amount of ETH to be withdrawn
+     * @param _fhe_tx_sender The encryption of _amount under the user's fhe public key, debited by the fhe_node
+     * @param _fhe_proof The proof that _fhe_tx_sender encrypts _amount (generated by the user's node and verified by the fhe_node)
      */
     function withdraw_ETH_request(
         uint256 _amount,
-        string calldata _fhe_sk,
-        string calldata _new_fhe_pk,
-        string calldata _fhe_new_balance
+        string calldata _fhe_tx_sender,
+        string calldata _fhe_proof
     ) external payable onlyUser onlyValidFees {
+        // generate the hash of the request
+        bytes32 _fhe_tx_hash = keccak256(
+            abi.encodePacked(msg.sender, _fhe_tx_sender, block.number)
+        );
+
         emit Withdraw_ETH_Request(
             msg.sender,
             _amount,
-            _fhe_sk,
-            _new_fhe_pk,
-            _fhe_new_balance
+            _fhe_tx_hash,
+            _fhe_tx_sender,
+            _fhe_proof
         );
     }
 
     function withdraw_ETH_approved(
         address _user,
         uint256 _amount,
-        string calldata _new_fhe_pk,
-        string calldata _fhe_new_balance
+        bytes32 _fhe_tx_hash
     ) external payable onlyOwner {
         payable(_user).transfer(_amount);
 
-        emit Deposit_fETH(_user, 0, _new_fhe_pk, _fhe_new_balance);
-        emit Withdraw_ETH_Approved(
-            _user,
-            _amount,
-            _new_fhe_pk,
-            _fhe_new_balance
-        );
+        emit Withdraw_ETH_Approved(_user, _amount, _fhe_tx_hash);
     }
 
     function changeOwner(address payable _owner) external onlyOwner {
@@ -0,0 +1,384 @@
+use crate::fhe_account_handler::keystore::{random_secret_coeffs, FheKeystore};
+use crate::fhe_node::fhe_assets::decode_balances;
+use crate::fhe_node::fhe_execution::SigningDomain;
+use crate::fhe_node::fhe_oracle::OracleError;
+use crate::fhe_node::fhe_replay::{decode_send_log, OracleEvent, OracleLog, SkippedLog};
+use fhe::bfv::{BfvParameters, Ciphertext, PublicKey, SecretKey};
+use fhe_traits::FheDecrypter;
+use rand::{CryptoRng, RngCore};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// The holder of the auditor key. Reads transfer amounts from the auditor
+/// copies and never needs a user's key.
+pub struct Auditor {
+    pub fhe_sk: SecretKey,
+    pub fhe_pk: PublicKey,
+}
+
+/// One transfer touching an audited address. `amounts` holds one amount per
+/// asset, `None` if the transfer carried no auditor copy.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditedFlow {
+    pub block_number: u64,
+    pub log_index: u64,
+    pub sender: String,
+    pub receiver: String,
+    pub amounts: Option<Vec<u64>>,
+    /// Whether the Oracle applied the transfer. Only then did its proof tie
+    /// the auditor copy to the amount that moved, a refused transfer's copy
+    /// says nothing and moved nothing.
+    pub applied: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FlowTotals {
+    pub sent: Vec<u64>,
+    pub received: Vec<u64>,
+    /// Applied transfers without an auditor copy, left out of the sums.
+    pub unaudited: usize,
+    /// Transfers the Oracle refused, left out of the sums.
+    pub refused: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AuditReport {
+    pub flows: Vec<AuditedFlow>,
+    pub totals: BTreeMap<String, FlowTotals>,
+    /// Send logs that could not be decoded, or whose auditor copy could not
+    /// be decrypted, so nothing about them is known.
+    pub skipped: Vec<SkippedLog>,
+}
+
+impl Auditor {
+    pub fn new<R: RngCore + CryptoRng>(parameters: &Arc<BfvParameters>, rng: &mut R) -> Auditor {
+        // sampled like the user keys, so it can go into an FHE keystore
+        let fhe_sk = SecretKey::new(random_secret_coeffs(parameters, rng), parameters);
+        let fhe_pk = PublicKey::new(&fhe_sk, rng);
+
+        Auditor { fhe_sk, fhe_pk }
+    }
+
+    /// Unlocks an auditor key kept in an FHE keystore.
+    pub fn load(
+        key_path: &str,
+        password: &str,
+        parameters: &Arc<BfvParameters>,
+    ) -> Result<Auditor, Box<dyn std::error::Error>> {
+        let keystore = FheKeystore::load(key_path)?;
+
+        Ok(Auditor {
+            fhe_sk: keystore.decrypt(parameters, password)?,
+            fhe_pk: keystore.public_key(parameters)?,
+        })
+    }
+
+    /// Decrypts the transfers in `logs` that `addresses` sent or received
+    /// and adds up those the Oracle applied per address. `refused` are the
+    /// logs the Oracle left out when it applied `logs`.
+    pub fn report(
+        &self,
+        parameters: &Arc<BfvParameters>,
+        domain: &SigningDomain,
+        logs: &[OracleLog],
+        refused: &[SkippedLog],
+        addresses: &[String],
+    ) -> Result<AuditReport, Box<dyn std::error::Error>> {
+        let mut report = AuditReport::default();
+        for address in addresses.iter() {
+            report.totals.insert(address.clone(), FlowTotals::default());
+        }
+
+        for log in logs.iter() {
+            if !matches!(log.event, OracleEvent::Send { .. }) {
+                continue;
+            }
+
+            let tx = match decode_send_log(parameters, domain, log) {
+                Ok(tx) => tx,
+                Err(error) => {
+                    report.skipped.push(SkippedLog::new(log, error));
+                    continue;
+                }
+            };
+            let sender = audited(addresses, &tx.sender);
+            let receiver = audited(addresses, &tx.receiver);
+            if sender.is_none() && receiver.is_none() {
+                continue;
+            }
+
+            // a copy under another key reads as noise or not at all, it
+            // takes only that log out of the report
+            let amounts = match &tx.tx_auditor {
+                Some(tx_auditor) => match self.read_copy(parameters, tx_auditor) {
+                    Ok(amounts) => Some(amounts),
+                    Err(error) => {
+                        report.skipped.push(SkippedLog::new(log, error));
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            let applied = !refused.iter().any(|refused| {
+                (refused.block_number, refused.log_index) == (log.block_number, log.log_index)
+            });
+
+            for (address, outgoing) in [(sender, true), (receiver, false)] {
+                let totals = match address.and_then(|address| report.totals.get_mut(address)) {
+                    Some(totals) => totals,
+                    None => continue,
+                };
+                match &amounts {
+                    _ if !applied => totals.refused += 1,
+                    Some(amounts) if outgoing => add_amounts(&mut totals.sent, amounts)?,
+                    Some(amounts) => add_amounts(&mut totals.received, amounts)?,
+                    None => totals.unaudited += 1,
+                }
+            }
+
+            report.flows.push(AuditedFlow {
+                block_number: log.block_number,
+                log_index: log.log_index,
+                sender: tx.sender,
+                receiver: tx.receiver,
+                amounts,
+                applied,
+            });
+        }
+
+        Ok(report)
+    }
+
+    fn read_copy(
+        &self,
+        parameters: &Arc<BfvParameters>,
+        tx_auditor: &Ciphertext,
+    ) -> Result<Vec<u64>, Box<dyn std::error::Error>> {
+        Ok(decode_balances(
+            parameters,
+            &self.fhe_sk.try_decrypt(tx_auditor)?,
+        )?)
+    }
+}
+
+// the entry of `addresses` that is `address`, however it is cased
+fn audited<'a>(addresses: &'a [String], address: &str) -> Option<&'a String> {
+    addresses
+        .iter()
+        .find(|audited| audited.eq_ignore_ascii_case(address))
+}
+
+fn add_amounts(totals: &mut Vec<u64>, amounts: &[u64]) -> Result<(), OracleError> {
+    if totals.len() < amounts.len() {
+        totals.resize(amounts.len(), 0);
+    }
+    for (total, amount) in totals.iter_mut().zip(amounts) {
+        *total = total
+            .checked_add(*amount)
+            .ok_or(OracleError::Unrepresentable(*amount))?;
+    }
+
+    Ok(())
+}
+
+impl fmt::Display for AuditReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for flow in self.flows.iter() {
+            let amounts = match &flow.amounts {
+                _ if !flow.applied => "refused by the Oracle".to_string(),
+                Some(amounts) => format!("{:?}", amounts),
+                None => "no auditor copy".to_string(),
+            };
+            writeln!(
+                f,
+                "block {} log {}: {} -> {}: {}",
+                flow.block_number, flow.log_index, flow.sender, flow.receiver, amounts
+            )?;
+        }
+        for (address, totals) in self.totals.iter() {
+            writeln!(
+                f,
+                "{}: sent {:?}, received {:?}, {} unaudited, {} refused",
+                address, totals.sent, totals.received, totals.unaudited, totals.refused
+            )?;
+        }
+        for skipped in self.skipped.iter() {
+            writeln!(
+                f,
+                "block {} log {}: skipped, {}",
+                skipped.block_number, skipped.log_index, skipped.reason
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhe_account_handler::get_keys::tests::create_users;
+    use crate::fhe_node::fhe_assets::{encode_balances, ETH};
+    use crate::fhe_node::fhe_execution::Tx;
+    use crate::fhe_node::fhe_proof::{OpeningBackend, TxVerifier};
+    use crate::fhe_node::fhe_replay::replay_logs;
+    use crate::fhe_node::fhe_replay::tests::{deposit_log, fresh_node, send_log};
+    use fhe_traits::FheEncrypter;
+    use rand::thread_rng;
+
+    #[test]
+    fn test_auditor_reads_bound_flows() {
+        let (mut fhe_oracle, alice, bob, owner) = create_users(100, 50);
+        let parameters = fhe_oracle.parameters.clone();
+        let auditor = Auditor::new(&parameters, &mut thread_rng());
+        fhe_oracle.register_auditor(auditor.fhe_pk.clone());
+
+        let to_bob = alice
+            .create_tx(fhe_oracle.users[&bob.address].clone(), &fhe_oracle, 10)
+            .unwrap();
+        let to_owner = bob
+            .create_tx(fhe_oracle.users[&owner.address].clone(), &fhe_oracle, 5)
+            .unwrap();
+        assert!(OpeningBackend.verify(&fhe_oracle, &to_bob).is_ok());
+
+        // the copy cannot be dropped or swapped for one of another amount
+        let stripped = Tx {
+            tx_auditor: None,
+            ..to_bob.clone()
+        };
+        let swapped = Tx {
+            tx_auditor: to_owner.tx_auditor.clone(),
+            ..to_bob.clone()
+        };
+        assert!(OpeningBackend.verify(&fhe_oracle, &stripped).is_err());
+        assert!(OpeningBackend.verify(&fhe_oracle, &swapped).is_err());
+
+        let logs = vec![
+            deposit_log(1, &alice, &fhe_oracle),
+            deposit_log(2, &bob, &fhe_oracle),
+            send_log(3, &to_bob, &fhe_oracle),
+            send_log(4, &to_owner, &fhe_oracle),
+        ];
+
+        let report = auditor
+            .report(
+                &parameters,
+                &fhe_oracle.signing_domain,
+                &logs,
+                &[],
+                &[alice.address.clone()],
+            )
+            .unwrap();
+        assert_eq!(report.flows.len(), 1);
+        assert_eq!(report.flows[0].amounts.as_ref().unwrap()[ETH], 10);
+        assert_eq!(report.totals[&alice.address].sent[ETH], 10);
+
+        let report = auditor
+            .report(
+                &parameters,
+                &fhe_oracle.signing_domain,
+                &logs,
+                &[],
+                &[bob.address.to_lowercase()],
+            )
+            .unwrap();
+        let totals = &report.totals[&bob.address.to_lowercase()];
+        assert_eq!(report.flows.len(), 2);
+        assert_eq!((totals.sent[ETH], totals.received[ETH]), (5, 10));
+        assert_eq!(totals.unaudited, 0);
+    }
+
+    #[test]
+    fn test_auditor_skips_mangled_envelope() {
+        let (mut fhe_oracle, alice, bob, ..) = create_users(100, 50);
+        let parameters = fhe_oracle.parameters.clone();
+        let auditor = Auditor::new(&parameters, &mut thread_rng());
+        fhe_oracle.register_auditor(auditor.fhe_pk.clone());
+
+        let tx = alice
+            .create_tx(fhe_oracle.users[&bob.address].clone(), &fhe_oracle, 10)
+            .unwrap();
+        let mut mangled = send_log(4, &tx, &fhe_oracle);
+        if let OracleEvent::Send { fhe_proof, .. } = &mut mangled.event {
+            *fhe_proof = r#"{"proof":1,"tx_auditor":"00"}"#.to_string();
+        }
+
+        // neither an envelope nor a proof, so not a tx without a copy
+        assert!(decode_send_log(&parameters, &fhe_oracle.signing_domain, &mangled).is_err());
+
+        // a copy that decrypts to no balance at all
+        let encrypt = |amount: u64| {
+            auditor
+                .fhe_pk
+                .try_encrypt(
+                    &encode_balances(&parameters, &[amount]).unwrap(),
+                    &mut thread_rng(),
+                )
+                .unwrap()
+        };
+        let negative = Tx {
+            tx_auditor: Some(&encrypt(0) - &encrypt(1)),
+            ..tx.clone()
+        };
+
+        let logs = vec![
+            mangled,
+            send_log(5, &tx, &fhe_oracle),
+            send_log(6, &negative, &fhe_oracle),
+        ];
+        let report = auditor
+            .report(
+                &parameters,
+                &fhe_oracle.signing_domain,
+                &logs,
+                &[],
+                &[alice.address.clone()],
+            )
+            .unwrap();
+        let skipped: Vec<u64> = report.skipped.iter().map(|log| log.block_number).collect();
+        assert_eq!(skipped, vec![4, 6]);
+        assert_eq!(report.flows.len(), 1);
+        assert_eq!(report.totals[&alice.address].sent[ETH], 10);
+        assert_eq!(report.totals[&alice.address].unaudited, 0);
+    }
+
+    #[test]
+    fn test_auditor_leaves_out_refused_flows() {
+        let (mut fhe_oracle, alice, bob, owner) = create_users(100, 50);
+        let parameters = fhe_oracle.parameters.clone();
+        let auditor = Auditor::new(&parameters, &mut thread_rng());
+        fhe_oracle.register_auditor(auditor.fhe_pk.clone());
+
+        // the owner never deposits, so the Oracle refuses the transfer to it
+        let refused_tx = alice
+            .create_tx(fhe_oracle.users[&owner.address].clone(), &fhe_oracle, 5)
+            .unwrap();
+        let tx = alice
+            .create_tx(fhe_oracle.users[&bob.address].clone(), &fhe_oracle, 10)
+            .unwrap();
+        let logs = vec![
+            deposit_log(1, &alice, &fhe_oracle),
+            deposit_log(2, &bob, &fhe_oracle),
+            send_log(3, &refused_tx, &fhe_oracle),
+            send_log(4, &tx, &fhe_oracle),
+        ];
+        let (_, refused) =
+            replay_logs(fresh_node(&fhe_oracle, &[&refused_tx, &tx]), logs.clone()).unwrap();
+        assert_eq!(refused.len(), 1);
+
+        let report = auditor
+            .report(
+                &parameters,
+                &fhe_oracle.signing_domain,
+                &logs,
+                &refused,
+                &[alice.address.clone()],
+            )
+            .unwrap();
+        let applied: Vec<bool> = report.flows.iter().map(|flow| flow.applied).collect();
+        assert_eq!(applied, vec![false, true]);
+        assert_eq!(report.totals[&alice.address].sent[ETH], 10);
+        assert_eq!(report.totals[&alice.address].refused, 1);
+    }
+}
@@ -0,0 +1,116 @@
+use crate::fhe_account_handler::keystore::random_secret_coeffs;
+use crate::fhe_node::fhe_oracle::OracleError;
+use crate::fhe_node::fhe_params::parameter_id;
+use ethers::signers::LocalWallet;
+use ethers::utils::{hash_message, hex, keccak256};
+use fhe::bfv::{BfvParameters, PublicKey, SecretKey};
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use std::sync::Arc;
+
+/// Prefix of every message signed to derive an FHE key. Changing it changes
+/// every derived key.
+pub const DERIVATION_DOMAIN: &str = "privacy-token-fhe key derivation v1";
+
+/// The FHE keys of an Ethereum address, regenerated from its wallet alone.
+/// `account` picks one of several FHE accounts under the same address.
+pub struct DerivedKeys {
+    pub path: String,
+    pub coeffs: Vec<i64>,
+    pub fhe_sk: SecretKey,
+    pub fhe_pk: PublicKey,
+}
+
+pub fn derivation_path(account: u32) -> String {
+    format!("m/fhe/{}", account)
+}
+
+/// What the wallet signs. It names the parameters so one signature never
+/// yields keys under two parameter sets.
+pub fn derivation_message(parameters: &BfvParameters, account: u32) -> String {
+    format!(
+        "{}\nparameters: {}\npath: {}",
+        DERIVATION_DOMAIN,
+        hex::encode(parameter_id(parameters)),
+        derivation_path(account)
+    )
+}
+
+/// Signs the derivation message and hashes the signature into a seed.
+/// ECDSA signing is deterministic (RFC 6979), so the same wallet always gets
+/// the same seed, and nobody without the wallet key can produce it.
+pub fn derivation_seed(
+    wallet: &LocalWallet,
+    parameters: &BfvParameters,
+    account: u32,
+) -> Result<[u8; 32], OracleError> {
+    let signature = wallet
+        .sign_hash(hash_message(derivation_message(parameters, account)))
+        .map_err(|error| OracleError::InvalidSignature(error.to_string()))?;
+
+    Ok(keccak256(signature.to_vec()))
+}
+
+/// Derives the secret key and a public key for it from `seed`. Both come out
+/// of one ChaCha20 stream, so the public key is reproducible too.
+pub fn derive_keys_from_seed(
+    parameters: &Arc<BfvParameters>,
+    seed: [u8; 32],
+    account: u32,
+) -> DerivedKeys {
+    let mut rng = ChaCha20Rng::from_seed(seed);
+    let coeffs = random_secret_coeffs(parameters, &mut rng);
+    let fhe_sk = SecretKey::new(coeffs.clone(), parameters);
+    let fhe_pk = PublicKey::new(&fhe_sk, &mut rng);
+
+    DerivedKeys {
+        path: derivation_path(account),
+        coeffs,
+        fhe_sk,
+        fhe_pk,
+    }
+}
+
+pub fn derive_keys(
+    wallet: &LocalWallet,
+    parameters: &Arc<BfvParameters>,
+    account: u32,
+) -> Result<DerivedKeys, OracleError> {
+    Ok(derive_keys_from_seed(
+        parameters,
+        derivation_seed(wallet, parameters, account)?,
+        account,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhe_account_handler::get_keys::get_keys;
+    use crate::fhe_node::fhe_params::ParameterSet;
+
+    fn wallet(name: &str) -> LocalWallet {
+        get_keys(name).unwrap().private_key.parse().unwrap()
+    }
+
+    #[test]
+    fn test_keys_are_bound_to_wallet_path_and_parameters() {
+        let parameters = ParameterSet::default_preset().build().unwrap();
+        let other = ParameterSet::preset("bfv-4096").unwrap().build().unwrap();
+
+        let first = derive_keys(&wallet("user"), &parameters, 0).unwrap();
+        let again = derive_keys(&wallet("user"), &parameters, 0).unwrap();
+        assert_eq!(first.coeffs, again.coeffs);
+        assert_eq!(first.fhe_pk, again.fhe_pk);
+        assert_eq!(first.path, "m/fhe/0");
+
+        let second_account = derive_keys(&wallet("user"), &parameters, 1).unwrap();
+        let other_wallet = derive_keys(&wallet("bob"), &parameters, 0).unwrap();
+        assert_ne!(first.coeffs, second_account.coeffs);
+        assert_ne!(first.coeffs, other_wallet.coeffs);
+        assert_ne!(
+            derivation_seed(&wallet("user"), &parameters, 0).unwrap(),
+            derivation_seed(&wallet("user"), &other, 0).unwrap()
+        );
+    }
+}
@@ -53,25 +53,34 @@ pub(crate) mod tests {
             fhe_oracle.parameters.clone(),
             None,
             Some(100),
-        );
+        )
+        .unwrap();
 
         let alice = create_user(
-            get_keys("alice").unwrap().public_key.to_string(),
+            get_keys("user").unwrap().public_key.to_string(),
             fhe_oracle.parameters.clone(),
             None,
             Some(alice_balance),
-        );
+        )
+        .unwrap();
 
         let bob = create_user(
             get_keys("bob").unwrap().public_key.to_string(),
             fhe_oracle.parameters.clone(),
             None,
             Some(bob_balance),
-        );
+        )
+        .unwrap();
 
-        fhe_oracle.add_user(owner.address.clone(), OracleUser::from_user(owner.clone()));
-        fhe_oracle.add_user(alice.address.clone(), OracleUser::from_user(alice.clone()));
-        fhe_oracle.add_user(bob.address.clone(), OracleUser::from_user(bob.clone()));
+        fhe_oracle
+            .add_user(owner.address.clone(), OracleUser::from_user(owner.clone()))
+            .unwrap();
+        fhe_oracle
+            .add_user(alice.address.clone(), OracleUser::from_user(alice.clone()))
+            .unwrap();
+        fhe_oracle
+            .add_user(bob.address.clone(), OracleUser::from_user(bob.clone()))
+            .unwrap();
 
         (fhe_oracle, alice, bob, owner)
     }
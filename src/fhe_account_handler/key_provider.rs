@@ -0,0 +1,169 @@
+use ethers::signers::coins_bip39::English;
+use ethers::signers::{LocalWallet, MnemonicBuilder};
+use ethers::utils::hex;
+use std::env;
+use std::fmt;
+use std::path::PathBuf;
+
+/// The mnemonic Anvil and Hardhat fund their default accounts from.
+pub const ANVIL_MNEMONIC: &str = "test test test test test test test test test test test junk";
+
+/// BIP-44 path of Ethereum accounts, the account index goes last.
+pub const ETHEREUM_DERIVATION_PATH: &str = "m/44'/60'/0'/0/";
+
+/// Prefix of the variables `KeySource::from_env` reads for the node's signer.
+pub const SIGNER_ENV: &str = "FHE_SIGNER";
+
+#[derive(Debug)]
+pub enum KeyError {
+    Missing(String),
+    Malformed(String),
+    Keystore(String),
+    Mnemonic(String),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyError::Missing(variable) => write!(f, "{} is not set", variable),
+            KeyError::Malformed(reason) => write!(f, "malformed private key: {}", reason),
+            KeyError::Keystore(reason) => write!(f, "cannot unlock keystore: {}", reason),
+            KeyError::Mnemonic(reason) => write!(f, "cannot derive from mnemonic: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+/// Where an Ethereum signing key comes from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeySource {
+    /// A V3 JSON keystore and its password.
+    Keystore { path: PathBuf, password: String },
+    /// A hex private key held in the named environment variable.
+    Env(String),
+    /// A BIP-39 phrase and the BIP-32 path to derive along.
+    Mnemonic { phrase: String, path: String },
+}
+
+impl KeySource {
+    /// Account `index` of Anvil's default mnemonic, the same keys as
+    /// `get_keys` hands out for local testing.
+    pub fn anvil(index: u32) -> KeySource {
+        KeySource::Mnemonic {
+            phrase: ANVIL_MNEMONIC.to_string(),
+            path: format!("{}{}", ETHEREUM_DERIVATION_PATH, index),
+        }
+    }
+
+    /// Reads `<prefix>_KEYSTORE` with `<prefix>_PASSWORD`, `<prefix>_KEY`, or
+    /// `<prefix>_MNEMONIC` with an optional `<prefix>_PATH`, in that order.
+    /// `None` if none of them is set.
+    pub fn from_env(prefix: &str) -> Result<Option<KeySource>, KeyError> {
+        if let Ok(path) = env::var(format!("{}_KEYSTORE", prefix)) {
+            let password_variable = format!("{}_PASSWORD", prefix);
+            let password =
+                env::var(&password_variable).map_err(|_| KeyError::Missing(password_variable))?;
+
+            return Ok(Some(KeySource::Keystore {
+                path: PathBuf::from(path),
+                password,
+            }));
+        }
+
+        let key_variable = format!("{}_KEY", prefix);
+        if env::var(&key_variable).is_ok() {
+            return Ok(Some(KeySource::Env(key_variable)));
+        }
+
+        if let Ok(phrase) = env::var(format!("{}_MNEMONIC", prefix)) {
+            let path = env::var(format!("{}_PATH", prefix))
+                .unwrap_or(format!("{}0", ETHEREUM_DERIVATION_PATH));
+
+            return Ok(Some(KeySource::Mnemonic { phrase, path }));
+        }
+
+        Ok(None)
+    }
+
+    pub fn load(&self) -> Result<LocalWallet, KeyError> {
+        match self {
+            KeySource::Keystore { path, password } => LocalWallet::decrypt_keystore(path, password)
+                .map_err(|error| KeyError::Keystore(error.to_string())),
+            KeySource::Env(variable) => env::var(variable)
+                .map_err(|_| KeyError::Missing(variable.clone()))?
+                .trim()
+                .parse::<LocalWallet>()
+                .map_err(|error| KeyError::Malformed(error.to_string())),
+            KeySource::Mnemonic { phrase, path } => MnemonicBuilder::<English>::default()
+                .phrase(phrase.as_str())
+                .derivation_path(path)
+                .and_then(|builder| builder.build())
+                .map_err(|error| KeyError::Mnemonic(error.to_string())),
+        }
+    }
+}
+
+/// The `0x` hex private key, as `cast --private-key` takes it.
+pub fn private_key_hex(signer: &LocalWallet) -> String {
+    format!("0x{}", hex::encode(signer.signer().to_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhe_account_handler::get_keys::get_keys;
+    use ethers::signers::Signer;
+    use ethers::utils::to_checksum;
+    use rand::thread_rng;
+
+    #[test]
+    fn test_anvil_mnemonic_matches_get_keys() {
+        for (index, name) in ["owner", "user", "bob", "charlie", "dave"]
+            .iter()
+            .enumerate()
+        {
+            let signer = KeySource::anvil(index as u32).load().unwrap();
+            let keys = get_keys(name).unwrap();
+
+            assert_eq!(to_checksum(&signer.address(), None), keys.public_key);
+            assert_eq!(private_key_hex(&signer), keys.private_key);
+        }
+    }
+
+    #[test]
+    fn test_keys_from_env_and_keystore() {
+        let keys = get_keys("bob").unwrap();
+
+        env::set_var("FHE_KEY_TEST_KEY", keys.private_key);
+        let source = KeySource::from_env("FHE_KEY_TEST").unwrap().unwrap();
+        assert_eq!(source, KeySource::Env("FHE_KEY_TEST_KEY".to_string()));
+        assert_eq!(private_key_hex(&source.load().unwrap()), keys.private_key);
+        assert_eq!(KeySource::from_env("FHE_KEY_UNSET").unwrap(), None);
+
+        let dir = env::temp_dir().join("fhe_key_provider");
+        std::fs::create_dir_all(&dir).unwrap();
+        let signer: LocalWallet = keys.private_key.parse().unwrap();
+        LocalWallet::encrypt_keystore(
+            &dir,
+            &mut thread_rng(),
+            signer.signer().to_bytes(),
+            "bob's password",
+            Some("bob.json"),
+        )
+        .unwrap();
+
+        let keystore = KeySource::Keystore {
+            path: dir.join("bob.json"),
+            password: "bob's password".to_string(),
+        };
+        assert_eq!(keystore.load().unwrap().address(), signer.address());
+
+        let wrong = KeySource::Keystore {
+            path: dir.join("bob.json"),
+            password: "not the password".to_string(),
+        };
+        assert!(matches!(wrong.load(), Err(KeyError::Keystore(_))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
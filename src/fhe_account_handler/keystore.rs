@@ -0,0 +1,471 @@
+use crate::fhe_node::fhe_params::{
+    decode_pk, encode_pk, parameter_id, ParameterError, PARAMETER_ID_LEN,
+};
+use crate::fhe_node::fhe_store::write_private;
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use ethers::utils::{hex, keccak256};
+use fhe::bfv::{BfvParameters, PublicKey, SecretKey};
+use rand::{CryptoRng, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+
+/// Version 2 authenticates the header along with the ciphertext.
+pub const KEYSTORE_VERSION: u32 = 2;
+
+const CIPHER: &str = "aes-128-ctr";
+const KDF: &str = "scrypt";
+const DKLEN: usize = 32;
+
+// a keystore names its own scrypt cost, these bound what loading one can
+// cost in time and memory (128 * n * r bytes, 1 GiB at the limits)
+const MAX_SCRYPT_N: u32 = 1 << 20;
+const MAX_SCRYPT_R: u32 = 8;
+const MAX_SCRYPT_P: u32 = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeystoreError {
+    Io(String),
+    Malformed(String),
+    UnsupportedVersion(u32),
+    Parameters(ParameterError),
+    WrongPassword,
+}
+
+impl fmt::Display for KeystoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeystoreError::Io(reason) => write!(f, "keystore io: {}", reason),
+            KeystoreError::Malformed(reason) => write!(f, "malformed keystore: {}", reason),
+            KeystoreError::UnsupportedVersion(version) => {
+                write!(f, "unsupported keystore version {}", version)
+            }
+            KeystoreError::Parameters(error) => write!(f, "{}", error),
+            KeystoreError::WrongPassword => write!(f, "wrong keystore password"),
+        }
+    }
+}
+
+impl std::error::Error for KeystoreError {}
+
+impl From<ParameterError> for KeystoreError {
+    fn from(error: ParameterError) -> KeystoreError {
+        KeystoreError::Parameters(error)
+    }
+}
+
+impl From<std::io::Error> for KeystoreError {
+    fn from(error: std::io::Error) -> KeystoreError {
+        KeystoreError::Io(error.to_string())
+    }
+}
+
+/// scrypt cost. `n` must be a power of two.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct KdfParams {
+    pub n: u32,
+    pub r: u32,
+    pub p: u32,
+}
+
+impl Default for KdfParams {
+    /// The cost of Ethereum's standard keystores.
+    fn default() -> KdfParams {
+        KdfParams {
+            n: 1 << 18,
+            r: 8,
+            p: 1,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Cheap enough for tests and local networks.
+    pub fn light() -> KdfParams {
+        KdfParams {
+            n: 1 << 12,
+            r: 8,
+            p: 6,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct KeystoreCrypto {
+    pub cipher: String,
+    pub iv: String,
+    pub ciphertext: String,
+    pub kdf: String,
+    pub kdfparams: KdfParams,
+    pub salt: String,
+    pub mac: String,
+}
+
+/// A BFV secret key encrypted under a password, laid out like Ethereum's V3
+/// keystore: scrypt derives 32 bytes, the first half keys AES-128-CTR over
+/// the secret coefficients and the second half authenticates the ciphertext
+/// together with the version, address, parameter fingerprint, public key,
+/// scrypt cost and iv.
+///
+/// The public key and the parameter fingerprint sit in the clear, so a key
+/// made under other parameters is refused before the password is tried, and
+/// the mac keeps anyone without the password from swapping them.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct FheKeystore {
+    pub version: u32,
+    pub address: String,
+    pub parameters: String,
+    pub fhe_pk: String,
+    pub crypto: KeystoreCrypto,
+}
+
+/// Samples a ternary secret, one coefficient in `{-1, 0, 1}` per slot.
+///
+/// Uniform rather than the centered binomial of `SecretKey::random`: the
+/// security estimates of the HE standard assume a ternary secret, the
+/// keystore stores one signed byte per coefficient and refuses anything
+/// outside `{-1, 0, 1}`, and wallet derivation only has to feed an rng.
+pub fn random_secret_coeffs<R: RngCore + CryptoRng>(
+    parameters: &BfvParameters,
+    rng: &mut R,
+) -> Vec<i64> {
+    (0..parameters.degree())
+        .map(|_| rng.gen_range(-1..=1))
+        .collect()
+}
+
+fn derive_key(password: &str, salt: &[u8], kdf: &KdfParams) -> Result<[u8; DKLEN], KeystoreError> {
+    if !kdf.n.is_power_of_two() {
+        return Err(KeystoreError::Malformed(format!(
+            "scrypt n {} is not a power of two",
+            kdf.n
+        )));
+    }
+    if kdf.n > MAX_SCRYPT_N || kdf.r > MAX_SCRYPT_R || kdf.p > MAX_SCRYPT_P {
+        return Err(KeystoreError::Malformed(format!(
+            "scrypt cost n={} r={} p={} above the limit",
+            kdf.n, kdf.r, kdf.p
+        )));
+    }
+
+    let params = scrypt::Params::new(kdf.n.trailing_zeros() as u8, kdf.r, kdf.p)
+        .map_err(|error| KeystoreError::Malformed(error.to_string()))?;
+    let mut key = [0u8; DKLEN];
+    scrypt::scrypt(password.as_bytes(), salt, &params, &mut key)
+        .map_err(|error| KeystoreError::Malformed(error.to_string()))?;
+
+    Ok(key)
+}
+
+// each field goes in behind its length, so no two headers run together
+// into the same preimage
+fn mac(key: &[u8; DKLEN], header: &[&[u8]], ciphertext: &[u8]) -> [u8; 32] {
+    let mut preimage = key[16..].to_vec();
+    for field in header.iter().copied().chain([ciphertext]) {
+        preimage.extend_from_slice(&(field.len() as u64).to_be_bytes());
+        preimage.extend_from_slice(field);
+    }
+
+    keccak256(preimage)
+}
+
+fn apply_cipher(key: &[u8; DKLEN], iv: &[u8], data: &mut [u8]) -> Result<(), KeystoreError> {
+    let mut cipher = Aes128Ctr::new_from_slices(&key[..16], iv)
+        .map_err(|error| KeystoreError::Malformed(error.to_string()))?;
+    cipher.apply_keystream(data);
+
+    Ok(())
+}
+
+fn decode_hex(field: &str, encoded: &str) -> Result<Vec<u8>, KeystoreError> {
+    hex::decode(encoded.trim_start_matches("0x"))
+        .map_err(|error| KeystoreError::Malformed(format!("{}: {}", field, error)))
+}
+
+impl FheKeystore {
+    /// Encrypts the secret `coeffs` of `address` under `password`.
+    pub fn encrypt<R: RngCore + CryptoRng>(
+        parameters: &BfvParameters,
+        address: &str,
+        coeffs: &[i64],
+        fhe_pk: &PublicKey,
+        password: &str,
+        kdf: KdfParams,
+        rng: &mut R,
+    ) -> Result<FheKeystore, KeystoreError> {
+        let salt: [u8; 32] = rng.gen();
+        let iv: [u8; 16] = rng.gen();
+        let key = derive_key(password, &salt, &kdf)?;
+
+        let mut ciphertext: Vec<u8> = coeffs.iter().map(|coeff| *coeff as i8 as u8).collect();
+        apply_cipher(&key, &iv, &mut ciphertext)?;
+
+        let mut keystore = FheKeystore {
+            version: KEYSTORE_VERSION,
+            address: address.to_string(),
+            parameters: hex::encode(parameter_id(parameters)),
+            fhe_pk: encode_pk(parameters, fhe_pk),
+            crypto: KeystoreCrypto {
+                cipher: CIPHER.to_string(),
+                iv: hex::encode(iv),
+                ciphertext: hex::encode(&ciphertext),
+                kdf: KDF.to_string(),
+                kdfparams: kdf,
+                salt: hex::encode(salt),
+                mac: String::new(),
+            },
+        };
+        keystore.crypto.mac = hex::encode(keystore.mac(&key, &ciphertext));
+
+        Ok(keystore)
+    }
+
+    fn mac(&self, key: &[u8; DKLEN], ciphertext: &[u8]) -> [u8; 32] {
+        let kdf = &self.crypto.kdfparams;
+        let mut cost = Vec::new();
+        for value in [kdf.n, kdf.r, kdf.p] {
+            cost.extend_from_slice(&value.to_be_bytes());
+        }
+
+        mac(
+            key,
+            &[
+                &self.version.to_be_bytes(),
+                self.address.as_bytes(),
+                self.parameters.as_bytes(),
+                self.fhe_pk.as_bytes(),
+                &cost,
+                self.crypto.iv.as_bytes(),
+            ],
+            ciphertext,
+        )
+    }
+
+    fn check_header(&self, parameters: &BfvParameters) -> Result<(), KeystoreError> {
+        if self.version != KEYSTORE_VERSION {
+            return Err(KeystoreError::UnsupportedVersion(self.version));
+        }
+        if self.crypto.cipher != CIPHER || self.crypto.kdf != KDF {
+            return Err(KeystoreError::Malformed(format!(
+                "unsupported {} with {}",
+                self.crypto.cipher, self.crypto.kdf
+            )));
+        }
+
+        let bytes = decode_hex("parameters", &self.parameters)?;
+        if bytes.len() != PARAMETER_ID_LEN {
+            return Err(KeystoreError::Malformed(
+                "bad parameter fingerprint".to_string(),
+            ));
+        }
+        let mut found = [0u8; PARAMETER_ID_LEN];
+        found.copy_from_slice(&bytes);
+
+        let expected = parameter_id(parameters);
+        if found != expected {
+            return Err(ParameterError::Mismatch { expected, found }.into());
+        }
+
+        Ok(())
+    }
+
+    /// Checks the header and the password, then rebuilds the secret key.
+    pub fn decrypt(
+        &self,
+        parameters: &Arc<BfvParameters>,
+        password: &str,
+    ) -> Result<SecretKey, KeystoreError> {
+        Ok(SecretKey::new(
+            self.decrypt_coeffs(parameters, password)?,
+            parameters,
+        ))
+    }
+
+    /// The secret coefficients, for re-encrypting them under a new password.
+    pub fn decrypt_coeffs(
+        &self,
+        parameters: &BfvParameters,
+        password: &str,
+    ) -> Result<Vec<i64>, KeystoreError> {
+        self.check_header(parameters)?;
+
+        let salt = decode_hex("salt", &self.crypto.salt)?;
+        let iv = decode_hex("iv", &self.crypto.iv)?;
+        let mut plaintext = decode_hex("ciphertext", &self.crypto.ciphertext)?;
+        let key = derive_key(password, &salt, &self.crypto.kdfparams)?;
+
+        // compared in full so the time taken says nothing about the mac
+        let expected = self.mac(&key, &plaintext);
+        let found = decode_hex("mac", &self.crypto.mac)?;
+        if found.len() != expected.len()
+            || found
+                .iter()
+                .zip(expected.iter())
+                .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+                != 0
+        {
+            return Err(KeystoreError::WrongPassword);
+        }
+
+        apply_cipher(&key, &iv, &mut plaintext)?;
+        if plaintext.len() != parameters.degree() {
+            return Err(KeystoreError::Malformed(format!(
+                "expected {} coefficients, found {}",
+                parameters.degree(),
+                plaintext.len()
+            )));
+        }
+
+        plaintext
+            .iter()
+            .map(|byte| match *byte as i8 {
+                coeff @ -1..=1 => Ok(coeff as i64),
+                coeff => Err(KeystoreError::Malformed(format!(
+                    "secret coefficient {} out of range",
+                    coeff
+                ))),
+            })
+            .collect()
+    }
+
+    pub fn public_key(&self, parameters: &Arc<BfvParameters>) -> Result<PublicKey, KeystoreError> {
+        Ok(decode_pk(parameters, &self.fhe_pk)?)
+    }
+
+    /// Writes the keystore as JSON, readable by the owner only. The file is
+    /// replaced atomically, a crash mid-save leaves the old keystore intact.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), KeystoreError> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let json = serde_json::to_vec_pretty(self)
+            .map_err(|error| KeystoreError::Malformed(error.to_string()))?;
+
+        // the temp file is created owner-only, so the renamed keystore is too
+        // whatever the mode of the file it replaces
+        write_private(path, &json)?;
+
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<FheKeystore, KeystoreError> {
+        let bytes = std::fs::read(path)?;
+
+        serde_json::from_slice(&bytes).map_err(|error| KeystoreError::Malformed(error.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhe_node::fhe_params::ParameterSet;
+    use rand::thread_rng;
+
+    fn keystore(parameters: &Arc<BfvParameters>, password: &str) -> (FheKeystore, Vec<i64>) {
+        let mut rng = thread_rng();
+        let coeffs = random_secret_coeffs(parameters, &mut rng);
+        let sk = SecretKey::new(coeffs.clone(), parameters);
+        let pk = PublicKey::new(&sk, &mut rng);
+
+        let keystore = FheKeystore::encrypt(
+            parameters,
+            "0xk0",
+            &coeffs,
+            &pk,
+            password,
+            KdfParams::light(),
+            &mut rng,
+        )
+        .unwrap();
+
+        (keystore, coeffs)
+    }
+
+    #[test]
+    fn test_keystore_round_trip() {
+        let parameters = ParameterSet::default_preset().build().unwrap();
+        let (keystore, coeffs) = keystore(&parameters, "hunter2");
+
+        let dir =
+            std::env::temp_dir().join(format!("fhe_keystore_round_trip_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("keystore.json");
+        std::fs::write(&path, b"{}").unwrap();
+        keystore.save(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let loaded = FheKeystore::load(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(loaded, keystore);
+        assert_eq!(
+            loaded.decrypt_coeffs(&parameters, "hunter2").unwrap(),
+            coeffs
+        );
+        assert_eq!(
+            loaded.decrypt_coeffs(&parameters, "hunter3"),
+            Err(KeystoreError::WrongPassword)
+        );
+    }
+
+    #[test]
+    fn test_keystore_refuses_foreign_parameters_and_tampering() {
+        let parameters = ParameterSet::default_preset().build().unwrap();
+        let other = ParameterSet::preset("bfv-4096").unwrap().build().unwrap();
+        let (keystore, _) = keystore(&parameters, "hunter2");
+
+        assert!(matches!(
+            keystore.decrypt_coeffs(&other, "hunter2"),
+            Err(KeystoreError::Parameters(ParameterError::Mismatch { .. }))
+        ));
+
+        let mut ciphertext = hex::decode(&keystore.crypto.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        let mut tampered = keystore.clone();
+        tampered.crypto.ciphertext = hex::encode(ciphertext);
+        assert_eq!(
+            tampered.decrypt_coeffs(&parameters, "hunter2"),
+            Err(KeystoreError::WrongPassword)
+        );
+
+        // the header is under the mac too
+        let mut swapped = keystore.clone();
+        swapped.address = "0xk1".to_string();
+        assert_eq!(
+            swapped.decrypt_coeffs(&parameters, "hunter2"),
+            Err(KeystoreError::WrongPassword)
+        );
+        let (other_key, _) = self::keystore(&parameters, "hunter2");
+        let mut swapped = keystore.clone();
+        swapped.fhe_pk = other_key.fhe_pk;
+        assert_eq!(
+            swapped.decrypt_coeffs(&parameters, "hunter2"),
+            Err(KeystoreError::WrongPassword)
+        );
+
+        let mut costly = keystore.clone();
+        costly.crypto.kdfparams.n = MAX_SCRYPT_N << 1;
+        assert!(matches!(
+            costly.decrypt_coeffs(&parameters, "hunter2"),
+            Err(KeystoreError::Malformed(_))
+        ));
+
+        let future = FheKeystore {
+            version: KEYSTORE_VERSION + 1,
+            ..keystore
+        };
+        assert_eq!(
+            future.decrypt_coeffs(&parameters, "hunter2"),
+            Err(KeystoreError::UnsupportedVersion(KEYSTORE_VERSION + 1))
+        );
+    }
+}
@@ -0,0 +1,361 @@
+use crate::fhe_node::fhe_assets::decode_balances;
+use crate::fhe_node::fhe_execution::SigningDomain;
+use crate::fhe_node::fhe_oracle::{Oracle, OracleError};
+use crate::fhe_node::fhe_params::{decode_ciphertext, decode_pk};
+use crate::fhe_node::fhe_replay::{decode_send_log, OracleEvent, OracleLog, SkippedLog};
+use fhe::bfv::{BfvParameters, Ciphertext, PublicKey, SecretKey};
+use fhe_traits::FheDecrypter;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoteKind {
+    /// The first deposit or a key rotation, which set the balance outright.
+    Opening,
+    Credit,
+    Debit,
+}
+
+/// A ciphertext from the chain that moved an account's balance, kept where
+/// it was emitted.
+#[derive(Clone)]
+pub struct Note {
+    pub block_number: u64,
+    pub log_index: u64,
+    pub kind: NoteKind,
+    pub ciphertext: Ciphertext,
+}
+
+/// The notes of one account since its last opening under the account's own
+/// key, enough to work out the balance without asking the Oracle.
+#[derive(Clone, Default)]
+pub struct NoteLedger {
+    notes: Vec<Note>,
+    /// Chain position of the last log taken in, so logs can be fed again.
+    cursor: Option<(u64, u64)>,
+    /// Whether the account is registered under the key the ledger reads,
+    /// `None` before its first deposit.
+    own_key: Option<bool>,
+}
+
+/// The balance the notes add up to next to the one the Oracle holds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BalanceCheck {
+    /// `None` until the ledger has seen an opening under the account's key.
+    pub expected: Option<Vec<u64>>,
+    pub found: Vec<u64>,
+}
+
+impl BalanceCheck {
+    pub fn matches(&self) -> bool {
+        self.expected.as_ref() == Some(&self.found)
+    }
+}
+
+impl NoteLedger {
+    pub fn new() -> NoteLedger {
+        NoteLedger::default()
+    }
+
+    pub fn notes(&self) -> &[Note] {
+        &self.notes
+    }
+
+    /// Takes the logs that concern `address` into the ledger. Logs have to be
+    /// in chain order, the ones at or before the last position taken in are
+    /// skipped. Logs in `refused`, the ones the Oracle left out when it
+    /// applied them, moved no balance and are passed over. Returns the logs
+    /// that could not be read, which are left out as well.
+    pub fn track_logs(
+        &mut self,
+        parameters: &Arc<BfvParameters>,
+        domain: &SigningDomain,
+        address: &str,
+        fhe_pk: &PublicKey,
+        logs: &[OracleLog],
+        refused: &[SkippedLog],
+    ) -> Vec<SkippedLog> {
+        let mut skipped: Vec<SkippedLog> = Vec::new();
+
+        for log in logs.iter() {
+            let position = (log.block_number, log.log_index);
+            if self.cursor.map_or(false, |cursor| position <= cursor) {
+                continue;
+            }
+
+            let was_refused = refused
+                .iter()
+                .any(|refused| (refused.block_number, refused.log_index) == position);
+            if !was_refused {
+                if let Err(error) = self.track_log(parameters, domain, address, fhe_pk, log) {
+                    skipped.push(SkippedLog::new(log, error));
+                }
+            }
+            self.cursor = Some(position);
+        }
+
+        skipped
+    }
+
+    fn track_log(
+        &mut self,
+        parameters: &Arc<BfvParameters>,
+        domain: &SigningDomain,
+        address: &str,
+        fhe_pk: &PublicKey,
+        log: &OracleLog,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match &log.event {
+            // later deposits add to the balance under the key of the
+            // first one, the Oracle refuses them under any other
+            OracleEvent::Deposit {
+                from,
+                fhe_pk: registered,
+                fhe_balance_init: balance,
+                ..
+            } if same_address(from, address) => {
+                let ours = decode_pk(parameters, registered)? == *fhe_pk;
+                match self.own_key {
+                    None if ours => self.push(
+                        log,
+                        NoteKind::Opening,
+                        decode_ciphertext(parameters, balance)?,
+                    ),
+                    Some(true) if ours => self.push(
+                        log,
+                        NoteKind::Credit,
+                        decode_ciphertext(parameters, balance)?,
+                    ),
+                    _ => {}
+                }
+                self.own_key.get_or_insert(ours);
+            }
+            OracleEvent::RotateKey {
+                from,
+                fhe_pk: registered,
+                fhe_balance: balance,
+                ..
+            } if same_address(from, address) => {
+                let ours = decode_pk(parameters, registered)? == *fhe_pk;
+                let balance = decode_ciphertext(parameters, balance)?;
+                // under a key that is not ours nothing that follows can
+                // be read, until the account comes back to our key
+                self.notes.clear();
+                self.own_key = Some(ours);
+                if ours {
+                    self.push(log, NoteKind::Opening, balance);
+                }
+            }
+            OracleEvent::Send { .. } => {
+                let tx = decode_send_log(parameters, domain, log)?;
+                if same_address(&tx.sender, address) {
+                    self.push(log, NoteKind::Debit, tx.tx_sender.clone());
+                }
+                if same_address(&tx.receiver, address) {
+                    self.push(log, NoteKind::Credit, tx.tx_receiver);
+                }
+            }
+            OracleEvent::WithdrawRequest {
+                to, fhe_tx_sender, ..
+            } if same_address(to, address) => {
+                self.push(
+                    log,
+                    NoteKind::Debit,
+                    decode_ciphertext(parameters, fhe_tx_sender)?,
+                );
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn push(&mut self, log: &OracleLog, kind: NoteKind, ciphertext: Ciphertext) {
+        // nothing moves a balance the ledger has not opened
+        if kind != NoteKind::Opening && self.notes.is_empty() {
+            return;
+        }
+
+        self.notes.push(Note {
+            block_number: log.block_number,
+            log_index: log.log_index,
+            kind,
+            ciphertext,
+        });
+    }
+
+    /// Decrypts every note and adds them up, one balance per asset. `None`
+    /// without an opening.
+    pub fn expected_balances(
+        &self,
+        parameters: &Arc<BfvParameters>,
+        fhe_sk: &SecretKey,
+    ) -> Result<Option<Vec<u64>>, OracleError> {
+        let mut balances: Option<Vec<u64>> = None;
+
+        for note in self.notes.iter() {
+            let amounts = decode_balances(parameters, &fhe_sk.try_decrypt(&note.ciphertext)?)?;
+            let current = balances.get_or_insert_with(|| vec![0; amounts.len()]);
+
+            for (balance, amount) in current.iter_mut().zip(amounts) {
+                *balance = match note.kind {
+                    NoteKind::Opening => amount,
+                    NoteKind::Credit => balance
+                        .checked_add(amount)
+                        .ok_or(OracleError::Unrepresentable(amount))?,
+                    NoteKind::Debit => balance
+                        .checked_sub(amount)
+                        .ok_or(OracleError::NegativeBalance)?,
+                };
+            }
+        }
+
+        Ok(balances)
+    }
+
+    /// Sets the notes against the ciphertext the Oracle holds for `address`.
+    pub fn check_balance(
+        &self,
+        oracle: &Oracle,
+        address: &str,
+        fhe_sk: &SecretKey,
+    ) -> Result<BalanceCheck, OracleError> {
+        let fhe_balance = oracle.return_user_fhe_balance(address.to_string())?;
+
+        Ok(BalanceCheck {
+            expected: self.expected_balances(&oracle.parameters, fhe_sk)?,
+            found: decode_balances(&oracle.parameters, &fhe_sk.try_decrypt(&fhe_balance)?)?,
+        })
+    }
+}
+
+fn same_address(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fhe_account_handler::get_keys::tests::create_users;
+    use crate::fhe_account_handler::user::User;
+    use crate::fhe_node::fhe_assets::{encode_balances, ETH};
+    use crate::fhe_node::fhe_attestation::tests::attest_debit;
+    use crate::fhe_node::fhe_replay::tests::{deposit_log, fresh_node, send_log, withdraw_log};
+    use crate::fhe_node::fhe_replay::{replay_logs, OracleEvent};
+    use fhe_traits::FheEncrypter;
+    use rand::thread_rng;
+
+    #[test]
+    fn test_notes_follow_the_chain() {
+        let (fhe_oracle, mut alice, mut bob, owner) = create_users(100, 50);
+        let parameters = fhe_oracle.parameters.clone();
+
+        let tx = alice
+            .create_tx(fhe_oracle.users[&bob.address].clone(), &fhe_oracle, 10)
+            .unwrap();
+        let withdrawal = alice.create_withdrawal(&fhe_oracle, ETH, 30).unwrap();
+        let top_up = User {
+            fhe_balance: bob
+                .fhe_pk
+                .try_encrypt(
+                    &encode_balances(&parameters, &[5]).unwrap(),
+                    &mut thread_rng(),
+                )
+                .unwrap(),
+            ..bob.clone()
+        };
+        let logs = vec![
+            deposit_log(1, &alice, &fhe_oracle),
+            deposit_log(2, &bob, &fhe_oracle),
+            send_log(3, &tx, &fhe_oracle),
+            withdraw_log(4, &withdrawal, &fhe_oracle),
+            deposit_log(5, &top_up, &fhe_oracle),
+        ];
+        // the node attested the withdrawal against alice's balance after the
+        // transfer
+        let (mut live, _) =
+            replay_logs(fresh_node(&fhe_oracle, &[&tx]), logs[..3].to_vec()).unwrap();
+        attest_debit(&mut live, &alice, &withdrawal.tx_sender);
+        let mut node = fresh_node(&fhe_oracle, &[&tx]);
+        node.attested_debits = live.attested_debits;
+        let (mut replayed, refused) = replay_logs(node, logs.clone()).unwrap();
+        assert_eq!(refused, vec![]);
+
+        // feeding the same logs twice does not count them twice
+        let domain = &fhe_oracle.signing_domain;
+        assert_eq!(
+            alice.track_logs(&parameters, domain, &logs, &refused),
+            vec![]
+        );
+        assert_eq!(
+            alice.track_logs(&parameters, domain, &logs, &refused),
+            vec![]
+        );
+        assert_eq!(bob.track_logs(&parameters, domain, &logs, &refused), vec![]);
+        assert_eq!(alice.notes.notes().len(), 3);
+
+        let check = alice.check_balance(&replayed).unwrap();
+        assert!(check.matches());
+        assert_eq!(check.expected.unwrap()[ETH], 60);
+        let check = bob.check_balance(&replayed).unwrap();
+        assert!(check.matches());
+        assert_eq!(check.expected.unwrap()[ETH], 65);
+
+        // nothing on the chain opened the owner's account
+        assert_eq!(owner.check_balance(&fhe_oracle).unwrap().expected, None);
+
+        // an Oracle that credits alice out of thin air is caught
+        let forged = alice
+            .fhe_pk
+            .try_encrypt(
+                &encode_balances(&parameters, &[1000]).unwrap(),
+                &mut thread_rng(),
+            )
+            .unwrap();
+        replayed
+            .update_user_fhe_balance(alice.address.clone(), forged)
+            .unwrap();
+        assert!(!alice.check_balance(&replayed).unwrap().matches());
+    }
+
+    #[test]
+    fn test_notes_pass_over_refused_and_unreadable_logs() {
+        let (fhe_oracle, mut alice, bob, owner) = create_users(100, 50);
+        let parameters = fhe_oracle.parameters.clone();
+
+        // the owner never deposits, so the Oracle refuses the transfer to it
+        let refused_tx = alice
+            .create_tx(fhe_oracle.users[&owner.address].clone(), &fhe_oracle, 5)
+            .unwrap();
+        let tx = alice
+            .create_tx(fhe_oracle.users[&bob.address].clone(), &fhe_oracle, 10)
+            .unwrap();
+        let mut garbage = send_log(3, &tx, &fhe_oracle);
+        if let OracleEvent::Send { fhe_tx_sender, .. } = &mut garbage.event {
+            *fhe_tx_sender = "zz".to_string();
+        }
+        let logs = vec![
+            deposit_log(1, &alice, &fhe_oracle),
+            deposit_log(2, &bob, &fhe_oracle),
+            garbage,
+            send_log(4, &refused_tx, &fhe_oracle),
+            send_log(5, &tx, &fhe_oracle),
+        ];
+        let (replayed, refused) =
+            replay_logs(fresh_node(&fhe_oracle, &[&refused_tx, &tx]), logs.clone()).unwrap();
+        assert_eq!(refused.len(), 2);
+
+        // the unreadable log does not hold up the ones after it
+        let skipped = alice.track_logs(&parameters, &fhe_oracle.signing_domain, &logs, &refused);
+        assert_eq!(
+            skipped
+                .iter()
+                .map(|log| log.block_number)
+                .collect::<Vec<u64>>(),
+            vec![3]
+        );
+        assert_eq!(alice.notes.notes().len(), 2);
+
+        let check = alice.check_balance(&replayed).unwrap();
+        assert!(check.matches());
+        assert_eq!(check.expected.unwrap()[ETH], 90);
+    }
+}
@@ -1,11 +1,28 @@
 use crate::{
-    fhe_account_handler::get_keys::get_keys,
-    fhe_node::{fhe_execution::Tx, fhe_oracle::*},
+    fhe_account_handler::{
+        derivation::derive_keys,
+        get_keys::get_keys,
+        keystore::{random_secret_coeffs, FheKeystore, KdfParams},
+        notes::{BalanceCheck, NoteLedger},
+    },
+    fhe_node::{
+        fhe_assets::{
+            decode_asset, decode_balances, decode_slots, encode_asset, encode_balances, AssetId,
+            ETH,
+        },
+        fhe_attestation::{BalanceChallenge, BalanceResponse},
+        fhe_execution::{KeyRotation, SigningDomain, Tx, TxHash, Withdrawal},
+        fhe_oracle::*,
+        fhe_proof::{OpeningBackend, TxProver},
+        fhe_replay::{OracleLog, SkippedLog},
+    },
 };
 
+use ethers::signers::{LocalWallet, Signer};
+use ethers::utils::to_checksum;
 use fhe::bfv::{BfvParametersBuilder, Ciphertext, Encoding, Plaintext, PublicKey, SecretKey};
 use fhe_traits::*;
-use rand::{rngs::OsRng, thread_rng};
+use rand::{rngs::OsRng, CryptoRng, RngCore};
 use std::sync::Arc;
 
 #[derive(Clone)]
@@ -16,6 +33,8 @@ pub struct User {
     pub fhe_sk: SecretKey,
     pub fhe_pk: PublicKey,
     pub fhe_balance: Ciphertext,
+    /// What the chain says moved this balance, see `track_logs`.
+    pub notes: NoteLedger,
 }
 
 impl User {
@@ -34,48 +53,301 @@ impl User {
             fhe_sk,
             fhe_pk,
             fhe_balance,
+            notes: NoteLedger::new(),
         }
     }
 
-    pub fn create_tx(&self, receiver: OracleUser, oracle: &Oracle, value: u64) -> Tx {
-        let sender = self.clone();
+    pub fn create_tx(
+        &self,
+        receiver: OracleUser,
+        oracle: &Oracle,
+        value: u64,
+    ) -> Result<Tx, OracleError> {
+        self.create_tx_with_rng(receiver, oracle, value, &mut OsRng)
+    }
 
-        let mut rng = thread_rng();
+    /// Like `create_tx`, with the randomness drawn from `rng`.
+    pub fn create_tx_with_rng<R: RngCore + CryptoRng>(
+        &self,
+        receiver: OracleUser,
+        oracle: &Oracle,
+        value: u64,
+        rng: &mut R,
+    ) -> Result<Tx, OracleError> {
+        self.create_proven_tx(receiver, oracle, ETH, value, &OpeningBackend, rng)
+    }
 
-        // this line somehow fixes the code DON'T REMOVE
-        println!("oracle balance: {}, value balance {}", sender.user_balance(oracle), value);
-        assert!(sender.user_balance(oracle) >= value, "Insufficient funds");
-        assert!(value > 0, "Value must be greater than 0");
+    /// Builds a transfer of `value` units of `asset`. The other slots of both
+    /// ciphertexts are zero, so executing it leaves the other assets as is.
+    pub fn create_asset_tx(
+        &self,
+        receiver: OracleUser,
+        oracle: &Oracle,
+        asset: AssetId,
+        value: u64,
+    ) -> Result<Tx, OracleError> {
+        self.create_proven_tx(receiver, oracle, asset, value, &OpeningBackend, &mut OsRng)
+    }
 
-        let fhe_value =
-            Plaintext::try_encode(&[value], Encoding::poly(), &oracle.parameters).unwrap();
+    /// Like `create_asset_tx`, with the transfer encrypted and proven by
+    /// `prover` using randomness from `rng`.
+    pub fn create_proven_tx<R: RngCore + CryptoRng>(
+        &self,
+        receiver: OracleUser,
+        oracle: &Oracle,
+        asset: AssetId,
+        value: u64,
+        prover: &impl TxProver,
+        rng: &mut R,
+    ) -> Result<Tx, OracleError> {
+        let sender = self.clone();
 
-        Tx::new(
-            String::new(),
+        if receiver.address == sender.address {
+            return Err(OracleError::SelfTransfer(sender.address));
+        }
+        let balance = sender.user_asset_balance(oracle, asset)?;
+        if value == 0 {
+            return Err(OracleError::InvalidAmount(value));
+        }
+        if balance < value {
+            return Err(OracleError::InsufficientFunds { balance, value });
+        }
+
+        // with an auditor registered the amount is encrypted a third time
+        // under its key
+        let transfer = prover.prove(
+            &oracle.parameters,
+            &sender.fhe_pk,
+            &receiver.fhe_pk,
+            oracle.auditor_pk.as_ref(),
+            asset,
+            value,
+            rng,
+        )?;
+
+        // the contract assigns the hash once the tx is mined, see
+        // `Tx::onchain_hash`
+        Ok(Tx::new(
+            TxHash::zero(),
             self.address.clone(),
             receiver.address.clone(),
-            sender.fhe_pk.try_encrypt(&fhe_value, &mut rng).unwrap(),
-            receiver.fhe_pk.try_encrypt(&fhe_value, &mut rng).unwrap(),
-            String::new(),
+            transfer.tx_sender,
+            transfer.tx_receiver,
+            transfer.proof.encode(),
         )
+        .with_auditor(transfer.tx_auditor))
+    }
+
+    /// Builds a request to withdraw `value` units of `asset`. Only the debit
+    /// and its opening leave the wallet, never `fhe_sk`.
+    pub fn create_withdrawal(
+        &self,
+        oracle: &Oracle,
+        asset: AssetId,
+        value: u64,
+    ) -> Result<Withdrawal, OracleError> {
+        self.create_withdrawal_with_rng(oracle, asset, value, &mut OsRng)
+    }
+
+    /// Like `create_withdrawal`, with the randomness drawn from `rng`.
+    pub fn create_withdrawal_with_rng<R: RngCore + CryptoRng>(
+        &self,
+        oracle: &Oracle,
+        asset: AssetId,
+        value: u64,
+        rng: &mut R,
+    ) -> Result<Withdrawal, OracleError> {
+        let balance = self.user_asset_balance(oracle, asset)?;
+
+        if value == 0 {
+            return Err(OracleError::InvalidAmount(value));
+        }
+        if balance < value {
+            return Err(OracleError::InsufficientFunds { balance, value });
+        }
+
+        let (tx_sender, proof) =
+            OpeningBackend.prove_withdrawal(&oracle.parameters, &self.fhe_pk, asset, value, rng)?;
+
+        Ok(Withdrawal {
+            tx_hash: TxHash::zero(),
+            sender: self.address.clone(),
+            asset,
+            value,
+            tx_sender,
+            proof: proof.encode(),
+        })
+    }
+
+    /// Moves the account to a fresh random key, see `rotate_key_to`.
+    pub fn rotate_key<R: RngCore + CryptoRng>(
+        &self,
+        oracle: &Oracle,
+        rng: &mut R,
+    ) -> Result<(User, KeyRotation, FheKeystore), OracleError> {
+        let coeffs = random_secret_coeffs(&oracle.parameters, rng);
+
+        self.rotate_key_to(oracle, &coeffs, rng)
+    }
+
+    /// Builds the rotation of this account to the secret key `coeffs`, for
+    /// instance the next account derived from the wallet. Returns the wallet
+    /// under the new key and its keystore, to be saved once the rotation has
+    /// executed; until then the old key is the one that decrypts.
+    pub fn rotate_key_to<R: RngCore + CryptoRng>(
+        &self,
+        oracle: &Oracle,
+        coeffs: &[i64],
+        rng: &mut R,
+    ) -> Result<(User, KeyRotation, FheKeystore), OracleError> {
+        let oracle_user = oracle.return_user(self.address.clone())?;
+        let balances = decode_balances(
+            &oracle.parameters,
+            &self.fhe_sk.try_decrypt(&oracle_user.fhe_balance)?,
+        )?;
+
+        let fhe_sk = SecretKey::new(coeffs.to_vec(), &oracle.parameters);
+        let fhe_pk = PublicKey::new(&fhe_sk, rng);
+        let (tx_sender, fhe_balance, proof) = OpeningBackend.prove_rotation(
+            &oracle.parameters,
+            &self.fhe_pk,
+            &fhe_pk,
+            &balances,
+            rng,
+        )?;
+
+        let keystore = FheKeystore::encrypt(
+            &oracle.parameters,
+            &self.address,
+            coeffs,
+            &fhe_pk,
+            &self.der_key,
+            user_kdf(),
+            rng,
+        )?;
+
+        let rotation = KeyRotation {
+            tx_hash: TxHash::zero(),
+            sender: self.address.clone(),
+            fhe_pk: fhe_pk.clone(),
+            tx_sender,
+            fhe_balance: fhe_balance.clone(),
+            proof: proof.encode(),
+            nonce: 0,
+            signature: None,
+        };
+        let user = User {
+            fhe_sk,
+            fhe_pk,
+            fhe_balance,
+            ..self.clone()
+        };
+
+        Ok((user, rotation, keystore))
+    }
+
+    pub fn user_balance(&self, oracle: &Oracle) -> Result<u64, OracleError> {
+        self.user_asset_balance(oracle, ETH)
     }
 
-    pub fn user_balance(&self, oracle: &Oracle) -> u64 {
-        let oracle_user = oracle.users.get(&self.address).unwrap();
-        let decrypted_plaintext = self.fhe_sk.try_decrypt(&oracle_user.fhe_balance).unwrap();
-        let decrypted_vector =
-            Vec::<u64>::try_decode(&decrypted_plaintext, Encoding::poly()).unwrap();
+    pub fn user_asset_balance(&self, oracle: &Oracle, asset: AssetId) -> Result<u64, OracleError> {
+        let oracle_user = oracle.return_user(self.address.clone())?;
+        let decrypted_plaintext = self.fhe_sk.try_decrypt(&oracle_user.fhe_balance)?;
 
-        decrypted_vector[0]
+        decode_asset(&oracle.parameters, &decrypted_plaintext, asset)
     }
+
+    /// Takes the deposits, transfers, rotations and withdrawals of this
+    /// account in `logs` into its notes, leaving out those the Oracle
+    /// `refused`. Signed transfers are attributed to their signer for
+    /// `domain`. Returns the logs that could not be read.
+    pub fn track_logs(
+        &mut self,
+        parameters: &Arc<fhe::bfv::BfvParameters>,
+        domain: &SigningDomain,
+        logs: &[OracleLog],
+        refused: &[SkippedLog],
+    ) -> Vec<SkippedLog> {
+        self.notes.track_logs(
+            parameters,
+            domain,
+            &self.address,
+            &self.fhe_pk,
+            logs,
+            refused,
+        )
+    }
+
+    /// Adds up the notes and sets the result against the Oracle's
+    /// ciphertext. A check that does not match means the Oracle holds a
+    /// balance the chain does not account for.
+    pub fn check_balance(&self, oracle: &Oracle) -> Result<BalanceCheck, OracleError> {
+        self.notes
+            .check_balance(oracle, &self.address, &self.fhe_sk)
+    }
+
+    /// Decrypts every ciphertext of a balance challenge. The slots look
+    /// random, the node alone can unmask the one that matters.
+    pub fn answer_challenge(
+        &self,
+        challenge: &BalanceChallenge,
+    ) -> Result<BalanceResponse, OracleError> {
+        let slots = challenge
+            .ciphertexts
+            .iter()
+            .map(|ct| decode_slots(&self.fhe_sk.try_decrypt(ct)?))
+            .collect::<Result<_, OracleError>>()?;
+
+        Ok(BalanceResponse { slots })
+    }
+}
+
+pub fn keystore_path(address: &str) -> String {
+    // tests keep their keystores out of the repository's keys/
+    #[cfg(test)]
+    let dir = std::env::temp_dir().join("fhe_keys").display().to_string();
+    #[cfg(not(test))]
+    let dir = "keys".to_string();
+
+    format!("{}/{}.json", dir, address)
+}
+
+// scrypt at full cost takes a second per key, too slow for the test suite
+fn user_kdf() -> KdfParams {
+    if cfg!(test) {
+        KdfParams::light()
+    } else {
+        KdfParams::default()
+    }
+}
+
+/// Unlocks the keystore at `key_path` with `password` and picks up the
+/// account's balance from `oracle`.
+pub fn load_user(
+    key_path: &str,
+    password: &str,
+    oracle: &Oracle,
+) -> Result<User, Box<dyn std::error::Error>> {
+    let keystore = FheKeystore::load(key_path)?;
+    let fhe_sk = keystore.decrypt(&oracle.parameters, password)?;
+    let fhe_pk = keystore.public_key(&oracle.parameters)?;
+    let fhe_balance = oracle.return_user_fhe_balance(keystore.address.clone())?;
+
+    Ok(User::new(
+        keystore.address,
+        key_path.to_string(),
+        password.to_string(),
+        fhe_sk,
+        fhe_pk,
+        fhe_balance,
+    ))
 }
 
 // TODO: add a function to create a user if does't
-pub fn decoded_user_balance(user: &User) -> u64 {
+pub fn decoded_user_balance(user: &User, parameters: &Arc<fhe::bfv::BfvParameters>) -> u64 {
     let decrypted_plaintext = user.fhe_sk.try_decrypt(&user.fhe_balance).unwrap();
-    let decrypted_vector = Vec::<u64>::try_decode(&decrypted_plaintext, Encoding::poly()).unwrap();
 
-    decrypted_vector[0]
+    decode_asset(parameters, &decrypted_plaintext, ETH).unwrap()
 }
 
 // TODO: add a function to create a user if does't
@@ -84,41 +356,161 @@ pub fn create_user(
     parameters: Arc<fhe::bfv::BfvParameters>,
     der_key: Option<String>,
     start_balance: Option<u64>,
-) -> User {
-    let mut rng = thread_rng();
+) -> Result<User, OracleError> {
+    create_user_with_balances(address, parameters, der_key, &[start_balance.unwrap_or(0)])
+}
+
+/// Like `create_user`, with a starting balance for each asset.
+pub fn create_user_with_balances(
+    address: String,
+    parameters: Arc<fhe::bfv::BfvParameters>,
+    der_key: Option<String>,
+    start_balances: &[u64],
+) -> Result<User, OracleError> {
+    create_user_with_rng(address, parameters, der_key, start_balances, &mut OsRng)
+}
 
+/// Like `create_user_with_balances`, with the keys and the starting balance
+/// drawn from `rng`.
+pub fn create_user_with_rng<R: RngCore + CryptoRng>(
+    address: String,
+    parameters: Arc<fhe::bfv::BfvParameters>,
+    der_key: Option<String>,
+    start_balances: &[u64],
+    rng: &mut R,
+) -> Result<User, OracleError> {
     let der_key = der_key.unwrap_or("default".to_string());
-    let start_balance = start_balance.unwrap_or(0);
 
-    let mut key_path = "keys/".to_string() + &address;
-    let sk = SecretKey::random_and_write_to_file(&parameters, &mut OsRng, &mut key_path);
+    let coeffs = random_secret_coeffs(&parameters, rng);
+    let sk = SecretKey::new(coeffs.clone(), &parameters);
+    let pk = PublicKey::new(&sk, rng);
+
+    build_user(
+        address,
+        &parameters,
+        der_key,
+        start_balances,
+        &coeffs,
+        sk,
+        pk,
+        rng,
+    )
+}
 
-    let pk = PublicKey::new(&sk, &mut rng);
+/// Like `create_user_with_rng`, with the keys derived from `wallet` under
+/// `account`, so the wallet alone can bring them back with `recover_user`.
+pub fn create_user_from_wallet<R: RngCore + CryptoRng>(
+    address: String,
+    parameters: Arc<fhe::bfv::BfvParameters>,
+    der_key: Option<String>,
+    start_balances: &[u64],
+    wallet: &LocalWallet,
+    account: u32,
+    rng: &mut R,
+) -> Result<User, OracleError> {
+    let der_key = der_key.unwrap_or("default".to_string());
+    let keys = derive_keys(wallet, &parameters, account)?;
+
+    build_user(
+        address,
+        &parameters,
+        der_key,
+        start_balances,
+        &keys.coeffs,
+        keys.fhe_sk,
+        keys.fhe_pk,
+        rng,
+    )
+}
 
-    let balance: Plaintext =
-        Plaintext::try_encode(&[start_balance], Encoding::poly(), &parameters).unwrap();
-    let fhe_balance: Ciphertext = sk.try_encrypt(&balance, &mut rng).unwrap();
+/// Re-derives the keys of `wallet` under `account` after the keystore was
+/// lost, checks them against the key the Oracle has registered and writes a
+/// fresh keystore under `der_key`.
+pub fn recover_user(
+    wallet: &LocalWallet,
+    account: u32,
+    der_key: &str,
+    oracle: &Oracle,
+) -> Result<User, Box<dyn std::error::Error>> {
+    let address = to_checksum(&wallet.address(), None);
+    let registered = oracle.return_user(address.clone())?;
+    let keys = derive_keys(wallet, &oracle.parameters, account)?;
+
+    if keys.fhe_pk != registered.fhe_pk {
+        return Err(format!(
+            "{} is not registered under a key derived at {}",
+            address, keys.path
+        )
+        .into());
+    }
 
-    User::new(address, key_path, der_key, sk, pk, fhe_balance).clone()
+    let key_path = keystore_path(&address);
+    FheKeystore::encrypt(
+        &oracle.parameters,
+        &address,
+        &keys.coeffs,
+        &keys.fhe_pk,
+        der_key,
+        user_kdf(),
+        &mut OsRng,
+    )?
+    .save(&key_path)?;
+
+    Ok(User::new(
+        address,
+        key_path,
+        der_key.to_string(),
+        keys.fhe_sk,
+        keys.fhe_pk,
+        registered.fhe_balance.clone(),
+    ))
+}
+
+// encrypts the starting balance and writes the keystore, the secret key
+// only ever reaches the disk encrypted under `der_key`
+#[allow(clippy::too_many_arguments)]
+fn build_user<R: RngCore + CryptoRng>(
+    address: String,
+    parameters: &Arc<fhe::bfv::BfvParameters>,
+    der_key: String,
+    start_balances: &[u64],
+    coeffs: &[i64],
+    sk: SecretKey,
+    pk: PublicKey,
+    rng: &mut R,
+) -> Result<User, OracleError> {
+    let key_path = keystore_path(&address);
+    FheKeystore::encrypt(parameters, &address, coeffs, &pk, &der_key, user_kdf(), rng)?
+        .save(&key_path)?;
+
+    let balance: Plaintext = encode_balances(parameters, start_balances)?;
+    // under the public key, like every later update, so balance challenges
+    // can match its noise
+    let fhe_balance: Ciphertext = pk.try_encrypt(&balance, rng)?;
+
+    Ok(User::new(address, key_path, der_key, sk, pk, fhe_balance))
 }
 
 #[cfg(test)]
 pub mod test {
     use super::*;
     use crate::fhe_account_handler::get_keys::tests::create_users;
+    use crate::fhe_node::fhe_attestation::challenge_debit;
+    use crate::fhe_node::fhe_attestation::tests::{attest_debit, attest_rotation};
+    use crate::fhe_node::fhe_params::ParameterSet;
     #[test]
     pub fn test_create_users() {
         let init_alice_balance = 100;
         let init_bob_balance = 50;
 
-        let (fhe_oracle, alice, bob, owner) = create_users(100, 50);
+        let (fhe_oracle, alice, bob, ..) = create_users(100, 50);
 
         assert!(
-            decoded_user_balance(&alice) == init_alice_balance,
+            decoded_user_balance(&alice, &fhe_oracle.parameters) == init_alice_balance,
             "Alice's balance is incorrect"
         );
         assert!(
-            decoded_user_balance(&bob) == init_bob_balance,
+            decoded_user_balance(&bob, &fhe_oracle.parameters) == init_bob_balance,
             "Bob's balance is incorrect"
         );
     }
@@ -129,13 +521,15 @@ pub mod test {
         let init_bob_balance = 50;
         let delta_balance = 10;
 
-        let (fhe_oracle, alice, bob, owner) = create_users(100, 50);
+        let (fhe_oracle, alice, bob, ..) = create_users(100, 50);
 
         let bob_user: OracleUser = fhe_oracle.users[&bob.address].clone();
 
-        let txs = alice.create_tx(bob_user, &fhe_oracle, delta_balance);
+        let txs = alice
+            .create_tx(bob_user, &fhe_oracle, delta_balance)
+            .unwrap();
 
-        let fhe_oracle = txs.execute_tx(&mut fhe_oracle.clone());
+        let fhe_oracle = txs.execute_tx(&mut fhe_oracle.clone()).unwrap();
 
         let alice_oracle = fhe_oracle.users[&alice.address].clone();
         let bob_oracle = fhe_oracle.users[&bob.address].clone();
@@ -151,58 +545,272 @@ pub mod test {
         };
 
         assert!(
-            decoded_user_balance(&alice) == init_alice_balance - delta_balance,
+            decoded_user_balance(&alice, &fhe_oracle.parameters)
+                == init_alice_balance - delta_balance,
             "Alice's balance is incorrect"
         );
 
         assert!(
-            decoded_user_balance(&bob) == init_bob_balance + delta_balance,
+            decoded_user_balance(&bob, &fhe_oracle.parameters) == init_bob_balance + delta_balance,
             "Bob's balance is incorrect"
         );
     }
 
+    #[test]
+    fn test_tx_rejects_bad_amounts() {
+        let (fhe_oracle, alice, bob, ..) = create_users(100, 50);
+        let bob_user: OracleUser = fhe_oracle.users[&bob.address].clone();
+
+        assert_eq!(
+            alice.create_tx(bob_user.clone(), &fhe_oracle, 101).err(),
+            Some(OracleError::InsufficientFunds {
+                balance: 100,
+                value: 101
+            })
+        );
+        assert_eq!(
+            alice.create_tx(bob_user.clone(), &fhe_oracle, 0).err(),
+            Some(OracleError::InvalidAmount(0))
+        );
+
+        let alice_user: OracleUser = fhe_oracle.users[&alice.address].clone();
+        assert_eq!(
+            alice.create_tx(alice_user, &fhe_oracle, 10).err(),
+            Some(OracleError::SelfTransfer(alice.address.clone()))
+        );
+
+        // a self-transfer built by hand would credit without the debit
+        let mut tx = alice.create_tx(bob_user, &fhe_oracle, 10).unwrap();
+        tx.receiver = alice.address.clone();
+        let mut executed = fhe_oracle.clone();
+        assert_eq!(
+            tx.execute_tx(&mut executed).err(),
+            Some(OracleError::SelfTransfer(alice.address.clone()))
+        );
+        assert_eq!(alice.user_balance(&executed).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_multi_asset_tx() {
+        let parameters = ParameterSet::preset("bfv-simd-2048")
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut fhe_oracle = Oracle::from_parameters(parameters.clone());
+
+        let alice =
+            create_user_with_balances("0xa1".to_string(), parameters.clone(), None, &[100, 7])
+                .unwrap();
+        let bob = create_user_with_balances("0xb0".to_string(), parameters.clone(), None, &[50, 1])
+            .unwrap();
+        fhe_oracle
+            .add_user(alice.address.clone(), OracleUser::from_user(alice.clone()))
+            .unwrap();
+        fhe_oracle
+            .add_user(bob.address.clone(), OracleUser::from_user(bob.clone()))
+            .unwrap();
+
+        let tx = alice
+            .create_asset_tx(fhe_oracle.users[&bob.address].clone(), &fhe_oracle, 1, 5)
+            .unwrap();
+        let fhe_oracle = tx.execute_tx(&mut fhe_oracle.clone()).unwrap();
+
+        assert_eq!(alice.user_asset_balance(&fhe_oracle, 0).unwrap(), 100);
+        assert_eq!(alice.user_asset_balance(&fhe_oracle, 1).unwrap(), 2);
+        assert_eq!(bob.user_asset_balance(&fhe_oracle, 0).unwrap(), 50);
+        assert_eq!(bob.user_asset_balance(&fhe_oracle, 1).unwrap(), 6);
+        assert_eq!(
+            alice
+                .create_asset_tx(fhe_oracle.users[&bob.address].clone(), &fhe_oracle, 1, 3)
+                .err(),
+            Some(OracleError::InsufficientFunds {
+                balance: 2,
+                value: 3
+            })
+        );
+    }
+
     #[test]
     fn test_tx_withdraw() {
         let init_alice_balance = 100;
         let delta_balance = 20;
 
-        let (mut fhe_oracle, alice, bob, owner) = create_users(100, 50);
+        let (mut fhe_oracle, alice, ..) = create_users(100, 50);
 
-        let new_alice = create_user(
-            alice.address.clone(),
-            fhe_oracle.parameters.clone(),
-            None,
-            Some(0),
+        let withdrawal = alice
+            .create_withdrawal(&fhe_oracle, ETH, delta_balance)
+            .unwrap();
+
+        // claiming more than the debit opens to is refused
+        let inflated = Withdrawal {
+            value: 50,
+            ..withdrawal.clone()
+        };
+        assert!(matches!(
+            inflated.execute_withdrawal(&mut fhe_oracle.clone()),
+            Err(OracleError::InvalidProof(_))
+        ));
+
+        // a payout is never made on trust, attestation required or not
+        assert_eq!(
+            withdrawal.execute_withdrawal(&mut fhe_oracle.clone()).err(),
+            Some(OracleError::UnattestedDebit(alice.address.clone()))
+        );
+        attest_debit(&mut fhe_oracle, &alice, &withdrawal.tx_sender);
+        fhe_oracle = withdrawal.execute_withdrawal(&mut fhe_oracle).unwrap();
+
+        // the key is kept and only the withdrawn amount is gone
+        assert_eq!(
+            fhe_oracle.return_user_pk(alice.address.clone()).unwrap(),
+            alice.fhe_pk
+        );
+        assert_eq!(
+            alice.user_balance(&fhe_oracle).unwrap(),
+            init_alice_balance - delta_balance
         );
+        assert_eq!(
+            alice.create_withdrawal(&fhe_oracle, ETH, 81).err(),
+            Some(OracleError::InsufficientFunds {
+                balance: 80,
+                value: 81
+            })
+        );
+    }
 
-        let new_alice_oracle_user: OracleUser = OracleUser::from_user(new_alice.clone());
+    #[test]
+    fn test_load_user_from_keystore() {
+        // an address of its own, other tests rewrite alice's keystore
+        let mut fhe_oracle = Oracle::new();
+        let carol = create_user(
+            "0xca".to_string(),
+            fhe_oracle.parameters.clone(),
+            Some("carol's password".to_string()),
+            Some(100),
+        )
+        .unwrap();
+        fhe_oracle
+            .add_user(carol.address.clone(), OracleUser::from_user(carol.clone()))
+            .unwrap();
 
-        let txs = alice.create_tx(new_alice_oracle_user.clone(), &fhe_oracle, 80);
+        let loaded = load_user(&carol.key_path, "carol's password", &fhe_oracle).unwrap();
+        assert_eq!(loaded.address, carol.address);
+        assert_eq!(loaded.fhe_pk, carol.fhe_pk);
+        assert_eq!(loaded.user_balance(&fhe_oracle).unwrap(), 100);
 
-        let sk = alice.fhe_sk.clone();
-        let new_pk = new_alice_oracle_user.fhe_pk.clone();
-        let new_fhe_balance: Ciphertext = new_alice.fhe_balance.clone();
+        assert!(load_user(&carol.key_path, "not the password", &fhe_oracle).is_err());
+    }
 
-        fhe_oracle = txs.execute_withdrawal(
-            &mut fhe_oracle,
-            sk,
-            delta_balance,
-            new_pk.clone(),
-            new_fhe_balance,
+    #[test]
+    fn test_rotate_key_keeps_the_balance() {
+        let (mut fhe_oracle, alice, bob, ..) = create_users(100, 50);
+        fhe_oracle.require_signatures = true;
+        let wallet: LocalWallet = get_keys("user").unwrap().private_key.parse().unwrap();
+
+        let (rotated, rotation, keystore) = alice.rotate_key(&fhe_oracle, &mut OsRng).unwrap();
+        assert!(matches!(
+            rotation.execute_rotation(&mut fhe_oracle.clone()),
+            Err(OracleError::InvalidSignature(_))
+        ));
+        let rotation = rotation
+            .sign(0, &fhe_oracle.signing_domain, &wallet)
+            .unwrap();
+
+        // attestation off or not, the debit has to take the whole balance
+        assert_eq!(
+            rotation.execute_rotation(&mut fhe_oracle.clone()).err(),
+            Some(OracleError::UnattestedDebit(alice.address.clone()))
         );
-
-        //assert the new fhe_oracle has the updated pk for alice
+        let (challenge, pending) = challenge_debit(
+            &fhe_oracle,
+            &rotation.sender,
+            &rotation.tx_sender,
+            4,
+            &mut OsRng,
+        )
+        .unwrap();
+        pending
+            .check(
+                &mut fhe_oracle,
+                &alice.answer_challenge(&challenge).unwrap(),
+            )
+            .unwrap();
         assert_eq!(
-            fhe_oracle.return_user_pk(new_alice.address.clone()),
-            new_pk.clone()
+            rotation.execute_rotation(&mut fhe_oracle.clone()).err(),
+            Some(OracleError::UnattestedDebit(alice.address.clone()))
         );
+        attest_rotation(&mut fhe_oracle, &alice, &rotation);
 
-        let new_alice = User {
-            fhe_balance: txs.tx_receiver.clone(),
-            ..new_alice
+        // the signature covers the nonce
+        let reordered = KeyRotation {
+            nonce: 1,
+            ..rotation.clone()
         };
-        let new_user_balance = decoded_user_balance(&new_alice.clone());
+        assert!(matches!(
+            reordered.execute_rotation(&mut fhe_oracle.clone()),
+            Err(OracleError::InvalidSignature(_))
+        ));
+
+        // a new balance that does not match the debit is refused
+        let inflated = KeyRotation {
+            fhe_balance: bob.fhe_balance.clone(),
+            ..rotation.clone()
+        }
+        .sign(0, &fhe_oracle.signing_domain, &wallet)
+        .unwrap();
+        assert!(matches!(
+            inflated.execute_rotation(&mut fhe_oracle.clone()),
+            Err(OracleError::InvalidProof(_))
+        ));
+
+        let mut fhe_oracle = rotation.execute_rotation(&mut fhe_oracle).unwrap();
+        assert_eq!(
+            fhe_oracle.return_user_pk(alice.address.clone()).unwrap(),
+            rotated.fhe_pk
+        );
+        assert_eq!(rotated.user_balance(&fhe_oracle).unwrap(), 100);
+        assert!(keystore
+            .decrypt(&fhe_oracle.parameters, &alice.der_key)
+            .is_ok());
+        assert_eq!(fhe_oracle.users[&alice.address].nonce, 1);
+
+        // the new key spends as usual
+        let tx = rotated
+            .create_tx(fhe_oracle.users[&bob.address].clone(), &fhe_oracle, 10)
+            .unwrap()
+            .sign(1, &fhe_oracle.signing_domain, &wallet)
+            .unwrap();
+        let fhe_oracle = tx.execute_tx(&mut fhe_oracle.clone()).unwrap();
+        assert_eq!(rotated.user_balance(&fhe_oracle).unwrap(), 90);
+    }
 
-        assert_eq!(new_user_balance, init_alice_balance - delta_balance)
+    #[test]
+    fn test_recover_user_from_wallet() {
+        let parameters = ParameterSet::default_preset().build().unwrap();
+        let mut fhe_oracle = Oracle::from_parameters(parameters.clone());
+        let keys = get_keys("dave").unwrap();
+        let wallet: LocalWallet = keys.private_key.parse().unwrap();
+
+        let dave = create_user_from_wallet(
+            keys.public_key.to_string(),
+            parameters.clone(),
+            None,
+            &[100],
+            &wallet,
+            0,
+            &mut OsRng,
+        )
+        .unwrap();
+        fhe_oracle
+            .add_user(dave.address.clone(), OracleUser::from_user(dave.clone()))
+            .unwrap();
+        std::fs::remove_file(&dave.key_path).unwrap();
+
+        let recovered = recover_user(&wallet, 0, "default", &fhe_oracle).unwrap();
+        assert_eq!(recovered.fhe_pk, dave.fhe_pk);
+        assert_eq!(recovered.user_balance(&fhe_oracle).unwrap(), 100);
+        assert!(load_user(&dave.key_path, "default", &fhe_oracle).is_ok());
+
+        // another account under the same address is another key
+        assert!(recover_user(&wallet, 1, "default", &fhe_oracle).is_err());
     }
 }
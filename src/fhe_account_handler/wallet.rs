@@ -0,0 +1,369 @@
+use crate::fhe_account_handler::derivation::derive_keys;
+use crate::fhe_account_handler::user::{create_user_from_wallet, keystore_path, User};
+use crate::fhe_node::fhe_assets::encode_balances;
+use crate::fhe_node::fhe_oracle::{Oracle, OracleError};
+use crate::fhe_node::fhe_store::write_atomic;
+use ethers::signers::{LocalWallet, Signer};
+use ethers::types::Address;
+use ethers::utils::to_checksum;
+use fhe::bfv::BfvParameters;
+use fhe_traits::FheEncrypter;
+use rand::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const WALLET_VERSION: u32 = 1;
+
+pub const DEFAULT_WALLET_DIR: &str = "wallet";
+
+const INDEX_FILE: &str = "wallet.json";
+
+/// What `wallet.json` records for an account. The signer sits next to it
+/// in an Ethereum V3 keystore and the FHE keys are derived from the signer,
+/// so nothing secret is kept in the clear.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct WalletEntry {
+    pub address: String,
+    pub fhe_account: u32,
+    pub keystore: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+struct WalletIndex {
+    version: u32,
+    accounts: Vec<WalletEntry>,
+}
+
+/// Accounts are keyed by their checksummed address, so lookups do not depend
+/// on how the caller spelled it.
+fn wallet_key(address: &str) -> String {
+    match address.parse::<Address>() {
+        Ok(address) => to_checksum(&address, None),
+        Err(_) => address.to_string(),
+    }
+}
+
+/// One Ethereum address with its signer, its FHE keys and the last balance
+/// read from the Oracle.
+#[derive(Clone)]
+pub struct WalletAccount {
+    pub signer: LocalWallet,
+    pub fhe_account: u32,
+    pub user: User,
+    /// ETH balance as of the last `refresh_balances`, `None` until then or
+    /// while the Oracle does not know the account.
+    pub cached_balance: Option<u64>,
+}
+
+/// The accounts a node acts for, kept in a wallet directory under one
+/// password.
+pub struct Wallet {
+    dir: PathBuf,
+    password: String,
+    accounts: BTreeMap<String, WalletAccount>,
+    // accounts changed or removed since the last save
+    dirty: BTreeSet<String>,
+}
+
+impl Wallet {
+    /// An empty wallet that will be saved to `dir` under `password`.
+    pub fn new<P: AsRef<Path>>(dir: P, password: &str) -> Wallet {
+        Wallet {
+            dir: dir.as_ref().to_path_buf(),
+            password: password.to_string(),
+            accounts: BTreeMap::new(),
+            dirty: BTreeSet::new(),
+        }
+    }
+
+    /// Unlocks every signer in `dir` with `password` and re-derives its FHE
+    /// keys. Balances are read from `oracle`, accounts it does not know yet
+    /// start with an empty one. A missing directory is an empty wallet.
+    pub fn open<P: AsRef<Path>, R: RngCore + CryptoRng>(
+        dir: P,
+        password: &str,
+        oracle: &Oracle,
+        rng: &mut R,
+    ) -> Result<Wallet, Box<dyn std::error::Error>> {
+        let mut wallet = Wallet::new(dir, password);
+
+        let index = match std::fs::read(wallet.dir.join(INDEX_FILE)) {
+            Ok(bytes) => serde_json::from_slice::<WalletIndex>(&bytes)?,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(wallet),
+            Err(error) => return Err(error.into()),
+        };
+        if index.version != WALLET_VERSION {
+            return Err(format!("unsupported wallet version {}", index.version).into());
+        }
+
+        for entry in index.accounts {
+            let signer = LocalWallet::decrypt_keystore(wallet.dir.join(&entry.keystore), password)?;
+            if to_checksum(&signer.address(), None) != entry.address {
+                return Err(format!(
+                    "{} does not hold the key of {}",
+                    entry.keystore, entry.address
+                )
+                .into());
+            }
+
+            wallet.add_account(signer, entry.fhe_account, oracle, rng)?;
+        }
+
+        wallet.refresh_balances(oracle);
+        wallet.dirty.clear();
+
+        Ok(wallet)
+    }
+
+    /// Writes the signers added or replaced since the last save as Ethereum
+    /// V3 keystores, drops those of removed accounts and rewrites the index
+    /// that lists them. Keystores of untouched accounts are left as they are.
+    pub fn save<R: RngCore + CryptoRng>(
+        &mut self,
+        rng: &mut R,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::create_dir_all(&self.dir)?;
+
+        for address in self.dirty.iter() {
+            let keystore = format!("{}.eth.json", address);
+            match self.accounts.get(address) {
+                Some(account) => {
+                    LocalWallet::encrypt_keystore(
+                        &self.dir,
+                        rng,
+                        account.signer.signer().to_bytes(),
+                        &self.password,
+                        Some(&keystore),
+                    )?;
+                }
+                None => match std::fs::remove_file(self.dir.join(&keystore)) {
+                    Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+                        return Err(error.into())
+                    }
+                    _ => {}
+                },
+            }
+        }
+
+        let mut accounts = Vec::with_capacity(self.accounts.len());
+        for (address, account) in self.accounts.iter() {
+            let keystore = format!("{}.eth.json", address);
+            accounts.push(WalletEntry {
+                address: address.clone(),
+                fhe_account: account.fhe_account,
+                keystore,
+            });
+        }
+
+        let index = WalletIndex {
+            version: WALLET_VERSION,
+            accounts,
+        };
+        write_atomic(
+            &self.dir.join(INDEX_FILE),
+            &serde_json::to_vec_pretty(&index)?,
+        )?;
+        self.dirty.clear();
+
+        Ok(())
+    }
+
+    /// Adds the account of `signer` with its FHE keys derived under
+    /// `fhe_account`, or replaces it if the address is already there.
+    pub fn add_account<R: RngCore + CryptoRng>(
+        &mut self,
+        signer: LocalWallet,
+        fhe_account: u32,
+        oracle: &Oracle,
+        rng: &mut R,
+    ) -> Result<&mut WalletAccount, OracleError> {
+        let address = to_checksum(&signer.address(), None);
+        let keys = derive_keys(&signer, &oracle.parameters, fhe_account)?;
+
+        let fhe_balance = match oracle.return_user_fhe_balance(address.clone()) {
+            Ok(fhe_balance) => fhe_balance,
+            Err(_) => keys
+                .fhe_pk
+                .try_encrypt(&encode_balances(&oracle.parameters, &[0])?, rng)?,
+        };
+
+        let user = User::new(
+            address.clone(),
+            keystore_path(&address),
+            self.password.clone(),
+            keys.fhe_sk,
+            keys.fhe_pk,
+            fhe_balance,
+        );
+
+        self.insert(signer, fhe_account, user)
+    }
+
+    /// A new user for `signer` with its FHE keys derived under `fhe_account`
+    /// and `start_balances` encrypted under them. Its FHE keystore is written
+    /// under the wallet password, like that of every other account.
+    pub fn create_user<R: RngCore + CryptoRng>(
+        &self,
+        signer: &LocalWallet,
+        fhe_account: u32,
+        parameters: &Arc<BfvParameters>,
+        start_balances: &[u64],
+        rng: &mut R,
+    ) -> Result<User, OracleError> {
+        create_user_from_wallet(
+            to_checksum(&signer.address(), None),
+            parameters.clone(),
+            Some(self.password.clone()),
+            start_balances,
+            signer,
+            fhe_account,
+            rng,
+        )
+    }
+
+    /// Adds `user` under its signer as is, or replaces the account if the
+    /// address is already there.
+    pub fn insert(
+        &mut self,
+        signer: LocalWallet,
+        fhe_account: u32,
+        user: User,
+    ) -> Result<&mut WalletAccount, OracleError> {
+        let address = to_checksum(&signer.address(), None);
+        if address != wallet_key(&user.address) {
+            return Err(OracleError::SignerMismatch {
+                signer: address,
+                address: user.address,
+            });
+        }
+
+        self.dirty.insert(address.clone());
+        self.accounts.insert(
+            address.clone(),
+            WalletAccount {
+                signer,
+                fhe_account,
+                user,
+                cached_balance: None,
+            },
+        );
+
+        Ok(self.accounts.get_mut(&address).unwrap())
+    }
+
+    pub fn remove(&mut self, address: &str) -> Option<WalletAccount> {
+        let address = wallet_key(address);
+        let account = self.accounts.remove(&address)?;
+        self.dirty.insert(address);
+
+        Some(account)
+    }
+
+    pub fn get(&self, address: &str) -> Option<&WalletAccount> {
+        self.accounts.get(&wallet_key(address))
+    }
+
+    pub fn get_mut(&mut self, address: &str) -> Option<&mut WalletAccount> {
+        self.accounts.get_mut(&wallet_key(address))
+    }
+
+    pub fn addresses(&self) -> Vec<String> {
+        self.accounts.keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+
+    /// Picks up every account's balance from `oracle` and decrypts it.
+    pub fn refresh_balances(&mut self, oracle: &Oracle) {
+        for (address, account) in self.accounts.iter_mut() {
+            account.cached_balance = match oracle.return_user_fhe_balance(address.clone()) {
+                Ok(fhe_balance) => {
+                    account.user.fhe_balance = fhe_balance;
+                    account.user.user_balance(oracle).ok()
+                }
+                Err(_) => None,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhe_account_handler::get_keys::get_keys;
+    use crate::fhe_node::fhe_oracle::OracleUser;
+    use rand::thread_rng;
+
+    fn signer(name: &str) -> LocalWallet {
+        get_keys(name).unwrap().private_key.parse().unwrap()
+    }
+
+    #[test]
+    fn test_wallet_round_trip() {
+        let mut fhe_oracle = Oracle::new();
+        let dir =
+            std::env::temp_dir().join(format!("fhe_wallet_round_trip_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut wallet = Wallet::new(&dir, "pw");
+        for name in ["charlie", "dave"] {
+            let account = wallet
+                .add_account(signer(name), 0, &fhe_oracle, &mut thread_rng())
+                .unwrap();
+            let user = account.user.clone();
+            let funded = User {
+                fhe_balance: user
+                    .fhe_pk
+                    .try_encrypt(
+                        &encode_balances(&fhe_oracle.parameters, &[40]).unwrap(),
+                        &mut thread_rng(),
+                    )
+                    .unwrap(),
+                ..user
+            };
+            fhe_oracle
+                .add_user(funded.address.clone(), OracleUser::from_user(funded))
+                .unwrap();
+        }
+        wallet.save(&mut thread_rng()).unwrap();
+
+        // only the account that changed gets a new keystore
+        let address = |name: &str| to_checksum(&signer(name).address(), None);
+        let keystore =
+            |name: &str| std::fs::read(dir.join(format!("{}.eth.json", address(name)))).unwrap();
+        let (charlie, dave) = (keystore("charlie"), keystore("dave"));
+        let user = wallet.get(&address("charlie")).unwrap().user.clone();
+        wallet.insert(signer("charlie"), 0, user.clone()).unwrap();
+        wallet.save(&mut thread_rng()).unwrap();
+        assert_ne!(keystore("charlie"), charlie);
+        assert_eq!(keystore("dave"), dave);
+
+        assert_eq!(
+            wallet.insert(signer("dave"), 0, user).map(|_| ()),
+            Err(OracleError::SignerMismatch {
+                signer: address("dave"),
+                address: address("charlie"),
+            })
+        );
+
+        let opened = Wallet::open(&dir, "pw", &fhe_oracle, &mut thread_rng()).unwrap();
+        assert_eq!(opened.addresses(), wallet.addresses());
+
+        let dave = get_keys("dave").unwrap().public_key;
+        let account = opened.get(&dave.to_lowercase()).unwrap();
+        assert_eq!(account.user.fhe_pk, wallet.get(dave).unwrap().user.fhe_pk);
+        assert_eq!(account.cached_balance, Some(40));
+        assert_eq!(account.signer.address(), signer("dave").address());
+
+        assert!(Wallet::open(&dir, "not pw", &fhe_oracle, &mut thread_rng()).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,296 @@
+use crate::fhe_node::fhe_oracle::OracleError;
+use fhe::bfv::{BfvParameters, Encoding, Plaintext};
+use fhe_traits::{FheDecoder, FheEncoder};
+use std::sync::Arc;
+
+/// Index of a token type; every asset owns `limbs` consecutive slots of the
+/// balance vector.
+pub type AssetId = usize;
+
+pub const ETH: AssetId = 0;
+
+/// Bits left free above each limb. Every homomorphic add or sub moves a limb
+/// by less than one limb base, so an account takes about
+/// `2^LIMB_HEADROOM_BITS` transfers, `BalanceLayout::max_updates` exactly,
+/// before its balance has to be encrypted afresh by a key rotation.
+pub const LIMB_HEADROOM_BITS: u32 = 6;
+
+/// How an amount is spread over slots: little-endian limbs of `limb_bits`
+/// bits, read back as signed values modulo the plaintext modulus so that
+/// limb-wise subtractions borrow instead of wrapping.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BalanceLayout {
+    pub plaintext_modulus: u64,
+    pub limb_bits: u32,
+    pub limbs: usize,
+}
+
+impl BalanceLayout {
+    pub fn new(parameters: &Arc<BfvParameters>) -> Result<BalanceLayout, OracleError> {
+        let plaintext_modulus = parameters.plaintext();
+        // one bit goes to the sign of a centered limb
+        let modulus_bits = 63 - plaintext_modulus.leading_zeros();
+        let limb_bits = modulus_bits.saturating_sub(1 + LIMB_HEADROOM_BITS);
+
+        if limb_bits == 0 {
+            return Err(OracleError::Unrepresentable(1));
+        }
+
+        let limbs = ((u64::BITS + limb_bits - 1) / limb_bits) as usize;
+
+        Ok(BalanceLayout {
+            plaintext_modulus,
+            limb_bits,
+            limbs: limbs.min(parameters.degree()),
+        })
+    }
+
+    /// How many homomorphic adds and subs a freshly encrypted balance takes
+    /// before a limb could leave the range `join` reads as signed and wrap.
+    pub fn max_updates(&self) -> u64 {
+        let max_limb = (1u64 << self.limb_bits) - 1;
+
+        (self.plaintext_modulus / 2 / max_limb).saturating_sub(1)
+    }
+
+    /// Largest amount the limbs can hold.
+    pub fn max_amount(&self) -> u64 {
+        let bits = self.limb_bits as usize * self.limbs;
+        if bits >= u64::BITS as usize {
+            u64::MAX
+        } else {
+            (1u64 << bits) - 1
+        }
+    }
+
+    pub fn split(&self, value: u64) -> Result<Vec<u64>, OracleError> {
+        if value > self.max_amount() {
+            return Err(OracleError::Unrepresentable(value));
+        }
+
+        let mask = (1u64 << self.limb_bits) - 1;
+
+        Ok((0..self.limbs)
+            .map(|i| {
+                let shift = self.limb_bits as usize * i;
+                if shift >= u64::BITS as usize {
+                    0
+                } else {
+                    (value >> shift) & mask
+                }
+            })
+            .collect())
+    }
+
+    /// Normalizes the carries and borrows left by homomorphic adds and subs
+    /// back into a single amount.
+    pub fn join(&self, limbs: &[u64]) -> Result<u64, OracleError> {
+        let half = self.plaintext_modulus / 2;
+
+        let total = limbs.iter().enumerate().fold(0i128, |total, (i, limb)| {
+            let limb = if *limb > half {
+                *limb as i128 - self.plaintext_modulus as i128
+            } else {
+                *limb as i128
+            };
+
+            total + (limb << (self.limb_bits as usize * i))
+        });
+
+        if total < 0 {
+            return Err(OracleError::NegativeBalance);
+        }
+
+        u64::try_from(total).map_err(|_| OracleError::Unrepresentable(u64::MAX))
+    }
+}
+
+/// SIMD packing needs a plaintext modulus that is a prime congruent to 1
+/// modulo twice the degree, the builder only sets up the NTT when it is.
+pub fn supports_simd(parameters: &Arc<BfvParameters>) -> bool {
+    Plaintext::try_encode(&[0u64], Encoding::simd(), parameters).is_ok()
+}
+
+/// Encoding used for balance vectors: SIMD slots when the parameters allow
+/// it, otherwise polynomial coefficients. Both keep slots independent under
+/// homomorphic addition.
+pub fn balance_encoding(parameters: &Arc<BfvParameters>) -> Encoding {
+    if supports_simd(parameters) {
+        Encoding::simd()
+    } else {
+        Encoding::poly()
+    }
+}
+
+pub fn max_assets(parameters: &Arc<BfvParameters>) -> usize {
+    match BalanceLayout::new(parameters) {
+        Ok(layout) => parameters.degree() / layout.limbs,
+        Err(_) => 0,
+    }
+}
+
+/// Encodes one balance per asset, assets past the end of `balances` are zero.
+pub fn encode_balances(
+    parameters: &Arc<BfvParameters>,
+    balances: &[u64],
+) -> Result<Plaintext, OracleError> {
+    if balances.len() > max_assets(parameters) {
+        return Err(OracleError::UnknownAsset(balances.len() - 1));
+    }
+
+    let layout = BalanceLayout::new(parameters)?;
+
+    let mut slots = Vec::with_capacity(balances.len() * layout.limbs);
+    for balance in balances.iter() {
+        slots.extend(layout.split(*balance)?);
+    }
+
+    encode_slots(parameters, &slots)
+}
+
+/// Encodes raw slot values, limbs included, the way balances are encoded.
+pub fn encode_slots(
+    parameters: &Arc<BfvParameters>,
+    slots: &[u64],
+) -> Result<Plaintext, OracleError> {
+    Ok(Plaintext::try_encode(
+        slots,
+        balance_encoding(parameters),
+        parameters,
+    )?)
+}
+
+/// Encodes a transfer of `value` units of `asset` and nothing of the others.
+pub fn encode_asset(
+    parameters: &Arc<BfvParameters>,
+    asset: AssetId,
+    value: u64,
+) -> Result<Plaintext, OracleError> {
+    if asset >= max_assets(parameters) {
+        return Err(OracleError::UnknownAsset(asset));
+    }
+
+    let mut balances = vec![0u64; asset + 1];
+    balances[asset] = value;
+
+    encode_balances(parameters, &balances)
+}
+
+/// Decodes raw slot values. SIMD decoding only fails when the parameters
+/// cannot pack slots, which is exactly when `balance_encoding` falls back to
+/// coefficients.
+pub fn decode_slots(plaintext: &Plaintext) -> Result<Vec<u64>, OracleError> {
+    Ok(Vec::<u64>::try_decode(plaintext, Encoding::simd())
+        .or_else(|_| Vec::<u64>::try_decode(plaintext, Encoding::poly()))?)
+}
+
+/// Decodes every asset of a balance vector.
+pub fn decode_balances(
+    parameters: &Arc<BfvParameters>,
+    plaintext: &Plaintext,
+) -> Result<Vec<u64>, OracleError> {
+    let layout = BalanceLayout::new(parameters)?;
+
+    decode_slots(plaintext)?
+        .chunks_exact(layout.limbs)
+        .map(|limbs| layout.join(limbs))
+        .collect()
+}
+
+pub fn decode_asset(
+    parameters: &Arc<BfvParameters>,
+    plaintext: &Plaintext,
+    asset: AssetId,
+) -> Result<u64, OracleError> {
+    decode_balances(parameters, plaintext)?
+        .get(asset)
+        .copied()
+        .ok_or(OracleError::UnknownAsset(asset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhe_node::fhe_params::ParameterSet;
+
+    #[test]
+    fn test_simd_support_follows_parameters() {
+        let poly = ParameterSet::preset("bfv-2048").unwrap().build().unwrap();
+        let simd = ParameterSet::preset("bfv-simd-2048")
+            .unwrap()
+            .build()
+            .unwrap();
+
+        assert!(!supports_simd(&poly));
+        assert!(supports_simd(&simd));
+    }
+
+    #[test]
+    fn test_encode_asset_round_trip() {
+        for preset in ["bfv-2048", "bfv-simd-2048"] {
+            let parameters = ParameterSet::preset(preset).unwrap().build().unwrap();
+
+            let plaintext = encode_asset(&parameters, 3, 42).unwrap();
+            let balances = decode_balances(&parameters, &plaintext).unwrap();
+
+            assert_eq!(&balances[..5], &[0, 0, 0, 42, 0]);
+            assert_eq!(decode_asset(&parameters, &plaintext, 3).unwrap(), 42);
+            assert_eq!(
+                encode_asset(&parameters, max_assets(&parameters), 1).err(),
+                Some(OracleError::UnknownAsset(max_assets(&parameters)))
+            );
+        }
+    }
+
+    #[test]
+    fn test_limbs_carry_and_borrow() {
+        let parameters = ParameterSet::default_preset().build().unwrap();
+        let layout = BalanceLayout::new(&parameters).unwrap();
+        let t = layout.plaintext_modulus;
+
+        assert_eq!(layout.limb_bits, 3);
+        assert_eq!(layout.max_amount(), u64::MAX);
+        assert_eq!(layout.max_updates(), 72);
+
+        // the updates allowed leave every limb readable, even when each one
+        // moves it by the most it can
+        let top = (1u64 << layout.limb_bits) - 1;
+        let limbs = vec![top * (layout.max_updates() + 1) % t; layout.limbs];
+        assert!(layout.join(&limbs).is_ok());
+        // and one debited as far as it goes still reads as negative
+        let limbs = vec![(t - top * layout.max_updates() % t) % t; layout.limbs];
+        assert_eq!(layout.join(&limbs), Err(OracleError::NegativeBalance));
+
+        let wei = 1_500_000_000_000_000_000u64;
+        assert_eq!(layout.join(&layout.split(wei).unwrap()).unwrap(), wei);
+
+        // 8 - 1 limb by limb is [t - 1, 1], which has to borrow to give 7
+        let eight = layout.split(8).unwrap();
+        let one = layout.split(1).unwrap();
+        let sub = |a: &[u64], b: &[u64]| -> Vec<u64> {
+            a.iter().zip(b).map(|(a, b)| (a + t - b) % t).collect()
+        };
+
+        assert_eq!(layout.join(&sub(&eight, &one)).unwrap(), 7);
+        assert_eq!(
+            layout.join(&sub(&one, &eight)),
+            Err(OracleError::NegativeBalance)
+        );
+    }
+
+    #[test]
+    fn test_rejects_unrepresentable_amounts() {
+        let layout = BalanceLayout {
+            plaintext_modulus: 1 << 10,
+            limb_bits: 3,
+            limbs: 4,
+        };
+
+        assert_eq!(layout.max_amount(), (1 << 12) - 1);
+        assert!(layout.split((1 << 12) - 1).is_ok());
+        assert_eq!(
+            layout.split(1 << 12),
+            Err(OracleError::Unrepresentable(1 << 12))
+        );
+    }
+}
@@ -0,0 +1,559 @@
+use crate::fhe_node::fhe_assets::{encode_slots, BalanceLayout};
+use crate::fhe_node::fhe_oracle::{Oracle, OracleError};
+use ethers::types::H256;
+use ethers::utils::keccak256;
+use fhe::bfv::Ciphertext;
+use fhe_traits::{FheEncrypter, Serialize as FheSerialize};
+use rand::seq::index::sample;
+use rand::{CryptoRng, Rng, RngCore};
+
+/// Ciphertexts in a challenge. Half of them carry the real balance, and a
+/// sender that misreports goes unnoticed only if it lies on exactly that
+/// half, one chance in C(16, 8) = 12870. The first time it is caught its
+/// account is locked out of debits for good.
+pub const DEFAULT_CHALLENGE_SIZE: usize = 16;
+
+/// Ciphertexts under the sender's key. Half of them are the sender's balance
+/// after the debit plus a random mask, the others encrypt random masks alone.
+/// All of them decrypt to uniformly random slots and carry the noise of as
+/// many fresh encryptions.
+#[derive(Clone)]
+pub struct BalanceChallenge {
+    pub address: String,
+    pub ciphertexts: Vec<Ciphertext>,
+}
+
+/// The raw slots of every challenge ciphertext, decrypted by the sender.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BalanceResponse {
+    pub slots: Vec<Vec<u64>>,
+}
+
+/// What the node keeps until the sender answers: which ciphertexts are real
+/// and every mask.
+pub struct PendingAttestation {
+    pub address: String,
+    pub debit: H256,
+    pub balance: H256,
+    /// The debit has to leave nothing behind, see `challenge_emptying_debit`.
+    pub empties: bool,
+    real: Vec<bool>,
+    masks: Vec<Vec<u64>>,
+}
+
+/// Identifies a debit by the account and the ciphertext the Oracle subtracts
+/// from it, `tx_sender` for transfers and withdrawals alike.
+pub fn debit_id(address: &str, debit: &Ciphertext) -> H256 {
+    let mut preimage = address.as_bytes().to_vec();
+    preimage.extend_from_slice(&debit.to_bytes());
+
+    H256::from(keccak256(preimage))
+}
+
+/// Identifies a debit attested to take the whole balance, apart from plain
+/// attestations of the same debit.
+pub fn emptying_debit_id(address: &str, debit: &Ciphertext) -> H256 {
+    let mut preimage = b"empties".to_vec();
+    preimage.extend_from_slice(debit_id(address, debit).as_bytes());
+
+    H256::from(keccak256(preimage))
+}
+
+pub fn balance_hash(fhe_balance: &Ciphertext) -> H256 {
+    H256::from(keccak256(fhe_balance.to_bytes()))
+}
+
+/// Starts the interactive check that subtracting `debit` leaves `address`
+/// with no asset below zero, with `size` ciphertexts. The answer is only
+/// valid for the sender's balance as it is now, any change to it before
+/// execution means attesting again.
+///
+/// The node learns the remaining balance of the sender, nobody else does;
+/// it holds the sender's keys in its wallet anyway. Every balance is a sum
+/// of fresh encryptions under the sender's key, one per update on top of the
+/// first, so the decoys get as many encryptions of zero and their noise
+/// cannot tell them from the real ones.
+pub fn challenge_debit<R: RngCore + CryptoRng>(
+    fhe_oracle: &Oracle,
+    address: &str,
+    debit: &Ciphertext,
+    size: usize,
+    rng: &mut R,
+) -> Result<(BalanceChallenge, PendingAttestation), OracleError> {
+    challenge(fhe_oracle, address, debit, false, size, rng)
+}
+
+/// Like `challenge_debit`, for a debit that has to take every asset of
+/// `address` down to exactly zero. A key rotation debits the whole balance
+/// under the old key, anything it left behind would be lost with that key
+/// or, worse, credited twice.
+pub fn challenge_emptying_debit<R: RngCore + CryptoRng>(
+    fhe_oracle: &Oracle,
+    address: &str,
+    debit: &Ciphertext,
+    size: usize,
+    rng: &mut R,
+) -> Result<(BalanceChallenge, PendingAttestation), OracleError> {
+    challenge(fhe_oracle, address, debit, true, size, rng)
+}
+
+fn challenge<R: RngCore + CryptoRng>(
+    fhe_oracle: &Oracle,
+    address: &str,
+    debit: &Ciphertext,
+    empties: bool,
+    size: usize,
+    rng: &mut R,
+) -> Result<(BalanceChallenge, PendingAttestation), OracleError> {
+    if fhe_oracle.locked_accounts.contains(address) {
+        return Err(OracleError::AttestationLocked(address.to_string()));
+    }
+    if size < 2 {
+        return Err(OracleError::AttestationFailed(format!(
+            "a challenge needs at least 2 ciphertexts, not {}",
+            size
+        )));
+    }
+
+    let parameters = &fhe_oracle.parameters;
+    let sender = fhe_oracle.return_user(address.to_string())?;
+    let remaining = &sender.fhe_balance - debit;
+    // the balance's encryptions and the debit's
+    let fresh = sender.updates + 2;
+    let zero = encode_slots(parameters, &vec![0; parameters.degree()])?;
+
+    let mut real = vec![false; size];
+    for i in sample(rng, size, size / 2) {
+        real[i] = true;
+    }
+    let mut ciphertexts = Vec::with_capacity(size);
+    let mut masks = Vec::with_capacity(size);
+
+    for is_real in real.iter() {
+        let mask: Vec<u64> = (0..parameters.degree())
+            .map(|_| rng.gen_range(0..parameters.plaintext()))
+            .collect();
+        let mut masked = sender
+            .fhe_pk
+            .try_encrypt(&encode_slots(parameters, &mask)?, rng)?;
+
+        if *is_real {
+            masked = &remaining + &masked;
+        } else {
+            for _ in 0..fresh {
+                masked = &masked + &sender.fhe_pk.try_encrypt(&zero, rng)?;
+            }
+        }
+        ciphertexts.push(masked);
+        masks.push(mask);
+    }
+
+    Ok((
+        BalanceChallenge {
+            address: address.to_string(),
+            ciphertexts,
+        },
+        PendingAttestation {
+            address: address.to_string(),
+            debit: if empties {
+                emptying_debit_id(address, debit)
+            } else {
+                debit_id(address, debit)
+            },
+            balance: balance_hash(&sender.fhe_balance),
+            empties,
+            real,
+            masks,
+        },
+    ))
+}
+
+impl PendingAttestation {
+    /// Checks the decoys came back as their masks, that the real answers
+    /// agree once unmasked and that they have no negative asset, or none
+    /// above zero either for an emptying debit. On success the Oracle accepts
+    /// the debit against the balance it was made for. A misreported answer
+    /// locks the account out of further challenges.
+    pub fn check(
+        &self,
+        fhe_oracle: &mut Oracle,
+        response: &BalanceResponse,
+    ) -> Result<(), OracleError> {
+        if response.slots.len() != self.masks.len() {
+            return Err(OracleError::AttestationFailed(format!(
+                "expected {} answers, got {}",
+                self.masks.len(),
+                response.slots.len()
+            )));
+        }
+
+        let layout = BalanceLayout::new(&fhe_oracle.parameters)?;
+        let t = layout.plaintext_modulus;
+        let mut remaining: Option<Vec<u64>> = None;
+
+        for (i, (slots, mask)) in response.slots.iter().zip(self.masks.iter()).enumerate() {
+            if !self.real[i] || slots.len() != mask.len() {
+                if slots != mask {
+                    return Err(self.lock_out(
+                        fhe_oracle,
+                        format!("challenge {} was not decrypted faithfully", i),
+                    ));
+                }
+                continue;
+            }
+
+            let unmasked: Vec<u64> = slots
+                .iter()
+                .zip(mask.iter())
+                .map(|(slot, mask)| (slot % t + t - mask) % t)
+                .collect();
+            match &remaining {
+                Some(remaining) if *remaining != unmasked => {
+                    return Err(self.lock_out(
+                        fhe_oracle,
+                        format!("challenge {} disagrees with the others", i),
+                    ));
+                }
+                Some(_) => {}
+                None => remaining = Some(unmasked),
+            }
+        }
+
+        for limbs in remaining.unwrap_or_default().chunks_exact(layout.limbs) {
+            match layout.join(limbs) {
+                Err(OracleError::NegativeBalance) => {
+                    return Err(OracleError::Overdraft(self.address.clone()))
+                }
+                Ok(remaining) if self.empties && remaining != 0 => {
+                    return Err(OracleError::BalanceLeftBehind(self.address.clone()))
+                }
+                other => {
+                    other?;
+                }
+            }
+        }
+
+        fhe_oracle.attested_debits.insert(self.debit, self.balance);
+
+        Ok(())
+    }
+
+    fn lock_out(&self, fhe_oracle: &mut Oracle, reason: String) -> OracleError {
+        fhe_oracle.locked_accounts.insert(self.address.clone());
+
+        OracleError::AttestationFailed(reason)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::fhe_account_handler::get_keys::tests::create_users;
+    use crate::fhe_account_handler::user::User;
+    use crate::fhe_node::fhe_execution::{KeyRotation, Tx, TxHash, Withdrawal};
+    use crate::fhe_node::fhe_proof::{OpeningBackend, TxProver};
+    use fhe::bfv::{PublicKey, SecretKey};
+    use rand::thread_rng;
+
+    // has `user` attest that `debit` leaves its balance non-negative
+    pub fn attest_debit(fhe_oracle: &mut Oracle, user: &User, debit: &Ciphertext) {
+        let (challenge, pending) =
+            challenge_debit(fhe_oracle, &user.address, debit, 4, &mut thread_rng()).unwrap();
+        let response = user.answer_challenge(&challenge).unwrap();
+        pending.check(fhe_oracle, &response).unwrap();
+    }
+
+    // has `user` attest that `rotation` takes its whole balance
+    pub fn attest_rotation(fhe_oracle: &mut Oracle, user: &User, rotation: &KeyRotation) {
+        let (challenge, pending) = challenge_emptying_debit(
+            fhe_oracle,
+            &rotation.sender,
+            &rotation.tx_sender,
+            4,
+            &mut thread_rng(),
+        )
+        .unwrap();
+        let response = user.answer_challenge(&challenge).unwrap();
+        pending.check(fhe_oracle, &response).unwrap();
+    }
+
+    // a transfer built without the client-side balance check
+    fn forged_tx(fhe_oracle: &Oracle, sender: &User, receiver: &User, value: u64) -> Tx {
+        let transfer = OpeningBackend
+            .prove(
+                &fhe_oracle.parameters,
+                &sender.fhe_pk,
+                &receiver.fhe_pk,
+                None,
+                0,
+                value,
+                &mut thread_rng(),
+            )
+            .unwrap();
+
+        Tx::new(
+            TxHash::zero(),
+            sender.address.clone(),
+            receiver.address.clone(),
+            transfer.tx_sender,
+            transfer.tx_receiver,
+            transfer.proof.encode(),
+        )
+    }
+
+    #[test]
+    fn test_attested_debit_executes_once() {
+        let (mut fhe_oracle, alice, bob, ..) = create_users(100, 50);
+        fhe_oracle.require_attestation = true;
+
+        let tx = forged_tx(&fhe_oracle, &alice, &bob, 100);
+        assert_eq!(
+            tx.execute_tx(&mut fhe_oracle.clone()).err(),
+            Some(OracleError::UnattestedDebit(alice.address.clone()))
+        );
+
+        let (challenge, pending) =
+            challenge_debit(&fhe_oracle, &tx.sender, &tx.tx_sender, 4, &mut thread_rng()).unwrap();
+        let response = alice.answer_challenge(&challenge).unwrap();
+        pending.check(&mut fhe_oracle, &response).unwrap();
+
+        let mut fhe_oracle = tx.execute_tx(&mut fhe_oracle).unwrap();
+        assert_eq!(alice.user_balance(&fhe_oracle).unwrap(), 0);
+        assert_eq!(
+            tx.execute_tx(&mut fhe_oracle).err(),
+            Some(OracleError::UnattestedDebit(alice.address.clone()))
+        );
+    }
+
+    #[test]
+    fn test_replayed_tx_keeps_the_attestation() {
+        let (mut fhe_oracle, alice, bob, ..) = create_users(100, 50);
+        fhe_oracle.require_attestation = true;
+
+        let mut tx = forged_tx(&fhe_oracle, &alice, &bob, 10);
+        tx.tx_hash = TxHash::from_low_u64_be(1);
+        fhe_oracle.mark_tx_seen(&tx.tx_hash).unwrap();
+
+        let (challenge, pending) =
+            challenge_debit(&fhe_oracle, &tx.sender, &tx.tx_sender, 4, &mut thread_rng()).unwrap();
+        let response = alice.answer_challenge(&challenge).unwrap();
+        pending.check(&mut fhe_oracle, &response).unwrap();
+
+        assert_eq!(
+            tx.execute_tx(&mut fhe_oracle).err(),
+            Some(OracleError::DuplicateTx(tx.tx_hash))
+        );
+        assert_eq!(fhe_oracle.attested_debits.len(), 1);
+    }
+
+    #[test]
+    fn test_overdraft_is_refused() {
+        let (mut fhe_oracle, alice, bob, ..) = create_users(100, 50);
+        fhe_oracle.require_attestation = true;
+
+        let tx = forged_tx(&fhe_oracle, &alice, &bob, 101);
+        let (challenge, pending) =
+            challenge_debit(&fhe_oracle, &tx.sender, &tx.tx_sender, 4, &mut thread_rng()).unwrap();
+        let response = alice.answer_challenge(&challenge).unwrap();
+
+        assert_eq!(
+            pending.check(&mut fhe_oracle, &response),
+            Err(OracleError::Overdraft(alice.address.clone()))
+        );
+        assert!(tx.execute_tx(&mut fhe_oracle).is_err());
+        assert_eq!(alice.user_balance(&fhe_oracle).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_misreported_decoy_is_caught() {
+        let (mut fhe_oracle, alice, bob, ..) = create_users(100, 50);
+
+        let tx = forged_tx(&fhe_oracle, &alice, &bob, 101);
+        let (challenge, pending) =
+            challenge_debit(&fhe_oracle, &tx.sender, &tx.tx_sender, 4, &mut thread_rng()).unwrap();
+        let mut response = alice.answer_challenge(&challenge).unwrap();
+
+        let decoy = pending.real.iter().position(|real| !real).unwrap();
+        response.slots[decoy][0] += 1;
+
+        assert!(matches!(
+            pending.check(&mut fhe_oracle, &response),
+            Err(OracleError::AttestationFailed(_))
+        ));
+        assert!(fhe_oracle.locked_accounts.contains(&alice.address));
+    }
+
+    #[test]
+    fn test_lie_on_one_real_copy_locks_the_account() {
+        let (mut fhe_oracle, alice, bob, ..) = create_users(100, 50);
+        fhe_oracle.require_attestation = true;
+
+        let tx = forged_tx(&fhe_oracle, &alice, &bob, 101);
+        let (challenge, pending) =
+            challenge_debit(&fhe_oracle, &tx.sender, &tx.tx_sender, 4, &mut thread_rng()).unwrap();
+        let mut response = alice.answer_challenge(&challenge).unwrap();
+
+        // the sender guessed one real copy and reports no overdraft for it
+        let real = pending.real.iter().position(|real| *real).unwrap();
+        response.slots[real][0] += 1;
+
+        assert!(matches!(
+            pending.check(&mut fhe_oracle, &response),
+            Err(OracleError::AttestationFailed(_))
+        ));
+        assert!(fhe_oracle.attested_debits.is_empty());
+        assert_eq!(
+            challenge_debit(&fhe_oracle, &tx.sender, &tx.tx_sender, 4, &mut thread_rng()).err(),
+            Some(OracleError::AttestationLocked(alice.address.clone()))
+        );
+    }
+
+    #[test]
+    fn test_rotation_that_leaves_funds_behind_is_refused() {
+        let (mut fhe_oracle, alice, ..) = create_users(100, 50);
+
+        // moves 60 to the new key and keeps 40 under the old one
+        let fhe_sk = SecretKey::random(&fhe_oracle.parameters, &mut thread_rng());
+        let fhe_pk = PublicKey::new(&fhe_sk, &mut thread_rng());
+        let (tx_sender, fhe_balance, proof) = OpeningBackend
+            .prove_rotation(
+                &fhe_oracle.parameters,
+                &alice.fhe_pk,
+                &fhe_pk,
+                &[60],
+                &mut thread_rng(),
+            )
+            .unwrap();
+        let rotation = KeyRotation {
+            tx_hash: TxHash::zero(),
+            sender: alice.address.clone(),
+            fhe_pk,
+            tx_sender,
+            fhe_balance,
+            proof: proof.encode(),
+            nonce: 0,
+            signature: None,
+        };
+
+        let (challenge, pending) = challenge_emptying_debit(
+            &fhe_oracle,
+            &rotation.sender,
+            &rotation.tx_sender,
+            4,
+            &mut thread_rng(),
+        )
+        .unwrap();
+        assert_eq!(
+            pending.check(
+                &mut fhe_oracle,
+                &alice.answer_challenge(&challenge).unwrap()
+            ),
+            Err(OracleError::BalanceLeftBehind(alice.address.clone()))
+        );
+        assert!(fhe_oracle.locked_accounts.is_empty());
+
+        // a plain attestation of the same debit does not do either
+        let (challenge, pending) = challenge_debit(
+            &fhe_oracle,
+            &rotation.sender,
+            &rotation.tx_sender,
+            4,
+            &mut thread_rng(),
+        )
+        .unwrap();
+        pending
+            .check(
+                &mut fhe_oracle,
+                &alice.answer_challenge(&challenge).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(
+            rotation.execute_rotation(&mut fhe_oracle).err(),
+            Some(OracleError::UnattestedDebit(alice.address.clone()))
+        );
+        assert_eq!(alice.user_balance(&fhe_oracle).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_attestation_goes_stale() {
+        let (mut fhe_oracle, alice, bob, ..) = create_users(100, 50);
+        fhe_oracle.require_attestation = true;
+
+        let tx = forged_tx(&fhe_oracle, &alice, &bob, 60);
+        let (challenge, pending) =
+            challenge_debit(&fhe_oracle, &tx.sender, &tx.tx_sender, 4, &mut thread_rng()).unwrap();
+        pending
+            .check(
+                &mut fhe_oracle,
+                &alice.answer_challenge(&challenge).unwrap(),
+            )
+            .unwrap();
+
+        // another debit lands first, so the attested balance is gone
+        let other = forged_tx(&fhe_oracle, &alice, &bob, 60);
+        let (challenge, pending) = challenge_debit(
+            &fhe_oracle,
+            &other.sender,
+            &other.tx_sender,
+            4,
+            &mut thread_rng(),
+        )
+        .unwrap();
+        pending
+            .check(
+                &mut fhe_oracle,
+                &alice.answer_challenge(&challenge).unwrap(),
+            )
+            .unwrap();
+        let mut fhe_oracle = other.execute_tx(&mut fhe_oracle).unwrap();
+
+        assert_eq!(
+            tx.execute_tx(&mut fhe_oracle).err(),
+            Some(OracleError::UnattestedDebit(alice.address.clone()))
+        );
+    }
+
+    #[test]
+    fn test_overdrawn_withdrawal_is_refused() {
+        let (mut fhe_oracle, alice, ..) = create_users(100, 50);
+        fhe_oracle.require_attestation = true;
+
+        let (tx_sender, proof) = OpeningBackend
+            .prove_withdrawal(
+                &fhe_oracle.parameters,
+                &alice.fhe_pk,
+                0,
+                101,
+                &mut thread_rng(),
+            )
+            .unwrap();
+        let withdrawal = Withdrawal {
+            tx_hash: TxHash::zero(),
+            sender: alice.address.clone(),
+            asset: 0,
+            value: 101,
+            tx_sender,
+            proof: proof.encode(),
+        };
+
+        let (challenge, pending) = challenge_debit(
+            &fhe_oracle,
+            &withdrawal.sender,
+            &withdrawal.tx_sender,
+            4,
+            &mut thread_rng(),
+        )
+        .unwrap();
+        assert_eq!(
+            pending.check(
+                &mut fhe_oracle,
+                &alice.answer_challenge(&challenge).unwrap()
+            ),
+            Err(OracleError::Overdraft(alice.address.clone()))
+        );
+        assert_eq!(
+            withdrawal.execute_withdrawal(&mut fhe_oracle).err(),
+            Some(OracleError::UnattestedDebit(alice.address.clone()))
+        );
+    }
+}
@@ -0,0 +1,362 @@
+use crate::fhe_node::fhe_execution::{Tx, TxHash};
+use crate::fhe_node::fhe_oracle::{Oracle, OracleError};
+use fhe::bfv::Ciphertext;
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Transfers into and out of one account within a block.
+#[derive(Clone, Default)]
+pub struct AccountDelta {
+    pub incoming: Vec<Ciphertext>,
+    pub outgoing: Vec<Ciphertext>,
+}
+
+impl AccountDelta {
+    /// Number of adds and subs `apply` makes.
+    pub fn updates(&self) -> u64 {
+        (self.incoming.len() + self.outgoing.len()) as u64
+    }
+
+    pub fn apply(&self, balance: &Ciphertext) -> Ciphertext {
+        let balance = self
+            .incoming
+            .iter()
+            .fold(balance.clone(), |balance, ct| &balance + ct);
+
+        self.outgoing
+            .iter()
+            .fold(balance, |balance, ct| &balance - ct)
+    }
+}
+
+/// Groups the transfer ciphertexts of `txs` by account, in address order.
+pub fn group_by_account(txs: &[Tx]) -> BTreeMap<String, AccountDelta> {
+    let mut deltas: BTreeMap<String, AccountDelta> = BTreeMap::new();
+
+    for tx in txs.iter() {
+        deltas
+            .entry(tx.sender.clone())
+            .or_default()
+            .outgoing
+            .push(tx.tx_sender.clone());
+        deltas
+            .entry(tx.receiver.clone())
+            .or_default()
+            .incoming
+            .push(tx.tx_receiver.clone());
+    }
+
+    deltas
+}
+
+/// A tx `execute_block` left out, by its position in the block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RejectedTx {
+    pub index: usize,
+    pub error: OracleError,
+}
+
+/// Executes the transfers of one block. Each tx is checked on its own, and
+/// one that is a replay or a self-transfer, fails its signature, nonce, proof
+/// or attestation, names an unknown account or one out of balance headroom
+/// is left out and returned while
+/// the rest of the block goes ahead. The deltas of the accepted txs are
+/// folded into each account's balance on the thread pool and written back
+/// once.
+///
+/// Ends in the same state as calling `Tx::execute_tx` on every tx in order
+/// and skipping those it refuses. The accepted txs were all checked against
+/// the state they are applied to, so an error applying them is a bug rather
+/// than a bad tx. Should one happen anyway, everything the block changed is
+/// undone before it is returned.
+pub fn execute_block(fhe_oracle: &mut Oracle, txs: &[Tx]) -> Result<Vec<RejectedTx>, OracleError> {
+    let checkpoint = fhe_oracle.checkpoint();
+    let executed = apply_block(fhe_oracle, txs);
+    if executed.is_err() {
+        fhe_oracle.revert_to(checkpoint);
+    }
+
+    executed
+}
+
+fn apply_block(fhe_oracle: &mut Oracle, txs: &[Tx]) -> Result<Vec<RejectedTx>, OracleError> {
+    let verified: Vec<Result<(), OracleError>> = txs
+        .par_iter()
+        .map(|tx| {
+            fhe_oracle.check_signer(tx)?;
+            fhe_oracle.verifier.verify(fhe_oracle, tx)
+        })
+        .collect();
+
+    let mut rejected: Vec<RejectedTx> = Vec::new();
+    let mut accepted: Vec<Tx> = Vec::new();
+    let mut hashes: HashSet<TxHash> = HashSet::new();
+    let mut nonces: HashMap<&str, u64> = HashMap::new();
+    let mut debited: HashSet<&str> = HashSet::new();
+    let mut updates: HashMap<&str, u64> = HashMap::new();
+
+    for (index, (tx, verified)) in txs.iter().zip(verified).enumerate() {
+        let checked = verified.and_then(|()| {
+            if tx.sender == tx.receiver {
+                return Err(OracleError::SelfTransfer(tx.sender.clone()));
+            }
+            fhe_oracle.check_tx_unseen(&tx.tx_hash)?;
+            if !tx.tx_hash.is_zero() && hashes.contains(&tx.tx_hash) {
+                return Err(OracleError::DuplicateTx(tx.tx_hash));
+            }
+
+            let sender = fhe_oracle.return_user(tx.sender.clone())?;
+            fhe_oracle.return_user(tx.receiver.clone())?;
+
+            // signed txs of one sender have to follow each other's nonces
+            let expected = nonces
+                .get(tx.sender.as_str())
+                .copied()
+                .unwrap_or(sender.nonce);
+            if tx.signature.is_some() {
+                if tx.nonce < expected {
+                    return Err(OracleError::NonceConflict {
+                        address: tx.sender.clone(),
+                        nonce: tx.nonce,
+                    });
+                }
+                if tx.nonce > expected {
+                    return Err(OracleError::NonceGap {
+                        address: tx.sender.clone(),
+                        expected,
+                        found: tx.nonce,
+                    });
+                }
+            }
+
+            // an attestation covers the balance before one debit, so a
+            // sender gets at most one attested debit per block
+            if fhe_oracle.require_attestation && debited.contains(tx.sender.as_str()) {
+                return Err(OracleError::UnattestedDebit(tx.sender.clone()));
+            }
+            fhe_oracle.check_attestation(&tx.sender, &tx.tx_sender)?;
+
+            // both balances take one more update on top of the block's
+            for address in [tx.sender.as_str(), tx.receiver.as_str()] {
+                let pending = updates.get(address).copied().unwrap_or(0);
+                fhe_oracle.check_headroom(address, pending + 1)?;
+            }
+
+            Ok(if tx.signature.is_some() {
+                expected + 1
+            } else {
+                expected
+            })
+        });
+
+        match checked {
+            Ok(next_nonce) => {
+                hashes.insert(tx.tx_hash);
+                nonces.insert(&tx.sender, next_nonce);
+                debited.insert(&tx.sender);
+                *updates.entry(&tx.sender).or_default() += 1;
+                *updates.entry(&tx.receiver).or_default() += 1;
+                accepted.push(tx.clone());
+            }
+            Err(error) => rejected.push(RejectedTx { index, error }),
+        }
+    }
+
+    let accounts: Vec<(String, Ciphertext, AccountDelta)> = group_by_account(&accepted)
+        .into_iter()
+        .map(|(address, delta)| {
+            let balance = fhe_oracle.return_user_fhe_balance(address.clone())?;
+            Ok((address, balance, delta))
+        })
+        .collect::<Result<_, OracleError>>()?;
+
+    let balances: Vec<(String, Ciphertext, u64)> = accounts
+        .into_par_iter()
+        .map(|(address, balance, delta)| {
+            let balance = delta.apply(&balance);
+            (address, balance, delta.updates())
+        })
+        .collect();
+
+    for tx in accepted.iter() {
+        fhe_oracle.mark_tx_seen(&tx.tx_hash)?;
+        fhe_oracle.use_nonce(tx)?;
+    }
+    for (address, balance, updates) in balances {
+        fhe_oracle.apply_updates(address, balance, updates)?;
+    }
+
+    Ok(rejected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhe_account_handler::get_keys::get_keys;
+    use crate::fhe_account_handler::get_keys::tests::create_users;
+    use crate::fhe_account_handler::user::{create_user, User};
+    use crate::fhe_node::fhe_oracle::OracleUser;
+    use ethers::signers::LocalWallet;
+    use libtest::Bencher;
+
+    const BENCH_ACCOUNTS: usize = 16;
+    const BENCH_TXS: usize = 48;
+
+    fn create_accounts(count: usize, balance: u64) -> (Oracle, Vec<User>) {
+        let mut fhe_oracle = Oracle::new();
+        let users: Vec<User> = (0..count)
+            .map(|i| {
+                let user = create_user(
+                    format!("0xb{}", i),
+                    fhe_oracle.parameters.clone(),
+                    None,
+                    Some(balance),
+                )
+                .unwrap();
+                fhe_oracle
+                    .add_user(user.address.clone(), OracleUser::from_user(user.clone()))
+                    .unwrap();
+                user
+            })
+            .collect();
+
+        (fhe_oracle, users)
+    }
+
+    // tx i moves i % 3 + 1 units from account i to account 7i + 1, both
+    // modulo the number of accounts
+    fn create_txs(fhe_oracle: &Oracle, users: &[User], count: usize) -> Vec<Tx> {
+        (0..count)
+            .map(|i| {
+                let sender = &users[i % users.len()];
+                let receiver = &users[(i * 7 + 1) % users.len()];
+                let mut tx = sender
+                    .create_tx(
+                        fhe_oracle.users[&receiver.address].clone(),
+                        fhe_oracle,
+                        i as u64 % 3 + 1,
+                    )
+                    .unwrap();
+                tx.tx_hash = TxHash::from_low_u64_be(i as u64 + 1);
+                tx
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_block_matches_sequential_execution() {
+        let (fhe_oracle, users) = create_accounts(4, 100);
+        let txs = create_txs(&fhe_oracle, &users, 12);
+
+        let mut sequential = fhe_oracle.clone();
+        for tx in txs.iter() {
+            sequential = tx.execute_tx(&mut sequential).unwrap();
+        }
+
+        let mut batched = fhe_oracle.clone();
+        assert_eq!(execute_block(&mut batched, &txs), Ok(vec![]));
+
+        for user in users.iter() {
+            assert_eq!(
+                user.user_balance(&batched).unwrap(),
+                user.user_balance(&sequential).unwrap()
+            );
+        }
+        assert!(txs.iter().all(|tx| batched.has_seen_tx(&tx.tx_hash)));
+        assert_eq!(
+            execute_block(&mut batched, &txs[..1]),
+            Ok(vec![RejectedTx {
+                index: 0,
+                error: OracleError::DuplicateTx(txs[0].tx_hash)
+            }])
+        );
+    }
+
+    #[test]
+    fn test_block_skips_invalid_txs() {
+        let (mut fhe_oracle, users) = create_accounts(2, 100);
+        let mut txs = create_txs(&fhe_oracle, &users, 2);
+        txs[1].receiver = "0x0".to_string();
+
+        assert_eq!(
+            execute_block(&mut fhe_oracle, &txs),
+            Ok(vec![RejectedTx {
+                index: 1,
+                error: OracleError::UnknownUser("0x0".to_string())
+            }])
+        );
+        // tx 0 still moves its unit from account 0 to account 1
+        assert_eq!(users[0].user_balance(&fhe_oracle).unwrap(), 99);
+        assert_eq!(users[1].user_balance(&fhe_oracle).unwrap(), 101);
+        assert!(fhe_oracle.has_seen_tx(&txs[0].tx_hash));
+        assert!(!fhe_oracle.has_seen_tx(&txs[1].tx_hash));
+
+        // a tx repeated within the block executes once
+        let mut repeated = create_txs(&fhe_oracle, &users, 1);
+        repeated[0].tx_hash = TxHash::from_low_u64_be(10);
+        repeated.push(repeated[0].clone());
+        assert_eq!(
+            execute_block(&mut fhe_oracle, &repeated),
+            Ok(vec![RejectedTx {
+                index: 1,
+                error: OracleError::DuplicateTx(repeated[0].tx_hash)
+            }])
+        );
+        assert_eq!(users[0].user_balance(&fhe_oracle).unwrap(), 98);
+    }
+
+    #[test]
+    fn test_block_reports_used_nonces() {
+        let (mut fhe_oracle, alice, bob, ..) = create_users(100, 50);
+        let wallet: LocalWallet = get_keys("user").unwrap().private_key.parse().unwrap();
+
+        let tx = alice
+            .create_tx(fhe_oracle.users[&bob.address].clone(), &fhe_oracle, 10)
+            .unwrap()
+            .sign(0, &fhe_oracle.signing_domain, &wallet)
+            .unwrap();
+        let mut first = tx.clone();
+        first.tx_hash = TxHash::from_low_u64_be(1);
+        let mut again = tx;
+        again.tx_hash = TxHash::from_low_u64_be(2);
+
+        assert_eq!(
+            execute_block(&mut fhe_oracle, &[first, again]),
+            Ok(vec![RejectedTx {
+                index: 1,
+                error: OracleError::NonceConflict {
+                    address: alice.address.clone(),
+                    nonce: 0
+                }
+            }])
+        );
+        assert_eq!(alice.user_balance(&fhe_oracle).unwrap(), 90);
+    }
+
+    // cargo bench -- fhe_block
+    #[bench]
+    fn bench_sequential_execution(b: &mut Bencher) {
+        let (fhe_oracle, users) = create_accounts(BENCH_ACCOUNTS, 100);
+        let txs = create_txs(&fhe_oracle, &users, BENCH_TXS);
+
+        b.iter(|| {
+            let mut fhe_oracle = fhe_oracle.clone();
+            for tx in txs.iter() {
+                fhe_oracle = tx.execute_tx(&mut fhe_oracle).unwrap();
+            }
+            fhe_oracle
+        });
+    }
+
+    #[bench]
+    fn bench_block_execution(b: &mut Bencher) {
+        let (fhe_oracle, users) = create_accounts(BENCH_ACCOUNTS, 100);
+        let txs = create_txs(&fhe_oracle, &users, BENCH_TXS);
+
+        b.iter(|| {
+            let mut fhe_oracle = fhe_oracle.clone();
+            execute_block(&mut fhe_oracle, &txs).unwrap();
+            fhe_oracle
+        });
+    }
+}
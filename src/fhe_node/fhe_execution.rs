@@ -1,26 +1,114 @@
 use crate::{
     fhe_account_handler::user::{self, decoded_user_balance, User},
-    fhe_node::fhe_oracle::Oracle,
+    fhe_node::fhe_assets::{AssetId, ETH},
+    fhe_node::fhe_oracle::{Oracle, OracleError},
+    fhe_node::fhe_params::{decode_ciphertext, decode_pk, encode_ciphertext},
+    fhe_node::fhe_proof::{OpeningBackend, TxProof},
+};
+use ethers::signers::LocalWallet;
+use ethers::types::{Address, Signature, H256, U256};
+use ethers::utils::{hash_message, hex, keccak256, to_checksum};
+use fhe::bfv::{
+    BfvParameters, BfvParametersBuilder, Ciphertext, Encoding, Plaintext, PublicKey, SecretKey,
 };
-use ethers::utils::hex;
-use fhe::bfv::{BfvParametersBuilder, Ciphertext, Encoding, Plaintext, PublicKey, SecretKey};
 use fhe_traits::*;
+use std::sync::Arc;
 
 use super::fhe_oracle::OracleUser;
 
+/// What the `fhe_proof` of `Send_fhe_tx` holds for a tx with an auditor
+/// copy. The contract has no field of its own for a third ciphertext.
+#[derive(serde::Deserialize, serde::Serialize)]
+struct AuditedProof {
+    proof: String,
+    tx_auditor: String,
+}
+
+/// Chain id of the local Anvil node the contract is deployed to by default.
+pub const ANVIL_CHAIN_ID: u64 = 31337;
+
+/// The deployment a tx signature is made for. It goes into the signing
+/// digest, so a signed tx cannot be replayed on another chain or against
+/// another FHEToken contract.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SigningDomain {
+    pub chain_id: u64,
+    pub contract: Address,
+}
+
+impl Default for SigningDomain {
+    fn default() -> Self {
+        SigningDomain {
+            chain_id: ANVIL_CHAIN_ID,
+            contract: Address::zero(),
+        }
+    }
+}
+
+/// A request its sender signs with its Ethereum key under its next nonce,
+/// checked by `Oracle::check_signature` and moved past by
+/// `Oracle::use_nonce`.
+pub trait Signed {
+    fn sender(&self) -> &str;
+    fn nonce(&self) -> u64;
+    fn signature(&self) -> Option<&Signature>;
+    fn signing_digest(&self, domain: &SigningDomain) -> Result<H256, OracleError>;
+
+    /// The Ethereum account that signed the request for `domain`.
+    fn signer(&self, domain: &SigningDomain) -> Result<Address, OracleError> {
+        let signature = self
+            .signature()
+            .ok_or_else(|| OracleError::InvalidSignature("tx is not signed".to_string()))?;
+
+        signature
+            .recover(self.signing_digest(domain)?.as_bytes())
+            .map_err(|error| OracleError::InvalidSignature(error.to_string()))
+    }
+
+    /// Checks the signature was made for `domain` by the key of the sender.
+    fn verify_signature(&self, domain: &SigningDomain) -> Result<(), OracleError> {
+        let sender: Address = self
+            .sender()
+            .parse()
+            .map_err(|_| OracleError::MalformedTx(format!("bad sender {}", self.sender())))?;
+
+        let signer = self.signer(domain)?;
+        if signer != sender {
+            return Err(OracleError::InvalidSignature(format!(
+                "signed by {:?}, not by the sender {}",
+                signer,
+                self.sender()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Id the contract gives a transfer. The zero hash marks a tx that has not
+/// been mined yet.
+pub type TxHash = H256;
+
 #[derive(Clone)]
 pub struct Tx {
-    pub tx_hash: String,
+    pub tx_hash: TxHash,
     pub sender: String,
     pub receiver: String,
     pub tx_sender: Ciphertext,
     pub tx_receiver: Ciphertext,
+    /// The amount once more, under the Oracle's auditor key.
+    pub tx_auditor: Option<Ciphertext>,
     pub tx_proof: String,
+    /// Position of the tx among the sender's signed txs, covered by the
+    /// signature so a relayer cannot submit it twice.
+    pub nonce: u64,
+    /// EIP-191 signature by the sender's Ethereum key over `signing_digest`.
+    pub signature: Option<Signature>,
 }
 
 impl Tx {
     pub fn new(
-        tx_hash: String,
+        tx_hash: TxHash,
         sender: String,
         receiver: String,
         tx_sender: Ciphertext,
@@ -33,140 +121,559 @@ impl Tx {
             receiver,
             tx_sender,
             tx_receiver,
+            tx_auditor: None,
             tx_proof,
+            nonce: 0,
+            signature: None,
         }
     }
 
+    pub fn with_auditor(self, tx_auditor: Option<Ciphertext>) -> Tx {
+        Tx { tx_auditor, ..self }
+    }
+
+    /// The commitment to `tx_proof` the signature covers, the same for the
+    /// proof and its on-chain form.
+    pub fn proof_commitment(&self) -> H256 {
+        TxProof::commitment_of(&self.tx_proof)
+    }
+
+    /// Signs the tx as its `nonce`th signed tx with the sender's Ethereum
+    /// key for `domain`, the way `personal_sign` signs the digest.
+    pub fn sign(
+        self,
+        nonce: u64,
+        domain: &SigningDomain,
+        wallet: &LocalWallet,
+    ) -> Result<Tx, OracleError> {
+        let tx = Tx { nonce, ..self };
+        let digest = tx.signing_digest(domain)?;
+        let signature = wallet
+            .sign_hash(hash_message(digest))
+            .map_err(|error| OracleError::InvalidSignature(error.to_string()))?;
+
+        Ok(Tx {
+            signature: Some(signature),
+            ..tx
+        })
+    }
+
+    /// The signature as `send_fhe_tx` takes it, hex encoded, or empty if the
+    /// tx is unsigned.
+    pub fn encoded_signature(&self) -> String {
+        self.signature
+            .map(|signature| format!("0x{}", hex::encode(signature.to_vec())))
+            .unwrap_or_default()
+    }
+
+    /// Rebuilds a tx from a `Send_fhe_tx` event. `sender` is the account
+    /// that called the contract; a signed tx may have been relayed, so its
+    /// signer for `domain` becomes the account that is debited.
+    #[allow(clippy::too_many_arguments)]
     pub fn decode_from_onchain_tx(
-        fhe_oracle: &mut Oracle,
+        parameters: &Arc<BfvParameters>,
+        domain: &SigningDomain,
         tx_hash: String,
         sender: String,
         receiver: String,
         tx_sender: String,
         tx_receiver: String,
         tx_proof: String,
-    ) -> Tx {
-        let sender = hex::encode(sender.as_bytes());
-
-        let fhe_tx_sender = hex::decode(tx_sender).unwrap();
-        let fhe_tx_receiver = hex::decode(tx_receiver).unwrap();
+        nonce: u64,
+        signature: Option<String>,
+        block_number: u64,
+    ) -> Result<Tx, OracleError> {
+        let found: TxHash = tx_hash
+            .parse()
+            .map_err(|_| OracleError::MalformedTx(format!("bad tx hash {}", tx_hash)))?;
+        let sender_address: Address = sender
+            .parse()
+            .map_err(|_| OracleError::MalformedTx(format!("bad sender {}", sender)))?;
+
+        // the hash is checked against the ciphertext strings as emitted,
+        // before they are re-encoded
+        let expected = compute_fhe_tx_hash(&sender_address, &tx_sender, &tx_receiver, block_number);
+        if found != expected {
+            return Err(OracleError::TxHashMismatch { expected, found });
+        }
 
-        let tx_sender =
-            Ciphertext::from_bytes(&fhe_tx_sender, &fhe_oracle.parameters.clone()).unwrap();
-        let tx_receiver =
-            Ciphertext::from_bytes(&fhe_tx_receiver, &fhe_oracle.parameters.clone()).unwrap();
+        let signature = signature
+            .map(|signature| {
+                signature
+                    .parse::<Signature>()
+                    .map_err(|error| OracleError::InvalidSignature(error.to_string()))
+            })
+            .transpose()?;
+
+        // both ciphertexts carry the fingerprint of the parameters they were
+        // produced under, so foreign material is rejected before parsing
+        let tx_sender = decode_ciphertext(parameters, &tx_sender)?;
+        let tx_receiver = decode_ciphertext(parameters, &tx_receiver)?;
+        let (tx_proof, tx_auditor) = match serde_json::from_str::<AuditedProof>(&tx_proof) {
+            Ok(audited) => (
+                audited.proof,
+                Some(decode_ciphertext(parameters, &audited.tx_auditor)?),
+            ),
+            // a mangled envelope must not pass for a tx without a copy
+            Err(_) => {
+                TxProof::decode(&tx_proof).map_err(|_| {
+                    OracleError::MalformedTx(format!(
+                        "fhe_proof {} is neither a proof nor an audited one",
+                        tx_proof
+                    ))
+                })?;
+                (tx_proof, None)
+            }
+        };
 
-        Tx {
-            tx_hash: hex::encode(tx_hash.as_bytes()),
+        let mut tx = Tx {
+            tx_hash: found,
             sender,
             receiver,
             tx_sender,
             tx_receiver,
+            tx_auditor,
             tx_proof,
+            nonce,
+            signature,
+        };
+        if tx.signature.is_some() {
+            tx.sender = to_checksum(&tx.signer(domain)?, None);
         }
+
+        Ok(tx)
     }
 
-    pub fn serialize_ct_tx_string(&self) -> (String, String) {
-        let tx_sender = hex::encode(self.tx_sender.to_bytes());
-        let tx_receiver = hex::encode(self.tx_receiver.to_bytes());
+    pub fn serialize_ct_tx_string(&self, parameters: &BfvParameters) -> (String, String) {
+        let tx_sender = encode_ciphertext(parameters, &self.tx_sender);
+        let tx_receiver = encode_ciphertext(parameters, &self.tx_receiver);
 
         (tx_sender, tx_receiver)
     }
 
-    pub fn execute_tx(&self, fhe_oracle: &mut Oracle) -> Oracle {
-        let tx = self.clone();
+    /// The proof as `send_fhe_tx` takes it: its on-chain form, wrapped up
+    /// with the auditor copy if there is one. The node has to hold the proof
+    /// itself, see `Oracle::hold_proof`.
+    pub fn encoded_proof(&self, parameters: &BfvParameters) -> String {
+        let proof = TxProof::onchain_form(&self.tx_proof);
+        match &self.tx_auditor {
+            Some(tx_auditor) => serde_json::to_string(&AuditedProof {
+                proof,
+                tx_auditor: encode_ciphertext(parameters, tx_auditor),
+            })
+            .unwrap(),
+            None => proof,
+        }
+    }
+
+    /// The hash the contract will emit for this tx if it is sent with
+    /// `send_fhe_tx` and mined in `block_number`.
+    pub fn onchain_hash(
+        &self,
+        parameters: &BfvParameters,
+        block_number: u64,
+    ) -> Result<TxHash, OracleError> {
+        let sender: Address = self
+            .sender
+            .parse()
+            .map_err(|_| OracleError::MalformedTx(format!("bad sender {}", self.sender)))?;
+        let (tx_sender, tx_receiver) = self.serialize_ct_tx_string(parameters);
+
+        Ok(compute_fhe_tx_hash(
+            &sender,
+            &tx_sender,
+            &tx_receiver,
+            block_number,
+        ))
+    }
+
+    pub fn execute_tx(&self, fhe_oracle: &mut Oracle) -> Result<Oracle, OracleError> {
+        let prepared = self.prepare(fhe_oracle)?;
+        self.commit(fhe_oracle, prepared)?;
 
-        // check if tx_hash is in LIST_OF_TXS
-        check_tx_hash(tx.tx_hash.clone());
+        Ok(fhe_oracle.clone())
+    }
 
-        unsafe {
-            LIST_OF_TXS.push(tx.clone());
+    /// Checks the tx against `fhe_oracle` and computes the balances it
+    /// leaves behind, without changing anything.
+    pub fn prepare(&self, fhe_oracle: &Oracle) -> Result<PreparedTx, OracleError> {
+        // the receiver's update would overwrite the sender's debit
+        if self.sender == self.receiver {
+            return Err(OracleError::SelfTransfer(self.sender.clone()));
         }
+        fhe_oracle.check_tx_unseen(&self.tx_hash)?;
+
+        // both lookups happen before either write so an unknown receiver
+        // leaves the sender untouched
+        let sender_fhe_balance: Ciphertext =
+            fhe_oracle.return_user_fhe_balance(self.sender.clone())?;
+        let receiver_fhe_balance: Ciphertext =
+            fhe_oracle.return_user_fhe_balance(self.receiver.clone())?;
+
+        fhe_oracle.check_signature(self)?;
+        fhe_oracle.verifier.verify(fhe_oracle, self)?;
+        fhe_oracle.check_headroom(&self.sender, 1)?;
+        fhe_oracle.check_headroom(&self.receiver, 1)?;
+        fhe_oracle.check_attestation(&self.sender, &self.tx_sender)?;
+
+        Ok(PreparedTx {
+            sender_fhe_balance: &sender_fhe_balance - &self.tx_sender,
+            receiver_fhe_balance: &receiver_fhe_balance + &self.tx_receiver,
+        })
+    }
 
-        let sender = tx.sender.clone();
-        let receiver = tx.receiver.clone();
+    /// Writes the balances `prepare` computed. The checks that a tx executed
+    /// in between could have invalidated are made again first. The caller
+    /// has to keep both balances from changing in between, as
+    /// `SharedOracle` does with its account locks.
+    pub fn commit(&self, fhe_oracle: &mut Oracle, prepared: PreparedTx) -> Result<(), OracleError> {
+        fhe_oracle.check_tx_unseen(&self.tx_hash)?;
+        fhe_oracle.check_headroom(&self.sender, 1)?;
+        fhe_oracle.check_headroom(&self.receiver, 1)?;
+        fhe_oracle.check_attestation(&self.sender, &self.tx_sender)?;
+
+        fhe_oracle.mark_tx_seen(&self.tx_hash)?;
+        fhe_oracle.use_nonce(self)?;
+        fhe_oracle.update_user_fhe_balance(self.sender.clone(), prepared.sender_fhe_balance)?;
+        fhe_oracle.update_user_fhe_balance(self.receiver.clone(), prepared.receiver_fhe_balance)
+    }
+}
 
-        let sender_tx = tx.tx_sender.clone();
-        let receiver_tx = tx.tx_receiver.clone();
+impl Signed for Tx {
+    fn sender(&self) -> &str {
+        &self.sender
+    }
 
-        let sender_fhe_balance: Ciphertext = fhe_oracle.return_user_fhe_balance(sender.clone());
-        let receiver_fhe_balance: Ciphertext = fhe_oracle.return_user_fhe_balance(receiver.clone());
+    fn nonce(&self) -> u64 {
+        self.nonce
+    }
 
-        let sender_fhe_balance = &sender_fhe_balance - &sender_tx;
-        let receiver_fhe_balance = &receiver_fhe_balance + &receiver_tx;
+    fn signature(&self) -> Option<&Signature> {
+        self.signature.as_ref()
+    }
 
-        fhe_oracle.update_user_fhe_balance(sender.clone(), sender_fhe_balance);
-        fhe_oracle.update_user_fhe_balance(receiver.clone(), receiver_fhe_balance);
+    /// `keccak256(chain_id || contract || tx_sender || tx_receiver ||
+    /// receiver || nonce || proof)`, with the chain id and nonce as 32 big
+    /// endian bytes, both addresses as 20 bytes, both ciphertexts in their
+    /// serialized form and the proof as its `proof_commitment`. An auditor
+    /// copy is appended serialized too, so a relayer cannot strip or swap it.
+    fn signing_digest(&self, domain: &SigningDomain) -> Result<H256, OracleError> {
+        let receiver: Address = self
+            .receiver
+            .parse()
+            .map_err(|_| OracleError::MalformedTx(format!("bad receiver {}", self.receiver)))?;
+        let mut chain_id = [0u8; 32];
+        U256::from(domain.chain_id).to_big_endian(&mut chain_id);
+        let mut nonce = [0u8; 32];
+        U256::from(self.nonce).to_big_endian(&mut nonce);
+
+        let mut packed = chain_id.to_vec();
+        packed.extend_from_slice(domain.contract.as_bytes());
+        packed.extend_from_slice(&self.tx_sender.to_bytes());
+        packed.extend_from_slice(&self.tx_receiver.to_bytes());
+        packed.extend_from_slice(receiver.as_bytes());
+        packed.extend_from_slice(&nonce);
+        packed.extend_from_slice(self.proof_commitment().as_bytes());
+        if let Some(tx_auditor) = &self.tx_auditor {
+            packed.extend_from_slice(&tx_auditor.to_bytes());
+        }
 
-        fhe_oracle.clone()
+        Ok(H256::from(keccak256(packed)))
     }
+}
 
-    pub fn execute_withdrawal(
-        &self,
+/// The balances a transfer leaves behind, from `Tx::prepare`.
+pub struct PreparedTx {
+    sender_fhe_balance: Ciphertext,
+    receiver_fhe_balance: Ciphertext,
+}
+
+/// A request to take `value` units of `asset` out of `sender`'s account.
+/// `tx_sender` is the debit and `proof` opens it to the amount, so the
+/// Oracle checks the request without ever seeing the secret key.
+#[derive(Clone)]
+pub struct Withdrawal {
+    pub tx_hash: TxHash,
+    pub sender: String,
+    pub asset: AssetId,
+    pub value: u64,
+    pub tx_sender: Ciphertext,
+    pub proof: String,
+}
+
+impl Withdrawal {
+    pub fn decode_from_onchain_tx(
         fhe_oracle: &mut Oracle,
-        sk: SecretKey,
-        amt: u64,
-        new_pk: PublicKey,
-        new_fhe_balance: Ciphertext,
-    ) -> Oracle {
-        let tx = self.clone();
-
-        // check if tx_hash is in LIST_OF_TXS
-        check_tx_hash(tx.tx_hash.clone());
-
-        unsafe {
-            LIST_OF_TXS.push(tx.clone());
+        tx_hash: String,
+        sender: String,
+        value: u64,
+        tx_sender: String,
+        proof: String,
+        block_number: u64,
+    ) -> Result<Withdrawal, OracleError> {
+        let found: TxHash = tx_hash
+            .parse()
+            .map_err(|_| OracleError::MalformedTx(format!("bad tx hash {}", tx_hash)))?;
+        let sender_address: Address = sender
+            .parse()
+            .map_err(|_| OracleError::MalformedTx(format!("bad sender {}", sender)))?;
+
+        // withdraw_ETH_request hashes the debit alone, which packs the same
+        // as a transfer with an empty receiver side
+        let expected = compute_fhe_tx_hash(&sender_address, &tx_sender, "", block_number);
+        if found != expected {
+            return Err(OracleError::TxHashMismatch { expected, found });
         }
 
-        let pk = fhe_oracle.return_user_pk(tx.sender.clone());
-        let address = tx.sender.clone();
+        // the contract only pays out ETH
+        Ok(Withdrawal {
+            tx_hash: found,
+            sender,
+            asset: ETH,
+            value,
+            tx_sender: decode_ciphertext(&fhe_oracle.parameters, &tx_sender)?,
+            proof,
+        })
+    }
 
-        let user_old: User = User::new(
-            address.clone(),
-            String::from(""),
-            String::from(""),
-            sk.clone(),
-            pk.clone(),
-            fhe_oracle.return_user_fhe_balance(address.clone()),
-        );
+    /// The hash the contract will emit for this withdrawal if it is requested
+    /// in `block_number`.
+    pub fn onchain_hash(
+        &self,
+        parameters: &BfvParameters,
+        block_number: u64,
+    ) -> Result<TxHash, OracleError> {
+        let sender: Address = self
+            .sender
+            .parse()
+            .map_err(|_| OracleError::MalformedTx(format!("bad sender {}", self.sender)))?;
+
+        Ok(compute_fhe_tx_hash(
+            &sender,
+            &encode_ciphertext(parameters, &self.tx_sender),
+            "",
+            block_number,
+        ))
+    }
 
-        let user_old_fhe_balance = fhe_oracle.return_user_fhe_balance(address.clone());
-        let user_balance = &user_old_fhe_balance - &self.tx_sender.clone();
+    /// Debits the withdrawn amount. The owner pays out once the request has
+    /// gone through here, the account keeps its key and the rest of its
+    /// balance. The debit has to be attested not to overdraw, whether or not
+    /// the Oracle requires attestation of transfers.
+    pub fn execute_withdrawal(&self, fhe_oracle: &mut Oracle) -> Result<Oracle, OracleError> {
+        let fhe_balance = fhe_oracle.return_user_fhe_balance(self.sender.clone())?;
 
-        let user_old = User {
-            fhe_balance: user_balance.clone(),
-            ..user_old
-        };
+        fhe_oracle.check_tx_unseen(&self.tx_hash)?;
+        OpeningBackend.verify_withdrawal(fhe_oracle, self)?;
+        fhe_oracle.check_headroom(&self.sender, 1)?;
+        fhe_oracle.check_attested_debit(&self.sender, &self.tx_sender)?;
 
-        let user_balance = decoded_user_balance(&user_old);
+        let fhe_balance = &fhe_balance - &self.tx_sender;
 
-        if amt == user_balance {
-            let new_fhe_balance: Ciphertext = &new_fhe_balance + &tx.tx_receiver.clone();
-            fhe_oracle.update_user_fhe_balance(address.clone(), new_fhe_balance);
-            fhe_oracle.update_user_pk(address.clone(), new_pk);
-        } else {
-            println!("Not enough balance to withdraw");
-        }
+        fhe_oracle.mark_tx_seen(&self.tx_hash)?;
+        fhe_oracle.update_user_fhe_balance(self.sender.clone(), fhe_balance)?;
 
-        fhe_oracle.clone()
+        Ok(fhe_oracle.clone())
     }
 }
 
-pub fn check_tx_hash(tx_hash: String) -> bool {
-    for tx in unsafe { LIST_OF_TXS.iter() } {
-        if tx.tx_hash == tx_hash {
-            println!("Tx already exists in LIST_OF_TXS");
-            return true;
+/// A request to move `sender` to the key `fhe_pk`. `tx_sender` debits the
+/// whole balance under the old key, `fhe_balance` holds the same under the
+/// new one and `proof` opens both to the same balances. The sender attests
+/// that the debit leaves exactly zero behind, so the new balance is the one
+/// the account held.
+///
+/// It is signed and takes a nonce like a transfer, but cannot be relayed:
+/// the signer has to be the account that calls the contract.
+#[derive(Clone)]
+pub struct KeyRotation {
+    pub tx_hash: TxHash,
+    pub sender: String,
+    pub fhe_pk: PublicKey,
+    pub tx_sender: Ciphertext,
+    pub fhe_balance: Ciphertext,
+    pub proof: String,
+    pub nonce: u64,
+    /// EIP-191 signature by the sender's Ethereum key over `signing_digest`.
+    pub signature: Option<Signature>,
+}
+
+impl KeyRotation {
+    #[allow(clippy::too_many_arguments)]
+    pub fn decode_from_onchain_tx(
+        fhe_oracle: &mut Oracle,
+        tx_hash: String,
+        sender: String,
+        fhe_pk: String,
+        tx_sender: String,
+        fhe_balance: String,
+        proof: String,
+        nonce: u64,
+        signature: Option<String>,
+        block_number: u64,
+    ) -> Result<KeyRotation, OracleError> {
+        let found: TxHash = tx_hash
+            .parse()
+            .map_err(|_| OracleError::MalformedTx(format!("bad tx hash {}", tx_hash)))?;
+        let sender_address: Address = sender
+            .parse()
+            .map_err(|_| OracleError::MalformedTx(format!("bad sender {}", sender)))?;
+
+        // rotate_fhe_key packs the debit and the new balance where a
+        // transfer has its two halves
+        let expected = compute_fhe_tx_hash(&sender_address, &tx_sender, &fhe_balance, block_number);
+        if found != expected {
+            return Err(OracleError::TxHashMismatch { expected, found });
         }
+
+        let signature = signature
+            .map(|signature| {
+                signature
+                    .parse::<Signature>()
+                    .map_err(|error| OracleError::InvalidSignature(error.to_string()))
+            })
+            .transpose()?;
+
+        Ok(KeyRotation {
+            tx_hash: found,
+            sender,
+            fhe_pk: decode_pk(&fhe_oracle.parameters, &fhe_pk)?,
+            tx_sender: decode_ciphertext(&fhe_oracle.parameters, &tx_sender)?,
+            fhe_balance: decode_ciphertext(&fhe_oracle.parameters, &fhe_balance)?,
+            proof,
+            nonce,
+            signature,
+        })
+    }
+
+    /// Signs the rotation as the `nonce`th signed tx of the sender for
+    /// `domain`, the way `Tx::sign` signs a transfer.
+    pub fn sign(
+        self,
+        nonce: u64,
+        domain: &SigningDomain,
+        wallet: &LocalWallet,
+    ) -> Result<KeyRotation, OracleError> {
+        let rotation = KeyRotation { nonce, ..self };
+        let digest = rotation.signing_digest(domain)?;
+        let signature = wallet
+            .sign_hash(hash_message(digest))
+            .map_err(|error| OracleError::InvalidSignature(error.to_string()))?;
+
+        Ok(KeyRotation {
+            signature: Some(signature),
+            ..rotation
+        })
+    }
+
+    /// The signature as `rotate_fhe_key` takes it, hex encoded, or empty if
+    /// the rotation is unsigned.
+    pub fn encoded_signature(&self) -> String {
+        self.signature
+            .map(|signature| format!("0x{}", hex::encode(signature.to_vec())))
+            .unwrap_or_default()
+    }
+
+    /// The proof as it goes on-chain. It opens the whole balance, so the
+    /// chain only sees its commitment.
+    pub fn encoded_proof(&self) -> String {
+        TxProof::onchain_form(&self.proof)
+    }
+
+    /// The hash the contract will emit for this rotation if it is sent in
+    /// `block_number`.
+    pub fn onchain_hash(
+        &self,
+        parameters: &BfvParameters,
+        block_number: u64,
+    ) -> Result<TxHash, OracleError> {
+        let sender: Address = self
+            .sender
+            .parse()
+            .map_err(|_| OracleError::MalformedTx(format!("bad sender {}", self.sender)))?;
+
+        Ok(compute_fhe_tx_hash(
+            &sender,
+            &encode_ciphertext(parameters, &self.tx_sender),
+            &encode_ciphertext(parameters, &self.fhe_balance),
+            block_number,
+        ))
     }
 
-    false
+    /// Checks the signature, the proof and that the debit empties the
+    /// account, then swaps the account's key and balance at once.
+    pub fn execute_rotation(&self, fhe_oracle: &mut Oracle) -> Result<Oracle, OracleError> {
+        fhe_oracle.check_tx_unseen(&self.tx_hash)?;
+        fhe_oracle.check_signature(self)?;
+        OpeningBackend.verify_rotation(fhe_oracle, self)?;
+        fhe_oracle.check_emptying_attestation(&self.sender, &self.tx_sender)?;
+
+        fhe_oracle.mark_tx_seen(&self.tx_hash)?;
+        fhe_oracle.use_nonce(self)?;
+        fhe_oracle.rotate_user_key(
+            self.sender.clone(),
+            self.fhe_pk.clone(),
+            self.fhe_balance.clone(),
+        )?;
+
+        Ok(fhe_oracle.clone())
+    }
 }
 
-fn bytes_to_hex_string(bytes: &[u8]) -> String {
-    let hex_chars: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+impl Signed for KeyRotation {
+    fn sender(&self) -> &str {
+        &self.sender
+    }
+
+    fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    fn signature(&self) -> Option<&Signature> {
+        self.signature.as_ref()
+    }
 
-    hex_chars.join("")
+    /// `keccak256("Rotate_fhe_key" || chain_id || contract || fhe_pk ||
+    /// tx_sender || fhe_balance || nonce || proof)`, packed the way
+    /// `Tx::signing_digest` packs a transfer with the new key serialized and
+    /// the proof as its commitment. The tag keeps a rotation signature from
+    /// ever passing for a transfer one.
+    fn signing_digest(&self, domain: &SigningDomain) -> Result<H256, OracleError> {
+        let mut chain_id = [0u8; 32];
+        U256::from(domain.chain_id).to_big_endian(&mut chain_id);
+        let mut nonce = [0u8; 32];
+        U256::from(self.nonce).to_big_endian(&mut nonce);
+
+        let mut packed = b"Rotate_fhe_key".to_vec();
+        packed.extend_from_slice(&chain_id);
+        packed.extend_from_slice(domain.contract.as_bytes());
+        packed.extend_from_slice(&self.fhe_pk.to_bytes());
+        packed.extend_from_slice(&self.tx_sender.to_bytes());
+        packed.extend_from_slice(&self.fhe_balance.to_bytes());
+        packed.extend_from_slice(&nonce);
+        packed.extend_from_slice(TxProof::commitment_of(&self.proof).as_bytes());
+
+        Ok(H256::from(keccak256(packed)))
+    }
 }
 
-pub(crate) static mut LIST_OF_TXS: Vec<Tx> = Vec::new();
+/// Recomputes the id `send_fhe_tx` assigns, `keccak256(abi.encodePacked(
+/// msg.sender, _fhe_tx_sender, _fhe_tx_receiver, block.number))`.
+pub fn compute_fhe_tx_hash(
+    sender: &Address,
+    fhe_tx_sender: &str,
+    fhe_tx_receiver: &str,
+    block_number: u64,
+) -> TxHash {
+    // packed encoding: the address as 20 bytes, the strings as their raw
+    // bytes and the uint256 as 32 big endian bytes
+    let mut block = [0u8; 32];
+    U256::from(block_number).to_big_endian(&mut block);
+
+    let mut packed = sender.as_bytes().to_vec();
+    packed.extend_from_slice(fhe_tx_sender.as_bytes());
+    packed.extend_from_slice(fhe_tx_receiver.as_bytes());
+    packed.extend_from_slice(&block);
+
+    H256::from(keccak256(packed))
+}
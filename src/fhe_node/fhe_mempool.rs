@@ -0,0 +1,468 @@
+use crate::fhe_node::fhe_attestation::debit_id;
+use crate::fhe_node::fhe_execution::{Tx, TxHash};
+use crate::fhe_node::fhe_oracle::OracleError;
+use std::collections::{BTreeMap, HashMap};
+
+/// Where a queued tx stands. Submitted txs were sent to the chain and wait
+/// for their log, confirmed ones carry their position on the chain, which
+/// is the order they were executed in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TxStatus {
+    Pending,
+    Submitted,
+    Confirmed {
+        tx_hash: TxHash,
+        block: u64,
+        log_index: u64,
+    },
+}
+
+#[derive(Clone)]
+pub struct MempoolEntry {
+    pub nonce: u64,
+    pub tx: Tx,
+    pub status: TxStatus,
+}
+
+impl MempoolEntry {
+    pub fn is_confirmed(&self) -> bool {
+        matches!(self.status, TxStatus::Confirmed { .. })
+    }
+}
+
+#[derive(Clone, Default)]
+struct SenderQueue {
+    // nonces below this one were confirmed and pruned
+    base: u64,
+    entries: BTreeMap<u64, MempoolEntry>,
+}
+
+impl SenderQueue {
+    fn next_nonce(&self) -> u64 {
+        self.entries
+            .keys()
+            .next_back()
+            .map_or(self.base, |nonce| self.base.max(nonce + 1))
+    }
+
+    fn head(&self) -> Option<&MempoolEntry> {
+        self.entries.values().find(|entry| !entry.is_confirmed())
+    }
+}
+
+/// Txs built by this node, queued per sender under consecutive nonces.
+///
+/// Every debit is checked against the sender's balance as it is when the
+/// debit executes, so two debits from one wallet in flight at once race on
+/// the same ciphertext. Only the head of a sender's queue, its lowest
+/// unconfirmed nonce, is submitted; the next one follows once the head is
+/// confirmed on-chain.
+#[derive(Clone, Default)]
+pub struct Mempool {
+    senders: HashMap<String, SenderQueue>,
+}
+
+impl Mempool {
+    pub fn new() -> Mempool {
+        Mempool::default()
+    }
+
+    /// The nonce the next tx of `sender` has to use.
+    pub fn next_nonce(&self, sender: &str) -> u64 {
+        self.senders
+            .get(sender)
+            .map_or(0, |queue| queue.next_nonce())
+    }
+
+    /// Starts the nonces of `sender` at `nonce` when none of its txs are
+    /// queued, for senders with signed txs this node has not queued itself.
+    pub fn resume_from(&mut self, sender: &str, nonce: u64) {
+        let queue = self.senders.entry(sender.to_string()).or_default();
+
+        if queue.entries.is_empty() {
+            queue.base = queue.base.max(nonce);
+        }
+    }
+
+    /// Moves `sender` past `nonce`, taken by a signed request that does not
+    /// go through the queue, such as a key rotation. Refused while a tx of
+    /// `sender` is still waiting, since it holds a nonce the request would
+    /// have to come after.
+    pub fn skip_nonce(&mut self, sender: &str, nonce: u64) -> Result<(), OracleError> {
+        let queue = self.senders.entry(sender.to_string()).or_default();
+
+        if let Some(head) = queue.head() {
+            return Err(OracleError::NonceConflict {
+                address: sender.to_string(),
+                nonce: head.nonce,
+            });
+        }
+        queue.base = queue.base.max(nonce + 1);
+
+        Ok(())
+    }
+
+    /// Queues `tx` under `nonce`. Inserting the same tx again is a no-op, a
+    /// different tx under a used nonce is a conflict and skipping a nonce is
+    /// a gap.
+    pub fn insert(&mut self, nonce: u64, tx: Tx) -> Result<(), OracleError> {
+        let queue = self.senders.entry(tx.sender.clone()).or_default();
+
+        if let Some(entry) = queue.entries.get(&nonce) {
+            if debit_id(&entry.tx.sender, &entry.tx.tx_sender)
+                == debit_id(&tx.sender, &tx.tx_sender)
+            {
+                return Ok(());
+            }
+        }
+        if nonce < queue.next_nonce() {
+            return Err(OracleError::NonceConflict {
+                address: tx.sender.clone(),
+                nonce,
+            });
+        }
+        if nonce > queue.next_nonce() {
+            return Err(OracleError::NonceGap {
+                address: tx.sender.clone(),
+                expected: queue.next_nonce(),
+                found: nonce,
+            });
+        }
+
+        queue.entries.insert(
+            nonce,
+            MempoolEntry {
+                nonce,
+                tx,
+                status: TxStatus::Pending,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// The tx of `sender` that may be submitted now.
+    pub fn head(&self, sender: &str) -> Option<&MempoolEntry> {
+        self.senders.get(sender).and_then(|queue| queue.head())
+    }
+
+    pub fn status(&self, sender: &str, nonce: u64) -> Option<TxStatus> {
+        self.senders
+            .get(sender)
+            .and_then(|queue| queue.entries.get(&nonce))
+            .map(|entry| entry.status)
+    }
+
+    /// Marks the queued tx with the same debit as `tx`, as executed on-chain
+    /// at `block` and `log_index`, and returns its nonce. Txs that other
+    /// nodes built are not queued here and give `None`.
+    ///
+    /// A tx confirmed ahead of an earlier unconfirmed nonce of the same sender
+    /// means the ordering was broken and is reported as a gap.
+    pub fn confirm(
+        &mut self,
+        tx: &Tx,
+        block: u64,
+        log_index: u64,
+    ) -> Result<Option<u64>, OracleError> {
+        let queue = match self.senders.get_mut(&tx.sender) {
+            Some(queue) => queue,
+            None => return Ok(None),
+        };
+
+        let id = debit_id(&tx.sender, &tx.tx_sender);
+        let nonce = match queue
+            .entries
+            .values()
+            .find(|entry| debit_id(&entry.tx.sender, &entry.tx.tx_sender) == id)
+        {
+            // seeing the same log again changes nothing
+            Some(entry) if entry.is_confirmed() => return Ok(Some(entry.nonce)),
+            Some(entry) => entry.nonce,
+            None => return Ok(None),
+        };
+
+        let head = queue.head().map(|entry| entry.nonce);
+        if head != Some(nonce) {
+            return Err(OracleError::NonceGap {
+                address: tx.sender.clone(),
+                expected: head.unwrap_or(nonce),
+                found: nonce,
+            });
+        }
+
+        let entry = queue.entries.get_mut(&nonce).unwrap();
+        entry.tx.tx_hash = tx.tx_hash;
+        entry.status = TxStatus::Confirmed {
+            tx_hash: tx.tx_hash,
+            block,
+            log_index,
+        };
+
+        Ok(Some(nonce))
+    }
+
+    /// Every tx not confirmed yet, submitted or not, by sender and then
+    /// nonce.
+    pub fn pending(&self) -> Vec<&MempoolEntry> {
+        let mut pending: Vec<&MempoolEntry> = self
+            .senders
+            .values()
+            .flat_map(|queue| queue.entries.values())
+            .filter(|entry| !entry.is_confirmed())
+            .collect();
+        pending.sort_by(|a, b| (&a.tx.sender, a.nonce).cmp(&(&b.tx.sender, b.nonce)));
+
+        pending
+    }
+
+    /// Every confirmed tx, in chain order.
+    pub fn confirmed(&self) -> Vec<&MempoolEntry> {
+        let mut confirmed: Vec<(u64, u64, &MempoolEntry)> = self
+            .senders
+            .values()
+            .flat_map(|queue| queue.entries.values())
+            .filter_map(|entry| match entry.status {
+                TxStatus::Confirmed {
+                    block, log_index, ..
+                } => Some((block, log_index, entry)),
+                _ => None,
+            })
+            .collect();
+        confirmed.sort_by_key(|(block, log_index, _)| (*block, *log_index));
+
+        confirmed.into_iter().map(|(_, _, entry)| entry).collect()
+    }
+
+    /// Puts txs confirmed after `block` back to submitted, to follow
+    /// `Oracle::rollback_to` on a reorg. The chain takes them up again, so
+    /// they are not submitted a second time.
+    pub fn rollback_to(&mut self, block: u64) {
+        for queue in self.senders.values_mut() {
+            for entry in queue.entries.values_mut() {
+                if let TxStatus::Confirmed {
+                    block: confirmed, ..
+                } = entry.status
+                {
+                    if confirmed > block {
+                        entry.tx.tx_hash = TxHash::zero();
+                        entry.status = TxStatus::Submitted;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Marks the head of every queue that was not submitted yet as
+    /// submitted and returns them, by sender, so each goes on-chain once.
+    pub fn submit_heads(&mut self) -> Vec<MempoolEntry> {
+        let mut heads: Vec<MempoolEntry> = Vec::new();
+        for queue in self.senders.values_mut() {
+            let nonce = match queue.head() {
+                Some(entry) if entry.status == TxStatus::Pending => entry.nonce,
+                _ => continue,
+            };
+            let entry = queue.entries.get_mut(&nonce).unwrap();
+            entry.status = TxStatus::Submitted;
+            heads.push(entry.clone());
+        }
+        heads.sort_by(|a, b| a.tx.sender.cmp(&b.tx.sender));
+
+        heads
+    }
+
+    /// Drops the unconfirmed tx of `sender` at `nonce` and every later one,
+    /// which can no longer follow it, and returns them. Their nonces are
+    /// handed out again.
+    pub fn evict(&mut self, sender: &str, nonce: u64) -> Vec<MempoolEntry> {
+        let queue = match self.senders.get_mut(sender) {
+            Some(queue) => queue,
+            None => return Vec::new(),
+        };
+        if queue
+            .entries
+            .get(&nonce)
+            .map_or(true, |entry| entry.is_confirmed())
+        {
+            return Vec::new();
+        }
+
+        queue.entries.split_off(&nonce).into_values().collect()
+    }
+
+    /// Forgets the confirmed prefix of every queue up to `block`. Nonces
+    /// keep counting from where they were.
+    pub fn prune_confirmed(&mut self, block: u64) {
+        for queue in self.senders.values_mut() {
+            while let Some(entry) = queue.entries.values().next() {
+                match entry.status {
+                    TxStatus::Confirmed {
+                        block: confirmed, ..
+                    } if confirmed <= block => {
+                        let nonce = entry.nonce;
+                        queue.entries.remove(&nonce);
+                        queue.base = nonce + 1;
+                    }
+                    _ => break,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhe_account_handler::get_keys::tests::create_users;
+
+    #[test]
+    fn test_nonces_gaps_and_conflicts() {
+        let (fhe_oracle, alice, bob, ..) = create_users(100, 50);
+        let bob_user = fhe_oracle.users[&bob.address].clone();
+        let first = alice.create_tx(bob_user.clone(), &fhe_oracle, 10).unwrap();
+        let second = alice.create_tx(bob_user.clone(), &fhe_oracle, 20).unwrap();
+
+        let mut mempool = Mempool::new();
+        assert_eq!(mempool.next_nonce(&alice.address), 0);
+        assert_eq!(
+            mempool.insert(1, first.clone()),
+            Err(OracleError::NonceGap {
+                address: alice.address.clone(),
+                expected: 0,
+                found: 1
+            })
+        );
+
+        mempool.insert(0, first.clone()).unwrap();
+        mempool.insert(0, first.clone()).unwrap();
+        assert_eq!(
+            mempool.insert(0, second.clone()),
+            Err(OracleError::NonceConflict {
+                address: alice.address.clone(),
+                nonce: 0
+            })
+        );
+
+        mempool.insert(1, second.clone()).unwrap();
+        assert_eq!(mempool.next_nonce(&alice.address), 2);
+        assert_eq!(mempool.head(&alice.address).unwrap().nonce, 0);
+        assert_eq!(mempool.pending().len(), 2);
+
+        // a rotation cannot take a nonce while sends are waiting
+        assert_eq!(
+            mempool.skip_nonce(&alice.address, 2),
+            Err(OracleError::NonceConflict {
+                address: alice.address.clone(),
+                nonce: 0
+            })
+        );
+        mempool.skip_nonce(&bob.address, 0).unwrap();
+        assert_eq!(mempool.next_nonce(&bob.address), 1);
+    }
+
+    #[test]
+    fn test_confirmation_follows_nonces() {
+        let (fhe_oracle, alice, bob, ..) = create_users(100, 50);
+        let bob_user = fhe_oracle.users[&bob.address].clone();
+        let first = alice.create_tx(bob_user.clone(), &fhe_oracle, 10).unwrap();
+        let second = alice.create_tx(bob_user.clone(), &fhe_oracle, 20).unwrap();
+
+        let mut mempool = Mempool::new();
+        mempool.insert(0, first.clone()).unwrap();
+        mempool.insert(1, second.clone()).unwrap();
+
+        // the second debit cannot land before the first
+        assert_eq!(
+            mempool.confirm(&second, 5, 0),
+            Err(OracleError::NonceGap {
+                address: alice.address.clone(),
+                expected: 0,
+                found: 1
+            })
+        );
+
+        let mined = Tx {
+            tx_hash: TxHash::from_low_u64_be(1),
+            ..first.clone()
+        };
+        assert_eq!(mempool.confirm(&mined, 5, 2), Ok(Some(0)));
+        assert_eq!(mempool.confirm(&mined, 5, 2), Ok(Some(0)));
+        assert_eq!(
+            mempool.status(&alice.address, 0),
+            Some(TxStatus::Confirmed {
+                tx_hash: mined.tx_hash,
+                block: 5,
+                log_index: 2
+            })
+        );
+        assert_eq!(mempool.status(&alice.address, 1), Some(TxStatus::Pending));
+        assert_eq!(mempool.head(&alice.address).unwrap().nonce, 1);
+
+        // a tx this node never queued is left alone
+        let foreign = bob
+            .create_tx(fhe_oracle.users[&alice.address].clone(), &fhe_oracle, 5)
+            .unwrap();
+        assert_eq!(mempool.confirm(&foreign, 5, 3), Ok(None));
+
+        mempool.confirm(&second, 6, 0).unwrap();
+        let order: Vec<u64> = mempool
+            .confirmed()
+            .iter()
+            .map(|entry| entry.nonce)
+            .collect();
+        assert_eq!(order, vec![0, 1]);
+
+        mempool.rollback_to(5);
+        assert_eq!(mempool.status(&alice.address, 1), Some(TxStatus::Submitted));
+
+        mempool.prune_confirmed(5);
+        assert_eq!(mempool.status(&alice.address, 0), None);
+        assert_eq!(mempool.next_nonce(&alice.address), 2);
+        assert_eq!(
+            mempool.insert(0, first),
+            Err(OracleError::NonceConflict {
+                address: alice.address.clone(),
+                nonce: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_heads_submitted_once_and_evicted() {
+        let (fhe_oracle, alice, bob, ..) = create_users(100, 50);
+        let bob_user = fhe_oracle.users[&bob.address].clone();
+        let txs: Vec<Tx> = (1..4)
+            .map(|amount| {
+                alice
+                    .create_tx(bob_user.clone(), &fhe_oracle, amount)
+                    .unwrap()
+            })
+            .collect();
+
+        let mut mempool = Mempool::new();
+        for (nonce, tx) in txs.iter().enumerate() {
+            mempool.insert(nonce as u64, tx.clone()).unwrap();
+        }
+
+        let heads = mempool.submit_heads();
+        assert_eq!(heads.len(), 1);
+        assert_eq!(heads[0].nonce, 0);
+        assert!(mempool.submit_heads().is_empty());
+        assert_eq!(mempool.status(&alice.address, 0), Some(TxStatus::Submitted));
+
+        // the next nonce goes out once the head is confirmed
+        mempool.confirm(&txs[0], 5, 0).unwrap();
+        assert_eq!(mempool.submit_heads()[0].nonce, 1);
+
+        // a refused head takes the nonces queued after it along
+        let evicted: Vec<u64> = mempool
+            .evict(&alice.address, 1)
+            .iter()
+            .map(|entry| entry.nonce)
+            .collect();
+        assert_eq!(evicted, vec![1, 2]);
+        assert!(mempool.evict(&alice.address, 0).is_empty());
+        assert!(mempool.head(&alice.address).is_none());
+        assert_eq!(mempool.next_nonce(&alice.address), 1);
+    }
+}
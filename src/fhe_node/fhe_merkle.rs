@@ -0,0 +1,197 @@
+use crate::fhe_node::fhe_oracle::Oracle;
+use ethers::types::H256;
+use ethers::utils::keccak256;
+use fhe::bfv::{Ciphertext, PublicKey};
+use fhe_traits::Serialize as FheSerialize;
+use serde::{Deserialize, Serialize};
+
+// domain separation so a leaf can never be passed off as an inner node
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ProofStep {
+    pub sibling: H256,
+    pub sibling_is_left: bool,
+}
+
+/// Path from one account's leaf up to the root. Levels where the node had no
+/// sibling and was carried up unchanged have no step.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct MerkleProof {
+    pub address: String,
+    pub leaf: H256,
+    pub steps: Vec<ProofStep>,
+}
+
+/// Merkle tree over `Oracle.users`, one leaf per address in address order.
+#[derive(Clone)]
+pub struct MerkleTree {
+    addresses: Vec<String>,
+    levels: Vec<Vec<H256>>,
+}
+
+impl MerkleTree {
+    pub fn from_oracle(oracle: &Oracle) -> MerkleTree {
+        let mut addresses: Vec<String> = oracle.users.keys().cloned().collect();
+        addresses.sort();
+
+        let leaves: Vec<H256> = addresses
+            .iter()
+            .map(|address| {
+                let user = &oracle.users[address];
+                leaf_hash(address, &user.fhe_pk, &user.fhe_balance)
+            })
+            .collect();
+
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let next = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+
+        MerkleTree { addresses, levels }
+    }
+
+    /// Root of the tree, or the zero hash for an empty Oracle.
+    pub fn root(&self) -> H256 {
+        self.levels
+            .last()
+            .and_then(|level| level.first().copied())
+            .unwrap_or_default()
+    }
+
+    pub fn proof(&self, address: &str) -> Option<MerkleProof> {
+        let mut index = self
+            .addresses
+            .binary_search_by(|probe| probe.as_str().cmp(address))
+            .ok()?;
+
+        let leaf = self.levels[0][index];
+        let mut steps = Vec::new();
+
+        for level in self.levels.iter().take(self.levels.len() - 1) {
+            let sibling_index = index ^ 1;
+            if let Some(sibling) = level.get(sibling_index) {
+                steps.push(ProofStep {
+                    sibling: *sibling,
+                    sibling_is_left: sibling_index < index,
+                });
+            }
+            index /= 2;
+        }
+
+        Some(MerkleProof {
+            address: address.to_string(),
+            leaf,
+            steps,
+        })
+    }
+}
+
+pub fn leaf_hash(address: &str, fhe_pk: &PublicKey, fhe_balance: &Ciphertext) -> H256 {
+    let mut preimage = vec![LEAF_PREFIX];
+    preimage.extend_from_slice(address.as_bytes());
+    preimage.extend_from_slice(&keccak256(fhe_pk.to_bytes()));
+    preimage.extend_from_slice(&keccak256(fhe_balance.to_bytes()));
+
+    H256::from(keccak256(preimage))
+}
+
+fn node_hash(left: &H256, right: &H256) -> H256 {
+    let mut preimage = vec![NODE_PREFIX];
+    preimage.extend_from_slice(left.as_bytes());
+    preimage.extend_from_slice(right.as_bytes());
+
+    H256::from(keccak256(preimage))
+}
+
+/// Checks that `proof` links `leaf` to `root`.
+pub fn verify_proof(root: H256, leaf: H256, proof: &MerkleProof) -> bool {
+    let computed = proof.steps.iter().fold(leaf, |node, step| {
+        if step.sibling_is_left {
+            node_hash(&step.sibling, &node)
+        } else {
+            node_hash(&node, &step.sibling)
+        }
+    });
+
+    computed == root
+}
+
+/// Lets a wallet check that the key and encrypted balance the node returned
+/// for `address` are the ones committed to under `root`.
+pub fn verify_account(
+    root: H256,
+    address: &str,
+    fhe_pk: &PublicKey,
+    fhe_balance: &Ciphertext,
+    proof: &MerkleProof,
+) -> bool {
+    let leaf = leaf_hash(address, fhe_pk, fhe_balance);
+
+    proof.address == address && proof.leaf == leaf && verify_proof(root, leaf, proof)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhe_account_handler::get_keys::tests::create_users;
+
+    #[test]
+    fn test_proofs_verify_for_every_account() {
+        let (fhe_oracle, alice, bob, owner) = create_users(100, 50);
+        let tree = MerkleTree::from_oracle(&fhe_oracle);
+        let root = tree.root();
+
+        for user in [&alice, &bob, &owner] {
+            let proof = tree.proof(&user.address).unwrap();
+            assert!(verify_account(
+                root,
+                &user.address,
+                &user.fhe_pk,
+                &user.fhe_balance,
+                &proof
+            ));
+        }
+
+        assert!(tree.proof("0x0").is_none());
+    }
+
+    #[test]
+    fn test_proof_rejects_other_balance() {
+        let (fhe_oracle, alice, bob, ..) = create_users(100, 50);
+        let tree = MerkleTree::from_oracle(&fhe_oracle);
+
+        let proof = tree.proof(&alice.address).unwrap();
+        assert!(!verify_account(
+            tree.root(),
+            &alice.address,
+            &alice.fhe_pk,
+            &bob.fhe_balance,
+            &proof
+        ));
+    }
+
+    #[test]
+    fn test_root_tracks_balance_updates() {
+        let (fhe_oracle, alice, bob, ..) = create_users(100, 50);
+        let root = MerkleTree::from_oracle(&fhe_oracle).root();
+
+        let tx = alice
+            .create_tx(fhe_oracle.users[&bob.address].clone(), &fhe_oracle, 10)
+            .unwrap();
+        let fhe_oracle = tx.execute_tx(&mut fhe_oracle.clone()).unwrap();
+
+        assert_ne!(MerkleTree::from_oracle(&fhe_oracle).root(), root);
+    }
+}
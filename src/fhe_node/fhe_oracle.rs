@@ -1,15 +1,210 @@
+use crate::fhe_account_handler::keystore::KeystoreError;
 use crate::fhe_account_handler::user::User;
+use crate::fhe_node::fhe_assets::BalanceLayout;
+use crate::fhe_node::fhe_attestation::{balance_hash, debit_id, emptying_debit_id};
+use crate::fhe_node::fhe_execution::{Signed, SigningDomain, Tx, TxHash};
+use crate::fhe_node::fhe_params::{ParameterError, ParameterSet};
+use crate::fhe_node::fhe_proof::{OpeningBackend, TxProof, TxVerifier};
+use ethers::types::H256;
 use fhe::bfv::{
     BfvParameters, BfvParametersBuilder, Ciphertext, Encoding, Plaintext, PublicKey, SecretKey,
 };
 use fhe_traits::*;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::sync::Arc;
+
+/// Number of blocks of history the Oracle keeps around for rollbacks.
+pub const DEFAULT_RETENTION: u64 = 64;
+/// Number of blocks after which an executed tx hash is forgotten.
+pub const DEFAULT_FINALITY_DEPTH: u64 = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OracleError {
+    UnknownUser(String),
+    DuplicateUser(String),
+    DuplicateTx(TxHash),
+    TxHashMismatch {
+        expected: TxHash,
+        found: TxHash,
+    },
+    MalformedTx(String),
+    InvalidProof(String),
+    ProofNotHeld(H256),
+    ParameterMismatch(String),
+    MalformedCiphertext(String),
+    InsufficientFunds {
+        balance: u64,
+        value: u64,
+    },
+    InvalidAmount(u64),
+    UnknownAsset(usize),
+    Unrepresentable(u64),
+    NegativeBalance,
+    Overdraft(String),
+    AttestationFailed(String),
+    UnattestedDebit(String),
+    AttestationLocked(String),
+    BalanceLeftBehind(String),
+    NonceGap {
+        address: String,
+        expected: u64,
+        found: u64,
+    },
+    NonceConflict {
+        address: String,
+        nonce: u64,
+    },
+    InvalidSignature(String),
+    SelfTransfer(String),
+    HeadroomExhausted(String),
+    KeyMismatch(String),
+    SignerMismatch {
+        signer: String,
+        address: String,
+    },
+    Keystore(String),
+    RollbackTooDeep {
+        block: u64,
+        floor: u64,
+    },
+}
+
+impl fmt::Display for OracleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OracleError::UnknownUser(address) => write!(f, "unknown user {}", address),
+            OracleError::DuplicateUser(address) => {
+                write!(f, "user {} is already registered", address)
+            }
+            OracleError::DuplicateTx(tx_hash) => {
+                write!(f, "tx {:?} has already been executed", tx_hash)
+            }
+            OracleError::TxHashMismatch { expected, found } => write!(
+                f,
+                "tx hash {:?} does not match the recomputed {:?}",
+                found, expected
+            ),
+            OracleError::MalformedTx(reason) => write!(f, "malformed tx: {}", reason),
+            OracleError::InvalidProof(reason) => write!(f, "invalid tx proof: {}", reason),
+            OracleError::ParameterMismatch(reason) => write!(f, "{}", reason),
+            OracleError::MalformedCiphertext(reason) => {
+                write!(f, "malformed ciphertext: {}", reason)
+            }
+            OracleError::InsufficientFunds { balance, value } => write!(
+                f,
+                "insufficient funds: balance is {} but {} was requested",
+                balance, value
+            ),
+            OracleError::InvalidAmount(value) => write!(f, "invalid amount {}", value),
+            OracleError::UnknownAsset(asset) => write!(f, "unknown asset {}", asset),
+            OracleError::Unrepresentable(value) => {
+                write!(f, "{} does not fit in the balance limbs", value)
+            }
+            OracleError::ProofNotHeld(commitment) => write!(
+                f,
+                "the proof behind {:?} was never sent to this node",
+                commitment
+            ),
+            OracleError::NegativeBalance => write!(f, "balance decrypted to a negative amount"),
+            OracleError::Overdraft(address) => {
+                write!(f, "debit would overdraw the balance of {}", address)
+            }
+            OracleError::AttestationFailed(reason) => {
+                write!(f, "balance attestation failed: {}", reason)
+            }
+            OracleError::UnattestedDebit(address) => {
+                write!(f, "debit from {} has no valid balance attestation", address)
+            }
+            OracleError::AttestationLocked(address) => write!(
+                f,
+                "{} misreported a balance challenge and may not attest again",
+                address
+            ),
+            OracleError::BalanceLeftBehind(address) => {
+                write!(f, "debit does not take the whole balance of {}", address)
+            }
+            OracleError::NonceGap {
+                address,
+                expected,
+                found,
+            } => write!(
+                f,
+                "nonce {} of {} is out of order, expected {}",
+                found, address, expected
+            ),
+            OracleError::NonceConflict { address, nonce } => {
+                write!(f, "nonce {} of {} is already used", nonce, address)
+            }
+            OracleError::InvalidSignature(reason) => write!(f, "invalid tx signature: {}", reason),
+            OracleError::SelfTransfer(address) => write!(f, "{} cannot send to itself", address),
+            OracleError::HeadroomExhausted(address) => {
+                write!(
+                    f,
+                    "{} has used up its balance headroom, rotate its key",
+                    address
+                )
+            }
+            OracleError::KeyMismatch(address) => {
+                write!(f, "{} is registered under another FHE key", address)
+            }
+            OracleError::SignerMismatch { signer, address } => {
+                write!(f, "{} cannot sign for {}", signer, address)
+            }
+            OracleError::Keystore(reason) => write!(f, "{}", reason),
+            OracleError::RollbackTooDeep { block, floor } => write!(
+                f,
+                "cannot roll back to block {}, history is only kept after block {}",
+                block, floor
+            ),
+        }
+    }
+}
+
+impl std::error::Error for OracleError {}
+
+impl OracleError {
+    /// Whether the error only says this node was never sent the proof or
+    /// attestation it needs to check a tx, rather than that the tx is unsound.
+    pub fn lacks_off_chain_material(&self) -> bool {
+        matches!(
+            self,
+            OracleError::ProofNotHeld(_) | OracleError::UnattestedDebit(_)
+        )
+    }
+}
+
+impl From<ParameterError> for OracleError {
+    fn from(error: ParameterError) -> Self {
+        match error {
+            ParameterError::Mismatch { .. } => OracleError::ParameterMismatch(error.to_string()),
+            ParameterError::Malformed(reason) => OracleError::MalformedCiphertext(reason),
+        }
+    }
+}
+
+impl From<KeystoreError> for OracleError {
+    fn from(error: KeystoreError) -> Self {
+        OracleError::Keystore(error.to_string())
+    }
+}
+
+impl From<fhe::Error> for OracleError {
+    fn from(error: fhe::Error) -> Self {
+        OracleError::MalformedCiphertext(error.to_string())
+    }
+}
+
 #[derive(Clone)]
 pub struct OracleUser {
     pub address: String,
     pub fhe_pk: PublicKey,
     pub fhe_balance: Ciphertext,
+    /// Nonce the account's next signed tx has to carry.
+    pub nonce: u64,
+    /// Homomorphic adds and subs applied to the balance since it was last
+    /// encrypted afresh, see `BalanceLayout::max_updates`.
+    pub updates: u64,
 }
 
 impl OracleUser {
@@ -18,63 +213,535 @@ impl OracleUser {
             address,
             fhe_pk,
             fhe_balance,
+            nonce: 0,
+            updates: 0,
         }
     }
 
     pub fn from_user(user: User) -> OracleUser {
-        OracleUser {
-            address: user.address,
-            fhe_pk: user.fhe_pk,
-            fhe_balance: user.fhe_balance,
-        }
+        OracleUser::new(user.address, user.fhe_pk, user.fhe_balance)
     }
 }
 
+/// Undo record for one account: its state before `block` changed it, or
+/// `None` if `block` registered it.
+#[derive(Clone)]
+pub struct JournalEntry {
+    pub block: u64,
+    pub address: String,
+    pub previous: Option<OracleUser>,
+}
+
+/// Where the Oracle stood at some point of the current block, see
+/// `revert_to`.
+pub struct Checkpoint {
+    block: u64,
+    journal_len: usize,
+    // hashes the block had executed by then
+    seen_txs: HashSet<TxHash>,
+}
+
 #[derive(Clone)]
 pub struct Oracle {
     pub users: HashMap<String, OracleUser>,
     pub parameters: Arc<fhe::bfv::BfvParameters>,
+    pub current_block: u64,
+    pub journal: VecDeque<JournalEntry>,
+    pub retention: u64,
+    // blocks at or below this one have been pruned from the journal
+    pub journal_floor: u64,
+    /// Executed tx hashes and the block that executed them.
+    pub seen_txs: HashMap<TxHash, u64>,
+    pub finality_depth: u64,
+    /// Checks the proof of every transfer before it is executed.
+    pub verifier: Arc<dyn TxVerifier>,
+    /// Debits whose sender attested a non-negative remaining balance, keyed
+    /// by `debit_id` and mapped to the hash of the balance they were checked
+    /// against. Once the debit lands that balance is gone, so each one holds
+    /// for a single execution. Stored with the state so a replay accepts the
+    /// same debits the node did.
+    pub attested_debits: HashMap<H256, H256>,
+    /// Accounts caught misreporting a balance challenge. They get no more
+    /// challenges and so can no longer be debited while attestation is on.
+    pub locked_accounts: HashSet<String>,
+    /// Refuse debits without an attestation, see `fhe_attestation`. The node
+    /// turns it on and replays with the same settings; off by default so
+    /// tests that do not exercise it can debit freely.
+    pub require_attestation: bool,
+    /// Refuse transfers that are not signed by their sender. Signatures
+    /// that are present are checked either way.
+    pub require_signatures: bool,
+    /// Chain and contract tx signatures have to be made for.
+    pub signing_domain: SigningDomain,
+    /// Proofs sent to the node off-chain, keyed by the commitment that
+    /// stands in for them on-chain. Kept for as long as the state so a
+    /// replay can open them again.
+    pub held_proofs: HashMap<H256, String>,
+    /// Logs this node left out because it lacked their proof or attestation,
+    /// by block and log index. Stored with the state so a replay, which
+    /// fails on any other log it cannot check, leaves out the same ones.
+    pub refused_logs: BTreeSet<(u64, u64)>,
+    /// Key every transfer also encrypts its amount under, so the auditor
+    /// holding the secret key can read the flows. `None` until one is
+    /// registered, auditor copies go unchecked until then.
+    pub auditor_pk: Option<PublicKey>,
 }
 
 impl Oracle {
     pub fn new() -> Self {
-        let parameters = Arc::new(
-            BfvParametersBuilder::new()
-                .set_degree(2048)
-                .set_moduli(&[0x3fffffff000001])
-                .set_plaintext_modulus(1 << 10)
-                .build()
-                .unwrap(),
-        );
+        let parameters = ParameterSet::default_preset().build().unwrap();
+
+        Self::from_parameters(parameters)
+    }
+
+    pub fn from_parameter_set(
+        parameter_set: &ParameterSet,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self::from_parameters(parameter_set.build()?))
+    }
+
+    pub fn from_parameters(parameters: Arc<fhe::bfv::BfvParameters>) -> Self {
         let users = HashMap::new();
 
-        Self { users, parameters }
+        Self {
+            users,
+            parameters,
+            current_block: 0,
+            journal: VecDeque::new(),
+            retention: DEFAULT_RETENTION,
+            journal_floor: 0,
+            seen_txs: HashMap::new(),
+            finality_depth: DEFAULT_FINALITY_DEPTH,
+            verifier: Arc::new(OpeningBackend),
+            attested_debits: HashMap::new(),
+            locked_accounts: HashSet::new(),
+            require_attestation: false,
+            require_signatures: false,
+            signing_domain: SigningDomain::default(),
+            held_proofs: HashMap::new(),
+            refused_logs: BTreeSet::new(),
+            auditor_pk: None,
+        }
+    }
+
+    /// Tags every following change with `block` and forgets history that fell
+    /// out of the retention window and tx hashes past the finality depth.
+    pub fn begin_block(&mut self, block: u64) {
+        self.current_block = block;
+        self.prune_journal();
+        self.prune_seen_txs(self.finality_depth);
+    }
+
+    /// Undoes every change made after `block`, newest first.
+    pub fn rollback_to(&mut self, block: u64) -> Result<(), OracleError> {
+        if block < self.journal_floor {
+            return Err(OracleError::RollbackTooDeep {
+                block,
+                floor: self.journal_floor,
+            });
+        }
+
+        while self
+            .journal
+            .back()
+            .map_or(false, |entry| entry.block > block)
+        {
+            self.undo_last();
+        }
+
+        // txs of the orphaned blocks may be mined again on the new branch
+        self.seen_txs.retain(|_, seen_block| *seen_block <= block);
+        self.refused_logs
+            .retain(|(log_block, _)| *log_block <= block);
+        self.current_block = block;
+
+        Ok(())
+    }
+
+    /// Marks the current state so a group of changes that fails halfway can
+    /// be undone with `revert_to`. Only valid until the next `begin_block`.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            block: self.current_block,
+            journal_len: self.journal.len(),
+            seen_txs: self
+                .seen_txs
+                .iter()
+                .filter(|(_, seen_block)| **seen_block == self.current_block)
+                .map(|(tx_hash, _)| *tx_hash)
+                .collect(),
+        }
+    }
+
+    /// Undoes every change made since `checkpoint`, newest first.
+    pub fn revert_to(&mut self, checkpoint: Checkpoint) {
+        while self.journal.len() > checkpoint.journal_len {
+            self.undo_last();
+        }
+
+        self.seen_txs.retain(|tx_hash, seen_block| {
+            *seen_block < checkpoint.block || checkpoint.seen_txs.contains(tx_hash)
+        });
+        self.current_block = checkpoint.block;
+    }
+
+    fn undo_last(&mut self) {
+        let entry = match self.journal.pop_back() {
+            Some(entry) => entry,
+            None => return,
+        };
+        match entry.previous {
+            Some(user) => {
+                self.users.insert(entry.address, user);
+            }
+            None => {
+                self.users.remove(&entry.address);
+            }
+        }
+    }
+
+    fn prune_journal(&mut self) {
+        let floor = self.current_block.saturating_sub(self.retention);
+
+        while self
+            .journal
+            .front()
+            .map_or(false, |entry| entry.block <= floor)
+        {
+            self.journal.pop_front();
+        }
+
+        self.journal_floor = self.journal_floor.max(floor);
+    }
+
+    /// Forgets tx hashes executed more than `finality_depth` blocks ago.
+    pub fn prune_seen_txs(&mut self, finality_depth: u64) {
+        let floor = self.current_block.saturating_sub(finality_depth);
+
+        self.seen_txs.retain(|_, seen_block| *seen_block > floor);
+    }
+
+    pub fn has_seen_tx(&self, tx_hash: &TxHash) -> bool {
+        self.seen_txs.contains_key(tx_hash)
+    }
+
+    /// Keeps a proof sent to the node off-chain under its commitment, so the
+    /// `Committed` form posted on-chain can be opened. Proofs that reveal no
+    /// amounts go on-chain as they are and are not kept.
+    pub fn hold_proof(&mut self, proof: &str) -> Result<H256, OracleError> {
+        let commitment = TxProof::commitment_of(proof);
+        if TxProof::decode(proof)?.reveals_amounts() {
+            self.held_proofs.insert(commitment, proof.to_string());
+        }
+
+        Ok(commitment)
+    }
+
+    /// Decodes a proof, looking up the one a `Committed` proof stands for.
+    pub fn open_proof(&self, proof: &str) -> Result<TxProof, OracleError> {
+        match TxProof::decode(proof)? {
+            TxProof::Committed { commitment } => {
+                let held = self
+                    .held_proofs
+                    .get(&commitment)
+                    .ok_or(OracleError::ProofNotHeld(commitment))?;
+                TxProof::decode(held)
+            }
+            proof => Ok(proof),
+        }
+    }
+
+    /// Refuses `tx_hash` if it has already been executed. Run before anything
+    /// a tx consumes, such as its nonce, is used up.
+    pub fn check_tx_unseen(&self, tx_hash: &TxHash) -> Result<(), OracleError> {
+        if !tx_hash.is_zero() && self.has_seen_tx(tx_hash) {
+            return Err(OracleError::DuplicateTx(*tx_hash));
+        }
+
+        Ok(())
+    }
+
+    /// Records `tx_hash` as executed in the current block. Txs built locally
+    /// have no hash until they are mined and are not tracked.
+    pub fn mark_tx_seen(&mut self, tx_hash: &TxHash) -> Result<(), OracleError> {
+        self.check_tx_unseen(tx_hash)?;
+        if tx_hash.is_zero() {
+            return Ok(());
+        }
+
+        self.seen_txs.insert(*tx_hash, self.current_block);
+
+        Ok(())
+    }
+
+    /// Checks `tx` is signed by its sender for the node's signing domain. An
+    /// unsigned tx passes only while signatures are not required.
+    pub fn check_signer<T: Signed>(&self, tx: &T) -> Result<(), OracleError> {
+        match tx.signature() {
+            Some(_) => tx.verify_signature(&self.signing_domain),
+            None if self.require_signatures => Err(OracleError::InvalidSignature(format!(
+                "tx from {} is not signed",
+                tx.sender()
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks the signature of `tx` with `check_signer` and that a signed tx
+    /// carries the sender's next nonce.
+    pub fn check_signature<T: Signed>(&self, tx: &T) -> Result<(), OracleError> {
+        self.check_signer(tx)?;
+        if tx.signature().is_none() {
+            return Ok(());
+        }
+
+        let expected = self.return_user(tx.sender().to_string())?.nonce;
+        if tx.nonce() < expected {
+            return Err(OracleError::NonceConflict {
+                address: tx.sender().to_string(),
+                nonce: tx.nonce(),
+            });
+        }
+        if tx.nonce() > expected {
+            return Err(OracleError::NonceGap {
+                address: tx.sender().to_string(),
+                expected,
+                found: tx.nonce(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Moves the sender past the nonce of a signed `tx`.
+    pub fn use_nonce<T: Signed>(&mut self, tx: &T) -> Result<(), OracleError> {
+        if tx.signature().is_none() {
+            return Ok(());
+        }
+
+        self.check_signature(tx)?;
+        self.record(tx.sender());
+        self.users.get_mut(tx.sender()).unwrap().nonce += 1;
+
+        Ok(())
+    }
+
+    /// Checks the debit of `debit` from `address` was attested against the
+    /// account's current balance, if attestation is required. The record is
+    /// kept, the debit itself changes the balance it was made for.
+    pub fn check_attestation(&self, address: &str, debit: &Ciphertext) -> Result<(), OracleError> {
+        if !self.require_attestation {
+            return Ok(());
+        }
+
+        self.check_attested_debit(address, debit)
+    }
+
+    /// Same as `check_attestation`, whether or not attestation is required.
+    /// A withdrawal is paid out in ETH, so it is never taken on trust.
+    pub fn check_attested_debit(
+        &self,
+        address: &str,
+        debit: &Ciphertext,
+    ) -> Result<(), OracleError> {
+        self.check_attested(address, debit_id(address, debit))
+    }
+
+    /// Checks `address` attested that `debit` takes its whole current
+    /// balance, see `challenge_emptying_debit`. Required whether or not
+    /// attestation is: it is all that keeps a key rotation from moving more
+    /// than the account holds.
+    pub fn check_emptying_attestation(
+        &self,
+        address: &str,
+        debit: &Ciphertext,
+    ) -> Result<(), OracleError> {
+        self.check_attested(address, emptying_debit_id(address, debit))
+    }
+
+    fn check_attested(&self, address: &str, id: H256) -> Result<(), OracleError> {
+        let balance = self.return_user_fhe_balance(address.to_string())?;
+        if self.attested_debits.get(&id) != Some(&balance_hash(&balance)) {
+            return Err(OracleError::UnattestedDebit(address.to_string()));
+        }
+
+        Ok(())
+    }
+
+    fn record(&mut self, address: &str) {
+        self.journal.push_back(JournalEntry {
+            block: self.current_block,
+            address: address.to_string(),
+            previous: self.users.get(address).cloned(),
+        });
+    }
+
+    pub fn contains_user(&self, address: &str) -> bool {
+        self.users.contains_key(address)
+    }
+
+    pub fn add_user(&mut self, address: String, user: OracleUser) -> Result<(), OracleError> {
+        if self.contains_user(&address) {
+            return Err(OracleError::DuplicateUser(address));
+        }
+
+        self.record(&address);
+        self.users.insert(address.to_string(), user);
+
+        Ok(())
     }
 
-    pub fn add_user(&mut self, address: String, user: OracleUser) {
+    /// Swaps a registered user's key and balance for new ones. The nonce
+    /// carries over so signed txs stay spent.
+    pub fn replace_user(&mut self, address: String, user: OracleUser) -> Result<(), OracleError> {
+        if !self.contains_user(&address) {
+            return Err(OracleError::UnknownUser(address));
+        }
+
+        self.add_or_replace_user(address, user);
+
+        Ok(())
+    }
+
+    /// Registers `address` or, if it is already known, replaces it.
+    pub fn add_or_replace_user(&mut self, address: String, mut user: OracleUser) {
+        if let Some(previous) = self.users.get(&address) {
+            user.nonce = user.nonce.max(previous.nonce);
+        }
+
+        self.record(&address);
         self.users.insert(address.to_string(), user);
     }
 
-    pub fn update_user_fhe_balance(&mut self, address: String, fhe_balance: Ciphertext) {
-        self.users.get_mut(&address).unwrap().fhe_balance = fhe_balance;
+    /// Credits a deposit of `fhe_balance` to `address`, registering it under
+    /// `fhe_pk` if it is new. A known address has to deposit under the key it
+    /// is registered with, moving to another key is a rotation.
+    pub fn deposit(
+        &mut self,
+        address: String,
+        fhe_pk: PublicKey,
+        fhe_balance: Ciphertext,
+    ) -> Result<(), OracleError> {
+        let registered = match self.users.get(&address) {
+            Some(registered) => registered,
+            None => {
+                return self.add_user(
+                    address.clone(),
+                    OracleUser::new(address, fhe_pk, fhe_balance),
+                )
+            }
+        };
+        if registered.fhe_pk != fhe_pk {
+            return Err(OracleError::KeyMismatch(address));
+        }
+
+        let fhe_balance = &registered.fhe_balance + &fhe_balance;
+        self.update_user_fhe_balance(address, fhe_balance)
+    }
+
+    /// Refuses `updates` more adds or subs on the balance of `address` if
+    /// its limbs could wrap under them.
+    pub fn check_headroom(&self, address: &str, updates: u64) -> Result<(), OracleError> {
+        let user = self.return_user(address.to_string())?;
+        let max_updates = BalanceLayout::new(&self.parameters)?.max_updates();
+        if user.updates.saturating_add(updates) > max_updates {
+            return Err(OracleError::HeadroomExhausted(address.to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Stores the balance one add or sub left behind.
+    pub fn update_user_fhe_balance(
+        &mut self,
+        address: String,
+        fhe_balance: Ciphertext,
+    ) -> Result<(), OracleError> {
+        self.apply_updates(address, fhe_balance, 1)
+    }
+
+    /// Stores the balance `updates` adds and subs left behind.
+    pub fn apply_updates(
+        &mut self,
+        address: String,
+        fhe_balance: Ciphertext,
+        updates: u64,
+    ) -> Result<(), OracleError> {
+        self.check_headroom(&address, updates)?;
+
+        self.record(&address);
+        let user = self.users.get_mut(&address).unwrap();
+        user.fhe_balance = fhe_balance;
+        user.updates += updates;
+
+        Ok(())
     }
 
-    pub fn update_user_pk(&mut self, address: String, fhe_pk: PublicKey) {
+    pub fn update_user_pk(
+        &mut self,
+        address: String,
+        fhe_pk: PublicKey,
+    ) -> Result<(), OracleError> {
+        if !self.contains_user(&address) {
+            return Err(OracleError::UnknownUser(address));
+        }
+
+        self.record(&address);
         self.users.get_mut(&address).unwrap().fhe_pk = fhe_pk;
+
+        Ok(())
     }
 
-    pub fn return_user_fhe_balance(&self, address: String) -> Ciphertext {
-        self.users[&address].fhe_balance.clone()
+    /// Swaps the key of `address` and its balance under that key in one
+    /// step, so the account is never left with a balance it cannot decrypt.
+    pub fn rotate_user_key(
+        &mut self,
+        address: String,
+        fhe_pk: PublicKey,
+        fhe_balance: Ciphertext,
+    ) -> Result<(), OracleError> {
+        if !self.contains_user(&address) {
+            return Err(OracleError::UnknownUser(address));
+        }
+
+        self.record(&address);
+        let user = self.users.get_mut(&address).unwrap();
+        user.fhe_pk = fhe_pk;
+        user.fhe_balance = fhe_balance;
+        user.updates = 0;
+
+        Ok(())
+    }
+
+    pub fn return_user(&self, address: String) -> Result<&OracleUser, OracleError> {
+        self.users
+            .get(&address)
+            .ok_or(OracleError::UnknownUser(address))
+    }
+
+    pub fn return_user_fhe_balance(&self, address: String) -> Result<Ciphertext, OracleError> {
+        Ok(self.return_user(address)?.fhe_balance.clone())
+    }
+
+    /// From now on transfers have to carry an auditor copy of their amount
+    /// under `auditor_pk`.
+    pub fn register_auditor(&mut self, auditor_pk: PublicKey) {
+        self.auditor_pk = Some(auditor_pk);
     }
 
-    pub fn return_user_pk(&self, address: String) -> PublicKey {
-        self.users[&address].fhe_pk.clone()
+    pub fn return_user_pk(&self, address: String) -> Result<PublicKey, OracleError> {
+        Ok(self.return_user(address)?.fhe_pk.clone())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::fhe_node::fhe_oracle::{Oracle, OracleUser};
+    use crate::fhe_account_handler::get_keys::tests::create_users;
+    use crate::fhe_account_handler::user::create_user;
+    use crate::fhe_node::fhe_assets::BalanceLayout;
+    use crate::fhe_node::fhe_attestation::tests::attest_rotation;
+    use crate::fhe_node::fhe_execution::TxHash;
+    use crate::fhe_node::fhe_oracle::{Oracle, OracleError, OracleUser};
     use fhe::bfv::{BfvParameters, Ciphertext, Encoding, Plaintext, PublicKey, SecretKey};
     use fhe_traits::{FheDecoder, FheDecrypter, FheEncoder, FheEncrypter};
     use rand::thread_rng;
@@ -95,7 +762,7 @@ mod tests {
         let address = "0x123".to_string();
         let user = OracleUser::new(address.clone(), fhe_pk, fhe_balance);
         let address_clone = address.clone();
-        oracle.add_user(address_clone, user.clone());
+        oracle.add_user(address_clone, user.clone()).unwrap();
 
         assert_eq!(user.address, oracle.users[&address].address);
 
@@ -104,4 +771,159 @@ mod tests {
             Vec::<u64>::try_decode(&decrypted_plaintext, Encoding::poly()).unwrap();
         assert_eq!(decrypted_vector[0], 0);
     }
+
+    #[test]
+    fn test_unknown_and_duplicate_users() {
+        let (mut oracle, alice, bob, ..) = create_users(100, 50);
+
+        assert_eq!(
+            oracle.return_user_pk("0x0".to_string()),
+            Err(OracleError::UnknownUser("0x0".to_string()))
+        );
+        assert!(matches!(
+            oracle.update_user_fhe_balance("0x0".to_string(), alice.fhe_balance.clone()),
+            Err(OracleError::UnknownUser(_))
+        ));
+        assert_eq!(
+            oracle.add_user(alice.address.clone(), OracleUser::from_user(alice.clone())),
+            Err(OracleError::DuplicateUser(alice.address.clone()))
+        );
+        assert!(oracle
+            .replace_user(alice.address.clone(), OracleUser::from_user(bob.clone()))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_headroom_runs_out_until_rotation() {
+        let (mut oracle, alice, bob, ..) = create_users(100, 50);
+        let max_updates = BalanceLayout::new(&oracle.parameters)
+            .unwrap()
+            .max_updates();
+        oracle.users.get_mut(&bob.address).unwrap().updates = max_updates;
+
+        // bob cannot receive another amount without risking a wrapped limb
+        let tx = alice
+            .create_tx(oracle.users[&bob.address].clone(), &oracle, 10)
+            .unwrap();
+        assert_eq!(
+            tx.execute_tx(&mut oracle.clone()).err(),
+            Some(OracleError::HeadroomExhausted(bob.address.clone()))
+        );
+
+        // a rotation encrypts the balance afresh
+        let (rotated, rotation, _) = bob.rotate_key(&oracle, &mut thread_rng()).unwrap();
+        attest_rotation(&mut oracle, &bob, &rotation);
+        let mut oracle = rotation.execute_rotation(&mut oracle).unwrap();
+        assert_eq!(oracle.users[&bob.address].updates, 0);
+
+        let tx = alice
+            .create_tx(oracle.users[&bob.address].clone(), &oracle, 10)
+            .unwrap();
+        let oracle = tx.execute_tx(&mut oracle).unwrap();
+        assert_eq!(rotated.user_balance(&oracle).unwrap(), 60);
+        assert_eq!(oracle.users[&alice.address].updates, 1);
+    }
+
+    #[test]
+    fn test_rollback_to_block() {
+        let (mut oracle, alice, bob, ..) = create_users(100, 50);
+
+        oracle.begin_block(1);
+        let tx = alice
+            .create_tx(oracle.users[&bob.address].clone(), &oracle, 10)
+            .unwrap();
+        let mut oracle = tx.execute_tx(&mut oracle).unwrap();
+
+        oracle.begin_block(2);
+        let carol =
+            create_user("0xc0".to_string(), oracle.parameters.clone(), None, Some(5)).unwrap();
+        oracle
+            .add_user(carol.address.clone(), OracleUser::from_user(carol.clone()))
+            .unwrap();
+        assert_eq!(alice.user_balance(&oracle).unwrap(), 90);
+
+        oracle.rollback_to(1).unwrap();
+        assert!(!oracle.users.contains_key(&carol.address));
+        assert_eq!(alice.user_balance(&oracle).unwrap(), 90);
+
+        oracle.rollback_to(0).unwrap();
+        assert_eq!(alice.user_balance(&oracle).unwrap(), 100);
+        assert_eq!(bob.user_balance(&oracle).unwrap(), 50);
+    }
+
+    #[test]
+    fn test_revert_to_checkpoint() {
+        let (mut oracle, alice, bob, ..) = create_users(100, 50);
+
+        oracle.begin_block(1);
+        let mut tx = alice
+            .create_tx(oracle.users[&bob.address].clone(), &oracle, 10)
+            .unwrap();
+        tx.tx_hash = TxHash::from_low_u64_be(1);
+        let mut oracle = tx.execute_tx(&mut oracle).unwrap();
+
+        let checkpoint = oracle.checkpoint();
+        let mut tx = bob
+            .create_tx(oracle.users[&alice.address].clone(), &oracle, 5)
+            .unwrap();
+        tx.tx_hash = TxHash::from_low_u64_be(2);
+        let mut oracle = tx.execute_tx(&mut oracle).unwrap();
+        assert_eq!(alice.user_balance(&oracle).unwrap(), 95);
+
+        // only the changes made after the checkpoint are undone
+        oracle.revert_to(checkpoint);
+        assert_eq!(alice.user_balance(&oracle).unwrap(), 90);
+        assert_eq!(bob.user_balance(&oracle).unwrap(), 60);
+        assert!(oracle.has_seen_tx(&TxHash::from_low_u64_be(1)));
+        assert!(!oracle.has_seen_tx(&TxHash::from_low_u64_be(2)));
+    }
+
+    #[test]
+    fn test_rollback_outside_retention() {
+        let (mut oracle, alice, bob, ..) = create_users(100, 50);
+        oracle.retention = 2;
+
+        oracle.begin_block(1);
+        let tx = alice
+            .create_tx(oracle.users[&bob.address].clone(), &oracle, 10)
+            .unwrap();
+        let mut oracle = tx.execute_tx(&mut oracle).unwrap();
+
+        oracle.begin_block(10);
+        assert!(oracle.journal.is_empty());
+        assert_eq!(
+            oracle.rollback_to(0),
+            Err(OracleError::RollbackTooDeep { block: 0, floor: 8 })
+        );
+        assert!(oracle.rollback_to(8).is_ok());
+        assert_eq!(alice.user_balance(&oracle).unwrap(), 90);
+    }
+
+    #[test]
+    fn test_rejects_replayed_tx() {
+        let (mut oracle, alice, bob, ..) = create_users(100, 50);
+
+        oracle.begin_block(1);
+        let mut tx = alice
+            .create_tx(oracle.users[&bob.address].clone(), &oracle, 10)
+            .unwrap();
+        tx.tx_hash = TxHash::from_low_u64_be(1);
+
+        let mut oracle = tx.execute_tx(&mut oracle).unwrap();
+        assert_eq!(
+            tx.execute_tx(&mut oracle).err(),
+            Some(OracleError::DuplicateTx(tx.tx_hash))
+        );
+        assert_eq!(alice.user_balance(&oracle).unwrap(), 90);
+        assert_eq!(bob.user_balance(&oracle).unwrap(), 60);
+
+        // a reorg that drops block 1 lets the tx be mined again
+        oracle.rollback_to(0).unwrap();
+        assert!(!oracle.has_seen_tx(&tx.tx_hash));
+
+        oracle.begin_block(1);
+        let mut oracle = tx.execute_tx(&mut oracle).unwrap();
+        oracle.begin_block(1 + oracle.finality_depth);
+        assert!(!oracle.has_seen_tx(&tx.tx_hash));
+    }
 }
@@ -0,0 +1,267 @@
+use ethers::utils::{hex, keccak256};
+use fhe::bfv::{BfvParameters, BfvParametersBuilder, Ciphertext, PublicKey};
+use fhe_traits::{DeserializeParametrized, Serialize as FheSerialize};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+pub const DEFAULT_PRESET: &str = "bfv-2048";
+/// Environment variable naming either a preset or a JSON parameter file.
+pub const PARAMS_ENV: &str = "FHE_PARAMS";
+pub const PARAMETER_ID_LEN: usize = 8;
+
+pub type ParameterId = [u8; PARAMETER_ID_LEN];
+
+/// A BFV parameter set. Either `moduli` lists the ciphertext moduli or
+/// `moduli_sizes` asks the builder to pick primes of those bit sizes.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ParameterSet {
+    pub name: String,
+    pub degree: usize,
+    #[serde(default)]
+    pub moduli: Vec<u64>,
+    #[serde(default)]
+    pub moduli_sizes: Vec<usize>,
+    pub plaintext_modulus: u64,
+}
+
+impl ParameterSet {
+    pub fn preset(name: &str) -> Option<ParameterSet> {
+        match name {
+            "bfv-2048" => Some(ParameterSet {
+                name: name.to_string(),
+                degree: 2048,
+                moduli: vec![0x3fffffff000001],
+                moduli_sizes: vec![],
+                plaintext_modulus: 1 << 10,
+            }),
+            // 12289 is a prime congruent to 1 modulo 4096, so balances can be
+            // packed into SIMD slots
+            "bfv-simd-2048" => Some(ParameterSet {
+                name: name.to_string(),
+                degree: 2048,
+                moduli: vec![0x3fffffff000001],
+                moduli_sizes: vec![],
+                plaintext_modulus: 12289,
+            }),
+            "bfv-4096" => Some(ParameterSet {
+                name: name.to_string(),
+                degree: 4096,
+                moduli: vec![],
+                moduli_sizes: vec![36, 36, 37],
+                plaintext_modulus: 1 << 10,
+            }),
+            "bfv-8192" => Some(ParameterSet {
+                name: name.to_string(),
+                degree: 8192,
+                moduli: vec![],
+                moduli_sizes: vec![43, 43, 44, 44, 44],
+                plaintext_modulus: 1 << 10,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn default_preset() -> ParameterSet {
+        ParameterSet::preset(DEFAULT_PRESET).unwrap()
+    }
+
+    /// Reads a JSON parameter file, which holds either a full parameter set or
+    /// just `{"preset": "<name>"}`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<ParameterSet, Box<dyn std::error::Error>> {
+        let bytes = std::fs::read(path)?;
+        let value: serde_json::Value = serde_json::from_slice(&bytes)?;
+
+        if let Some(preset) = value.get("preset").and_then(|preset| preset.as_str()) {
+            return ParameterSet::preset(preset)
+                .ok_or_else(|| format!("unknown parameter preset {}", preset).into());
+        }
+
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Resolves `FHE_PARAMS` as a preset name first and a file path second,
+    /// falling back to the default preset when it is unset.
+    pub fn from_env() -> Result<ParameterSet, Box<dyn std::error::Error>> {
+        match std::env::var(PARAMS_ENV) {
+            Ok(value) => match ParameterSet::preset(&value) {
+                Some(preset) => Ok(preset),
+                None => ParameterSet::from_file(value),
+            },
+            Err(_) => Ok(ParameterSet::default_preset()),
+        }
+    }
+
+    pub fn build(&self) -> Result<Arc<BfvParameters>, Box<dyn std::error::Error>> {
+        let mut builder = BfvParametersBuilder::new();
+        builder
+            .set_degree(self.degree)
+            .set_plaintext_modulus(self.plaintext_modulus);
+
+        if self.moduli.is_empty() {
+            builder.set_moduli_sizes(&self.moduli_sizes);
+        } else {
+            builder.set_moduli(&self.moduli);
+        }
+
+        Ok(Arc::new(builder.build()?))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParameterError {
+    Malformed(String),
+    Mismatch {
+        expected: ParameterId,
+        found: ParameterId,
+    },
+}
+
+impl fmt::Display for ParameterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParameterError::Malformed(reason) => write!(f, "malformed FHE material: {}", reason),
+            ParameterError::Mismatch { expected, found } => write!(
+                f,
+                "FHE material was produced under parameters {} but the node uses {}",
+                hex::encode(found),
+                hex::encode(expected)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParameterError {}
+
+/// Fingerprint of a parameter set: the first bytes of the keccak256 of its
+/// serialization.
+pub fn parameter_id(parameters: &BfvParameters) -> ParameterId {
+    let mut id = [0u8; PARAMETER_ID_LEN];
+    id.copy_from_slice(&keccak256(parameters.to_bytes())[..PARAMETER_ID_LEN]);
+
+    id
+}
+
+/// Hex encodes `bytes` behind the fingerprint of `parameters`.
+pub fn encode_with_id(parameters: &BfvParameters, bytes: &[u8]) -> String {
+    let mut tagged = parameter_id(parameters).to_vec();
+    tagged.extend_from_slice(bytes);
+
+    hex::encode(tagged)
+}
+
+/// Strips and checks the fingerprint added by `encode_with_id`.
+pub fn decode_with_id(
+    parameters: &BfvParameters,
+    encoded: &str,
+) -> Result<Vec<u8>, ParameterError> {
+    let bytes = hex::decode(encoded.trim_start_matches("0x"))
+        .map_err(|error| ParameterError::Malformed(error.to_string()))?;
+
+    if bytes.len() < PARAMETER_ID_LEN {
+        return Err(ParameterError::Malformed(
+            "missing parameter fingerprint".to_string(),
+        ));
+    }
+
+    let mut found = [0u8; PARAMETER_ID_LEN];
+    found.copy_from_slice(&bytes[..PARAMETER_ID_LEN]);
+    let expected = parameter_id(parameters);
+
+    if found != expected {
+        return Err(ParameterError::Mismatch { expected, found });
+    }
+
+    Ok(bytes[PARAMETER_ID_LEN..].to_vec())
+}
+
+pub fn encode_ciphertext(parameters: &BfvParameters, ct: &Ciphertext) -> String {
+    encode_with_id(parameters, &ct.to_bytes())
+}
+
+pub fn decode_ciphertext(
+    parameters: &Arc<BfvParameters>,
+    encoded: &str,
+) -> Result<Ciphertext, ParameterError> {
+    let bytes = decode_with_id(parameters, encoded)?;
+
+    Ciphertext::from_bytes(&bytes, parameters)
+        .map_err(|error| ParameterError::Malformed(error.to_string()))
+}
+
+pub fn encode_pk(parameters: &BfvParameters, pk: &PublicKey) -> String {
+    encode_with_id(parameters, &pk.to_bytes())
+}
+
+pub fn decode_pk(
+    parameters: &Arc<BfvParameters>,
+    encoded: &str,
+) -> Result<PublicKey, ParameterError> {
+    let bytes = decode_with_id(parameters, encoded)?;
+
+    PublicKey::from_bytes(&bytes, parameters)
+        .map_err(|error| ParameterError::Malformed(error.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhe_account_handler::get_keys::tests::create_users;
+
+    #[test]
+    fn test_presets_have_distinct_ids() {
+        let small = ParameterSet::preset("bfv-2048").unwrap().build().unwrap();
+        let large = ParameterSet::preset("bfv-4096").unwrap().build().unwrap();
+
+        assert_ne!(parameter_id(&small), parameter_id(&large));
+        assert_eq!(
+            parameter_id(&small),
+            parameter_id(&ParameterSet::default_preset().build().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_parameter_file() {
+        let path = std::env::temp_dir().join(format!("fhe_params_{}.json", std::process::id()));
+
+        std::fs::write(&path, r#"{"preset": "bfv-4096"}"#).unwrap();
+        assert_eq!(
+            ParameterSet::from_file(&path).unwrap(),
+            ParameterSet::preset("bfv-4096").unwrap()
+        );
+
+        std::fs::write(
+            &path,
+            r#"{"name": "custom", "degree": 2048, "moduli": [18014398492704769], "plaintext_modulus": 2048}"#,
+        )
+        .unwrap();
+        let custom = ParameterSet::from_file(&path).unwrap();
+        assert_eq!(custom.plaintext_modulus, 2048);
+        assert!(custom.build().is_ok());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_foreign_ciphertext() {
+        let (fhe_oracle, alice, ..) = create_users(100, 50);
+        let other_parameters = ParameterSet::preset("bfv-4096").unwrap().build().unwrap();
+
+        let encoded = encode_ciphertext(&fhe_oracle.parameters, &alice.fhe_balance);
+        assert_eq!(
+            decode_ciphertext(&fhe_oracle.parameters, &encoded).unwrap(),
+            alice.fhe_balance
+        );
+        assert!(matches!(
+            decode_ciphertext(&other_parameters, &encoded),
+            Err(ParameterError::Mismatch { .. })
+        ));
+
+        let encoded = encode_pk(&fhe_oracle.parameters, &alice.fhe_pk);
+        assert!(matches!(
+            decode_pk(&other_parameters, &encoded),
+            Err(ParameterError::Mismatch { .. })
+        ));
+    }
+}
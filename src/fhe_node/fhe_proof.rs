@@ -0,0 +1,503 @@
+use crate::fhe_node::fhe_assets::{encode_asset, encode_balances, AssetId};
+use crate::fhe_node::fhe_execution::{KeyRotation, Tx, Withdrawal};
+use crate::fhe_node::fhe_oracle::{Oracle, OracleError};
+use ethers::types::H256;
+use ethers::utils::keccak256;
+use fhe::bfv::{BfvParameters, Ciphertext, Plaintext, PublicKey};
+use fhe_traits::FheEncrypter;
+use rand::{CryptoRng, Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Proof attached to a transfer, stored JSON encoded in `Tx.tx_proof`. A
+/// proof that gives amounts away reaches the node off-chain and only its
+/// `Committed` form goes into the `fhe_proof` field of the event.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum TxProof {
+    Transparent,
+    Opening {
+        asset: AssetId,
+        value: u64,
+        sender_seed: H256,
+        receiver_seed: H256,
+        /// Seed of the auditor copy, if the transfer carries one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        auditor_seed: Option<H256>,
+    },
+    Withdrawal {
+        asset: AssetId,
+        value: u64,
+        seed: H256,
+    },
+    Rotation {
+        balances: Vec<u64>,
+        old_seed: H256,
+        new_seed: H256,
+    },
+    /// Stands in on-chain for a proof held by the node, by the keccak256 of
+    /// its encoding. See `Oracle::hold_proof`.
+    Committed {
+        commitment: H256,
+    },
+}
+
+impl TxProof {
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn decode(encoded: &str) -> Result<TxProof, OracleError> {
+        serde_json::from_str(encoded).map_err(|error| OracleError::InvalidProof(error.to_string()))
+    }
+
+    /// Whether the proof shows amounts the chain must not see.
+    pub fn reveals_amounts(&self) -> bool {
+        matches!(self, TxProof::Opening { .. } | TxProof::Rotation { .. })
+    }
+
+    /// The commitment to an encoded proof. A `Committed` proof is its own
+    /// commitment, so a proof and the form it takes on-chain agree.
+    pub fn commitment_of(encoded: &str) -> H256 {
+        match TxProof::decode(encoded) {
+            Ok(TxProof::Committed { commitment }) => commitment,
+            _ => H256::from(keccak256(encoded.as_bytes())),
+        }
+    }
+
+    /// The form of an encoded proof that goes on-chain: its commitment if it
+    /// reveals amounts, otherwise the proof as is.
+    pub fn onchain_form(encoded: &str) -> String {
+        match TxProof::decode(encoded) {
+            Ok(proof) if proof.reveals_amounts() => TxProof::Committed {
+                commitment: TxProof::commitment_of(encoded),
+            }
+            .encode(),
+            _ => encoded.to_string(),
+        }
+    }
+}
+
+/// Both halves of a transfer, the auditor copy if asked for, and the proof
+/// that they agree.
+pub struct ProvenTransfer {
+    pub tx_sender: Ciphertext,
+    pub tx_receiver: Ciphertext,
+    pub tx_auditor: Option<Ciphertext>,
+    pub proof: TxProof,
+}
+
+pub trait TxProver {
+    /// Encrypts `value` units of `asset` once under each key, and under
+    /// `auditor_pk` if given, and proves the ciphertexts encrypt the same
+    /// amount. All the randomness is drawn from `rng`.
+    fn prove<R: RngCore + CryptoRng>(
+        &self,
+        parameters: &Arc<BfvParameters>,
+        sender_pk: &PublicKey,
+        receiver_pk: &PublicKey,
+        auditor_pk: Option<&PublicKey>,
+        asset: AssetId,
+        value: u64,
+        rng: &mut R,
+    ) -> Result<ProvenTransfer, OracleError>;
+}
+
+pub trait TxVerifier: Send + Sync {
+    /// Checks `tx.tx_proof` against the keys `fhe_oracle` has registered for
+    /// the sender and the receiver, opening a committed proof from the ones
+    /// it holds.
+    fn verify(&self, fhe_oracle: &Oracle, tx: &Tx) -> Result<(), OracleError>;
+}
+
+/// Test backend: encrypts as usual and accepts any transparent proof without
+/// checking it. Only meant for tests and local networks.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TransparentBackend;
+
+impl TxProver for TransparentBackend {
+    fn prove<R: RngCore + CryptoRng>(
+        &self,
+        parameters: &Arc<BfvParameters>,
+        sender_pk: &PublicKey,
+        receiver_pk: &PublicKey,
+        auditor_pk: Option<&PublicKey>,
+        asset: AssetId,
+        value: u64,
+        rng: &mut R,
+    ) -> Result<ProvenTransfer, OracleError> {
+        let plaintext = encode_asset(parameters, asset, value)?;
+
+        Ok(ProvenTransfer {
+            tx_sender: sender_pk.try_encrypt(&plaintext, rng)?,
+            tx_receiver: receiver_pk.try_encrypt(&plaintext, rng)?,
+            tx_auditor: auditor_pk
+                .map(|auditor_pk| auditor_pk.try_encrypt(&plaintext, rng))
+                .transpose()?,
+            proof: TxProof::Transparent,
+        })
+    }
+}
+
+impl TxVerifier for TransparentBackend {
+    fn verify(&self, _: &Oracle, tx: &Tx) -> Result<(), OracleError> {
+        match TxProof::decode(&tx.tx_proof)? {
+            TxProof::Transparent => Ok(()),
+            other => Err(OracleError::InvalidProof(format!(
+                "expected a transparent proof, got {:?}",
+                other
+            ))),
+        }
+    }
+}
+
+/// Proves equality by opening both encryptions. Each side is encrypted with
+/// randomness drawn from a fresh seed, the proof carries the amount and both
+/// seeds, and the verifier re-encrypts under the registered keys and compares.
+/// An auditor copy is opened the same way.
+///
+/// The proof is sound but not zero-knowledge: whoever reads it learns the
+/// amount. It is only sent to the node, which holds it, and `Send_fhe_tx`
+/// carries its `Committed` form.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OpeningBackend;
+
+fn encrypt_seeded(
+    parameters: &Arc<BfvParameters>,
+    pk: &PublicKey,
+    asset: AssetId,
+    value: u64,
+    seed: &H256,
+) -> Result<Ciphertext, OracleError> {
+    encrypt_plaintext_seeded(pk, &encode_asset(parameters, asset, value)?, seed)
+}
+
+fn encrypt_plaintext_seeded(
+    pk: &PublicKey,
+    plaintext: &Plaintext,
+    seed: &H256,
+) -> Result<Ciphertext, OracleError> {
+    let mut rng = ChaCha20Rng::from_seed(seed.to_fixed_bytes());
+
+    Ok(pk.try_encrypt(plaintext, &mut rng)?)
+}
+
+impl TxProver for OpeningBackend {
+    fn prove<R: RngCore + CryptoRng>(
+        &self,
+        parameters: &Arc<BfvParameters>,
+        sender_pk: &PublicKey,
+        receiver_pk: &PublicKey,
+        auditor_pk: Option<&PublicKey>,
+        asset: AssetId,
+        value: u64,
+        rng: &mut R,
+    ) -> Result<ProvenTransfer, OracleError> {
+        let sender_seed = H256::from(rng.gen::<[u8; 32]>());
+        let receiver_seed = H256::from(rng.gen::<[u8; 32]>());
+        let auditor_seed = auditor_pk.map(|_| H256::from(rng.gen::<[u8; 32]>()));
+
+        let tx_auditor = match (auditor_pk, &auditor_seed) {
+            (Some(auditor_pk), Some(seed)) => {
+                Some(encrypt_seeded(parameters, auditor_pk, asset, value, seed)?)
+            }
+            _ => None,
+        };
+
+        Ok(ProvenTransfer {
+            tx_sender: encrypt_seeded(parameters, sender_pk, asset, value, &sender_seed)?,
+            tx_receiver: encrypt_seeded(parameters, receiver_pk, asset, value, &receiver_seed)?,
+            tx_auditor,
+            proof: TxProof::Opening {
+                asset,
+                value,
+                sender_seed,
+                receiver_seed,
+                auditor_seed,
+            },
+        })
+    }
+}
+
+impl OpeningBackend {
+    /// Encrypts the debit of a withdrawal under the account's own key. The
+    /// amount leaves the contract in the clear anyway, so opening the debit
+    /// gives nothing away, and the secret key stays with the wallet.
+    pub fn prove_withdrawal<R: RngCore + CryptoRng>(
+        &self,
+        parameters: &Arc<BfvParameters>,
+        pk: &PublicKey,
+        asset: AssetId,
+        value: u64,
+        rng: &mut R,
+    ) -> Result<(Ciphertext, TxProof), OracleError> {
+        let seed = H256::from(rng.gen::<[u8; 32]>());
+
+        Ok((
+            encrypt_seeded(parameters, pk, asset, value, &seed)?,
+            TxProof::Withdrawal { asset, value, seed },
+        ))
+    }
+
+    /// Encrypts `balances` once under the old key, as the debit that empties
+    /// the account, and once under the new key, as its balance from then on.
+    /// Like a withdrawal the proof opens both, so it gives the balances away.
+    pub fn prove_rotation<R: RngCore + CryptoRng>(
+        &self,
+        parameters: &Arc<BfvParameters>,
+        old_pk: &PublicKey,
+        new_pk: &PublicKey,
+        balances: &[u64],
+        rng: &mut R,
+    ) -> Result<(Ciphertext, Ciphertext, TxProof), OracleError> {
+        let plaintext = encode_balances(parameters, balances)?;
+        let old_seed = H256::from(rng.gen::<[u8; 32]>());
+        let new_seed = H256::from(rng.gen::<[u8; 32]>());
+
+        Ok((
+            encrypt_plaintext_seeded(old_pk, &plaintext, &old_seed)?,
+            encrypt_plaintext_seeded(new_pk, &plaintext, &new_seed)?,
+            TxProof::Rotation {
+                balances: balances.to_vec(),
+                old_seed,
+                new_seed,
+            },
+        ))
+    }
+
+    /// Checks the debit of `rotation` opens to the proven balances under the
+    /// registered key and its new balance to the same under the new key.
+    pub fn verify_rotation(
+        &self,
+        fhe_oracle: &Oracle,
+        rotation: &KeyRotation,
+    ) -> Result<(), OracleError> {
+        let (balances, old_seed, new_seed) = match fhe_oracle.open_proof(&rotation.proof)? {
+            TxProof::Rotation {
+                balances,
+                old_seed,
+                new_seed,
+            } => (balances, old_seed, new_seed),
+            other => {
+                return Err(OracleError::InvalidProof(format!(
+                    "expected a rotation proof, got {:?}",
+                    other
+                )))
+            }
+        };
+
+        let plaintext = encode_balances(&fhe_oracle.parameters, &balances)?;
+        let old_pk = fhe_oracle.return_user_pk(rotation.sender.clone())?;
+
+        if encrypt_plaintext_seeded(&old_pk, &plaintext, &old_seed)? != rotation.tx_sender {
+            return Err(OracleError::InvalidProof(
+                "tx_sender does not open to the proven balances".to_string(),
+            ));
+        }
+        if encrypt_plaintext_seeded(&rotation.fhe_pk, &plaintext, &new_seed)?
+            != rotation.fhe_balance
+        {
+            return Err(OracleError::InvalidProof(
+                "the new balance does not open to the proven balances".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Checks `withdrawal.tx_sender` opens to the withdrawn amount under the
+    /// key registered for the account.
+    pub fn verify_withdrawal(
+        &self,
+        fhe_oracle: &Oracle,
+        withdrawal: &Withdrawal,
+    ) -> Result<(), OracleError> {
+        let (asset, value, seed) = match fhe_oracle.open_proof(&withdrawal.proof)? {
+            TxProof::Withdrawal { asset, value, seed } => (asset, value, seed),
+            other => {
+                return Err(OracleError::InvalidProof(format!(
+                    "expected a withdrawal proof, got {:?}",
+                    other
+                )))
+            }
+        };
+
+        if value == 0 {
+            return Err(OracleError::InvalidAmount(value));
+        }
+        if (asset, value) != (withdrawal.asset, withdrawal.value) {
+            return Err(OracleError::InvalidProof(format!(
+                "proof is for {} of asset {}, the request is for {} of asset {}",
+                value, asset, withdrawal.value, withdrawal.asset
+            )));
+        }
+
+        let pk = fhe_oracle.return_user_pk(withdrawal.sender.clone())?;
+        if encrypt_seeded(&fhe_oracle.parameters, &pk, asset, value, &seed)? != withdrawal.tx_sender
+        {
+            return Err(OracleError::InvalidProof(
+                "tx_sender does not open to the withdrawn amount".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl TxVerifier for OpeningBackend {
+    fn verify(&self, fhe_oracle: &Oracle, tx: &Tx) -> Result<(), OracleError> {
+        let (asset, value, sender_seed, receiver_seed, auditor_seed) =
+            match fhe_oracle.open_proof(&tx.tx_proof)? {
+                TxProof::Opening {
+                    asset,
+                    value,
+                    sender_seed,
+                    receiver_seed,
+                    auditor_seed,
+                } => (asset, value, sender_seed, receiver_seed, auditor_seed),
+                other => {
+                    return Err(OracleError::InvalidProof(format!(
+                        "expected an opening proof, got {:?}",
+                        other
+                    )))
+                }
+            };
+
+        if value == 0 {
+            return Err(OracleError::InvalidAmount(value));
+        }
+
+        let parameters = &fhe_oracle.parameters;
+        let sender_pk = fhe_oracle.return_user_pk(tx.sender.clone())?;
+        let receiver_pk = fhe_oracle.return_user_pk(tx.receiver.clone())?;
+
+        if encrypt_seeded(parameters, &sender_pk, asset, value, &sender_seed)? != tx.tx_sender {
+            return Err(OracleError::InvalidProof(
+                "tx_sender does not open to the proven amount".to_string(),
+            ));
+        }
+        if encrypt_seeded(parameters, &receiver_pk, asset, value, &receiver_seed)? != tx.tx_receiver
+        {
+            return Err(OracleError::InvalidProof(
+                "tx_receiver does not open to the proven amount".to_string(),
+            ));
+        }
+
+        // without a registered key there is nothing to check the copy against
+        if let Some(auditor_pk) = &fhe_oracle.auditor_pk {
+            let (seed, tx_auditor) = match (auditor_seed, &tx.tx_auditor) {
+                (Some(seed), Some(tx_auditor)) => (seed, tx_auditor),
+                _ => {
+                    return Err(OracleError::InvalidProof(
+                        "the auditor copy is missing".to_string(),
+                    ))
+                }
+            };
+            if encrypt_seeded(parameters, auditor_pk, asset, value, &seed)? != *tx_auditor {
+                return Err(OracleError::InvalidProof(
+                    "tx_auditor does not open to the proven amount".to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhe_account_handler::get_keys::tests::create_users;
+
+    #[test]
+    fn test_opening_proof_verifies() {
+        let (fhe_oracle, alice, bob, ..) = create_users(100, 50);
+
+        let tx = alice
+            .create_tx(fhe_oracle.users[&bob.address].clone(), &fhe_oracle, 10)
+            .unwrap();
+
+        assert!(OpeningBackend.verify(&fhe_oracle, &tx).is_ok());
+        assert!(matches!(
+            TxProof::decode(&tx.tx_proof).unwrap(),
+            TxProof::Opening { value: 10, .. }
+        ));
+    }
+
+    #[test]
+    fn test_opening_proof_rejects_mismatched_halves() {
+        let (fhe_oracle, alice, bob, ..) = create_users(100, 50);
+
+        let tx = alice
+            .create_tx(fhe_oracle.users[&bob.address].clone(), &fhe_oracle, 10)
+            .unwrap();
+        let other = alice
+            .create_tx(fhe_oracle.users[&bob.address].clone(), &fhe_oracle, 50)
+            .unwrap();
+
+        // the receiver would be credited 50 while the sender pays 10
+        let forged = Tx {
+            tx_receiver: other.tx_receiver.clone(),
+            ..tx.clone()
+        };
+        assert!(matches!(
+            OpeningBackend.verify(&fhe_oracle, &forged),
+            Err(OracleError::InvalidProof(_))
+        ));
+
+        let transparent = Tx {
+            tx_proof: TxProof::Transparent.encode(),
+            ..tx.clone()
+        };
+        assert!(OpeningBackend.verify(&fhe_oracle, &transparent).is_err());
+        assert!(TransparentBackend.verify(&fhe_oracle, &transparent).is_ok());
+        assert!(TransparentBackend.verify(&fhe_oracle, &tx).is_err());
+    }
+
+    #[test]
+    fn test_committed_proof_opens_from_the_node() {
+        let (mut fhe_oracle, alice, bob, ..) = create_users(100, 50);
+
+        let tx = alice
+            .create_tx(fhe_oracle.users[&bob.address].clone(), &fhe_oracle, 10)
+            .unwrap();
+        let onchain = Tx {
+            tx_proof: TxProof::onchain_form(&tx.tx_proof),
+            ..tx.clone()
+        };
+
+        // the chain sees neither the amount nor the seeds
+        assert!(matches!(
+            TxProof::decode(&onchain.tx_proof).unwrap(),
+            TxProof::Committed { .. }
+        ));
+        assert_eq!(onchain.proof_commitment(), tx.proof_commitment());
+        assert!(matches!(
+            OpeningBackend.verify(&fhe_oracle, &onchain),
+            Err(OracleError::InvalidProof(_))
+        ));
+
+        fhe_oracle.hold_proof(&tx.tx_proof).unwrap();
+        assert!(OpeningBackend.verify(&fhe_oracle, &onchain).is_ok());
+    }
+
+    #[test]
+    fn test_execute_tx_refuses_bad_proof() {
+        let (mut fhe_oracle, alice, bob, ..) = create_users(100, 50);
+
+        let tx = alice
+            .create_tx(fhe_oracle.users[&bob.address].clone(), &fhe_oracle, 10)
+            .unwrap();
+        let forged = Tx {
+            tx_proof: String::from("0"),
+            ..tx
+        };
+
+        assert!(matches!(
+            forged.execute_tx(&mut fhe_oracle),
+            Err(OracleError::InvalidProof(_))
+        ));
+        assert_eq!(alice.user_balance(&fhe_oracle).unwrap(), 100);
+    }
+}
@@ -0,0 +1,1099 @@
+use crate::fhe_node::{
+    fhe_block::execute_block,
+    fhe_execution::{KeyRotation, SigningDomain, Tx, Withdrawal},
+    fhe_mempool::Mempool,
+    fhe_oracle::{Oracle, OracleError},
+    fhe_params::{decode_ciphertext, decode_pk},
+};
+use crate::fhe_tx_sender::contract_deployer::DEPLOYED_BLOCK;
+use ethers::abi::{decode, ParamType, Token};
+use ethers::providers::{Http, Middleware, Provider};
+use ethers::types::{Address, Filter, Log, H256};
+use ethers::utils::{hex, keccak256, to_checksum};
+use fhe::bfv::BfvParameters;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+pub const DEPOSIT_EVENT: &str = "Deposit_fETH(address,uint256,string,string)";
+pub const SEND_EVENT: &str =
+    "Send_fhe_tx(address,address,bytes32,string,string,string,uint256,bytes)";
+pub const ROTATE_KEY_EVENT: &str =
+    "Rotate_fhe_key(address,bytes32,string,string,string,string,uint256,bytes)";
+pub const WITHDRAW_REQUEST_EVENT: &str =
+    "Withdraw_ETH_Request(address,uint256,bytes32,string,string)";
+pub const WITHDRAW_APPROVED_EVENT: &str = "Withdraw_ETH_Approved(address,uint256,bytes32)";
+
+/// A decoded FHEToken event. Field names follow the contract and the
+/// ciphertext material is kept in the hex form the node posted on-chain.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "event")]
+pub enum OracleEvent {
+    #[serde(rename = "Deposit_fETH")]
+    Deposit {
+        from: String,
+        amount: String,
+        fhe_pk: String,
+        fhe_balance_init: String,
+    },
+    #[serde(rename = "Send_fhe_tx")]
+    Send {
+        from: String,
+        to: String,
+        fhe_tx_hash: String,
+        fhe_tx_sender: String,
+        fhe_tx_receiver: String,
+        fhe_proof: String,
+        #[serde(default)]
+        nonce: u64,
+        /// Hex encoded, empty for a tx the sender submitted unsigned.
+        #[serde(default)]
+        fhe_signature: String,
+    },
+    #[serde(rename = "Rotate_fhe_key")]
+    RotateKey {
+        from: String,
+        fhe_tx_hash: String,
+        fhe_pk: String,
+        fhe_tx_sender: String,
+        fhe_balance: String,
+        fhe_proof: String,
+        #[serde(default)]
+        nonce: u64,
+        /// Hex encoded, empty for an unsigned rotation.
+        #[serde(default)]
+        fhe_signature: String,
+    },
+    #[serde(rename = "Withdraw_ETH_Request")]
+    WithdrawRequest {
+        to: String,
+        amount: String,
+        fhe_tx_hash: String,
+        fhe_tx_sender: String,
+        fhe_proof: String,
+    },
+    #[serde(rename = "Withdraw_ETH_Approved")]
+    WithdrawApproved {
+        to: String,
+        amount: String,
+        fhe_tx_hash: String,
+    },
+}
+
+impl OracleEvent {
+    /// The account whose balance the event changes, the sender's for a
+    /// transfer.
+    pub fn account(&self) -> &str {
+        match self {
+            OracleEvent::Deposit { from, .. }
+            | OracleEvent::Send { from, .. }
+            | OracleEvent::RotateKey { from, .. } => from,
+            OracleEvent::WithdrawRequest { to, .. } | OracleEvent::WithdrawApproved { to, .. } => {
+                to
+            }
+        }
+    }
+}
+
+/// An event together with its position on the chain, which fixes the order
+/// it is replayed in.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct OracleLog {
+    pub block_number: u64,
+    pub log_index: u64,
+    #[serde(flatten)]
+    pub event: OracleEvent,
+}
+
+/// A log that could not be applied and was left out, with the reason.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct SkippedLog {
+    pub block_number: u64,
+    pub log_index: u64,
+    pub reason: String,
+}
+
+impl SkippedLog {
+    pub fn new(log: &OracleLog, reason: impl fmt::Display) -> SkippedLog {
+        SkippedLog {
+            block_number: log.block_number,
+            log_index: log.log_index,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// Rebuilds the accounts of `fhe_oracle`, which should hold none yet, by
+/// applying `logs` in chain order and ignoring anything emitted before
+/// `DEPLOYED_BLOCK`. `fhe_oracle` brings the node's settings, the proofs and
+/// attestations it was sent off-chain and the logs it refused for want of
+/// them. Returns it together with the logs that were left out.
+///
+/// Fails on a log it lacks the proof or attestation for that the node did
+/// not refuse, as the accounts would no longer match the node's.
+pub fn replay_logs(
+    mut fhe_oracle: Oracle,
+    logs: Vec<OracleLog>,
+) -> Result<(Oracle, Vec<SkippedLog>), Box<dyn std::error::Error>> {
+    let mut logs: Vec<OracleLog> = logs
+        .into_iter()
+        .filter(|log| log.block_number >= DEPLOYED_BLOCK)
+        .collect();
+    logs.sort_by_key(|log| (log.block_number, log.log_index));
+
+    let skipped = apply_chain_logs(&mut fhe_oracle, &logs, true)?;
+
+    Ok((fhe_oracle, skipped))
+}
+
+/// Brings `fhe_oracle` up to the chain at `url` by applying the logs emitted
+/// after the last block it has seen. An Oracle without a snapshot applies
+/// all of them as strictly as `replay_logs` does. Returns the logs that were
+/// left out.
+pub async fn catch_up(
+    fhe_oracle: &mut Oracle,
+    url: &str,
+    contract_address: &str,
+) -> Result<Vec<SkippedLog>, Box<dyn std::error::Error>> {
+    let current_block = fhe_oracle.current_block;
+    let mut logs: Vec<OracleLog> = fetch_logs(url, contract_address, current_block + 1)
+        .await?
+        .into_iter()
+        .filter(|log| log.block_number > current_block)
+        .collect();
+    logs.sort_by_key(|log| (log.block_number, log.log_index));
+
+    apply_chain_logs(fhe_oracle, &logs, current_block == 0)
+}
+
+/// Handles a reorg: undoes everything after `common_ancestor` and applies
+/// the canonical logs that come after it, returning those left out.
+pub fn reorg_to(
+    fhe_oracle: &mut Oracle,
+    common_ancestor: u64,
+    canonical_logs: Vec<OracleLog>,
+) -> Result<Vec<SkippedLog>, Box<dyn std::error::Error>> {
+    fhe_oracle.rollback_to(common_ancestor)?;
+
+    let mut logs: Vec<OracleLog> = canonical_logs
+        .into_iter()
+        .filter(|log| log.block_number > common_ancestor)
+        .collect();
+    logs.sort_by_key(|log| (log.block_number, log.log_index));
+
+    Ok(apply_logs(fhe_oracle, &logs))
+}
+
+/// Applies `logs`, which must be in chain order. Consecutive transfers of the
+/// same block go through `execute_block` together, anything else is applied
+/// on its own so deposits and withdrawals keep their place between them.
+///
+/// A log that cannot be decoded or applied is left out and returned, and the
+/// logs after it, in its block or later ones, still apply. One left out for
+/// want of its proof or attestation is recorded in `refused_logs`.
+pub fn apply_logs(fhe_oracle: &mut Oracle, logs: &[OracleLog]) -> Vec<SkippedLog> {
+    apply_chain_logs(fhe_oracle, logs, false).expect("only a strict replay fails")
+}
+
+// `apply_logs`, which with `strict` fails instead on a log it lacks the
+// proof or attestation for that is not in `refused_logs`. Logs that are
+// there are left out either way
+fn apply_chain_logs(
+    fhe_oracle: &mut Oracle,
+    logs: &[OracleLog],
+    strict: bool,
+) -> Result<Vec<SkippedLog>, Box<dyn std::error::Error>> {
+    let mut skipped: Vec<SkippedLog> = Vec::new();
+    let mut pending: Vec<Tx> = Vec::new();
+    let mut pending_logs: Vec<&OracleLog> = Vec::new();
+
+    for (i, log) in logs.iter().enumerate() {
+        let refused = fhe_oracle
+            .refused_logs
+            .contains(&(log.block_number, log.log_index));
+        match &log.event {
+            OracleEvent::Send { .. } => {
+                if log.block_number != fhe_oracle.current_block {
+                    fhe_oracle.begin_block(log.block_number);
+                }
+                if refused {
+                    skipped.push(SkippedLog::new(log, REFUSED));
+                } else {
+                    match decode_send_log(&fhe_oracle.parameters, &fhe_oracle.signing_domain, log) {
+                        Ok(tx) => {
+                            pending.push(tx);
+                            pending_logs.push(log);
+                        }
+                        Err(error) => skipped.push(SkippedLog::new(log, error)),
+                    }
+                }
+
+                let run_continues = logs.get(i + 1).map_or(false, |next| {
+                    next.block_number == log.block_number
+                        && matches!(next.event, OracleEvent::Send { .. })
+                });
+                if !run_continues {
+                    match execute_block(fhe_oracle, &pending) {
+                        Ok(rejected) => {
+                            for tx in rejected {
+                                skipped.push(leave_out(
+                                    fhe_oracle,
+                                    pending_logs[tx.index],
+                                    &tx.error,
+                                    strict,
+                                )?);
+                            }
+                        }
+                        Err(error) => skipped
+                            .extend(pending_logs.iter().map(|log| SkippedLog::new(log, &error))),
+                    }
+                    pending.clear();
+                    pending_logs.clear();
+                }
+            }
+            _ if refused => skipped.push(SkippedLog::new(log, REFUSED)),
+            _ => {
+                if let Err(error) = apply_log(fhe_oracle, log) {
+                    skipped.push(leave_out(fhe_oracle, log, error.as_ref(), strict)?);
+                }
+            }
+        }
+    }
+
+    skipped.sort_by_key(|log| (log.block_number, log.log_index));
+    Ok(skipped)
+}
+
+const REFUSED: &str = "refused when this node first applied it";
+
+/// Whether `error` only says this node lacks the proof or attestation a log
+/// needs, see `OracleError::lacks_off_chain_material`.
+pub fn lacks_off_chain_material(error: &(dyn std::error::Error + 'static)) -> bool {
+    error
+        .downcast_ref::<OracleError>()
+        .map_or(false, OracleError::lacks_off_chain_material)
+}
+
+// reports `log` as left out for `error`, recording it in `refused_logs` if
+// the node lacked what it needed to check it. A strict replay fails instead,
+// the node applied the log with material the replay was not given
+fn leave_out(
+    fhe_oracle: &mut Oracle,
+    log: &OracleLog,
+    error: &(dyn std::error::Error + 'static),
+    strict: bool,
+) -> Result<SkippedLog, Box<dyn std::error::Error>> {
+    if lacks_off_chain_material(error) {
+        if strict {
+            return Err(format!(
+                "log {} of block {} was not refused by the node but cannot be checked here: {}",
+                log.log_index, log.block_number, error
+            )
+            .into());
+        }
+        fhe_oracle
+            .refused_logs
+            .insert((log.block_number, log.log_index));
+    }
+
+    Ok(SkippedLog::new(log, error))
+}
+
+pub fn apply_log(
+    fhe_oracle: &mut Oracle,
+    log: &OracleLog,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if log.block_number != fhe_oracle.current_block {
+        fhe_oracle.begin_block(log.block_number);
+    }
+
+    match &log.event {
+        OracleEvent::Deposit {
+            from,
+            fhe_pk,
+            fhe_balance_init,
+            ..
+        } => {
+            let fhe_pk = decode_pk(&fhe_oracle.parameters, fhe_pk)?;
+            let fhe_balance = decode_ciphertext(&fhe_oracle.parameters, fhe_balance_init)?;
+
+            // a deposit from a known address adds to its balance
+            fhe_oracle.deposit(from.clone(), fhe_pk, fhe_balance)?;
+        }
+        OracleEvent::Send { .. } => {
+            let tx = decode_send_log(&fhe_oracle.parameters, &fhe_oracle.signing_domain, log)?;
+            tx.execute_tx(fhe_oracle)?;
+        }
+        OracleEvent::RotateKey {
+            from,
+            fhe_tx_hash,
+            fhe_pk,
+            fhe_tx_sender,
+            fhe_balance,
+            fhe_proof,
+            nonce,
+            fhe_signature,
+        } => {
+            let rotation = KeyRotation::decode_from_onchain_tx(
+                fhe_oracle,
+                fhe_tx_hash.clone(),
+                from.clone(),
+                fhe_pk.clone(),
+                fhe_tx_sender.clone(),
+                fhe_balance.clone(),
+                fhe_proof.clone(),
+                *nonce,
+                Some(fhe_signature.clone()).filter(|signature| !signature.is_empty()),
+                log.block_number,
+            )?;
+            rotation.execute_rotation(fhe_oracle)?;
+        }
+        // the debit happens on request, the owner only pays out requests
+        // the Oracle accepted
+        OracleEvent::WithdrawRequest {
+            to,
+            amount,
+            fhe_tx_hash,
+            fhe_tx_sender,
+            fhe_proof,
+        } => {
+            let withdrawal = Withdrawal::decode_from_onchain_tx(
+                fhe_oracle,
+                fhe_tx_hash.clone(),
+                to.clone(),
+                amount.parse()?,
+                fhe_tx_sender.clone(),
+                fhe_proof.clone(),
+                log.block_number,
+            )?;
+            withdrawal.execute_withdrawal(fhe_oracle)?;
+        }
+        OracleEvent::WithdrawApproved { .. } => {}
+    }
+
+    Ok(())
+}
+
+/// Marks the transfers in `logs` that this node queued as confirmed at their
+/// chain position and returns the logs the mempool refused. Logs have to be
+/// in chain order. Ones that do not decode are left to `apply_logs`, which
+/// reports them.
+pub fn confirm_logs(
+    mempool: &mut Mempool,
+    fhe_oracle: &Oracle,
+    logs: &[OracleLog],
+) -> Vec<SkippedLog> {
+    let mut skipped = Vec::new();
+    for log in logs.iter() {
+        if let OracleEvent::Send { .. } = log.event {
+            let tx = match decode_send_log(&fhe_oracle.parameters, &fhe_oracle.signing_domain, log)
+            {
+                Ok(tx) => tx,
+                Err(_) => continue,
+            };
+            if let Err(error) = mempool.confirm(&tx, log.block_number, log.log_index) {
+                skipped.push(SkippedLog::new(log, error));
+            }
+        }
+    }
+
+    skipped
+}
+
+/// Decodes the transfer of a `Send_fhe_tx` log, attributing a signed one to
+/// its signer for `domain`. Needs no Oracle, so wallets following their own
+/// notes use it too.
+pub fn decode_send_log(
+    parameters: &Arc<BfvParameters>,
+    domain: &SigningDomain,
+    log: &OracleLog,
+) -> Result<Tx, Box<dyn std::error::Error>> {
+    match &log.event {
+        OracleEvent::Send {
+            from,
+            to,
+            fhe_tx_hash,
+            fhe_tx_sender,
+            fhe_tx_receiver,
+            fhe_proof,
+            nonce,
+            fhe_signature,
+        } => Ok(Tx::decode_from_onchain_tx(
+            parameters,
+            domain,
+            fhe_tx_hash.clone(),
+            from.clone(),
+            to.clone(),
+            fhe_tx_sender.clone(),
+            fhe_tx_receiver.clone(),
+            fhe_proof.clone(),
+            *nonce,
+            Some(fhe_signature.clone()).filter(|signature| !signature.is_empty()),
+            log.block_number,
+        )?),
+        _ => Err("not a Send_fhe_tx event".into()),
+    }
+}
+
+/// Reads logs exported as a JSON array of `OracleLog`.
+pub fn load_logs_json<P: AsRef<Path>>(
+    path: P,
+) -> Result<Vec<OracleLog>, Box<dyn std::error::Error>> {
+    let bytes = std::fs::read(path)?;
+    let logs: Vec<OracleLog> = serde_json::from_slice(&bytes)?;
+
+    Ok(logs)
+}
+
+/// Fetches the FHEToken logs from the node at `url` emitted in `from_block`
+/// or later, and never before `DEPLOYED_BLOCK`.
+pub async fn fetch_logs(
+    url: &str,
+    contract_address: &str,
+    from_block: u64,
+) -> Result<Vec<OracleLog>, Box<dyn std::error::Error>> {
+    let provider = Provider::<Http>::try_from(url)?;
+
+    let filter = Filter::new()
+        .address(contract_address.parse::<Address>()?)
+        .from_block(from_block.max(DEPLOYED_BLOCK));
+
+    let logs = provider.get_logs(&filter).await?;
+
+    let mut oracle_logs = Vec::new();
+    for log in logs.iter() {
+        if let Some(oracle_log) = decode_log(log)? {
+            oracle_logs.push(oracle_log);
+        }
+    }
+
+    Ok(oracle_logs)
+}
+
+/// Decodes a raw FHEToken log, returning `None` for events the Oracle does
+/// not follow.
+pub fn decode_log(log: &Log) -> Result<Option<OracleLog>, Box<dyn std::error::Error>> {
+    let topic = match log.topics.first() {
+        Some(topic) => *topic,
+        None => return Ok(None),
+    };
+
+    let event = if topic == H256::from(keccak256(DEPOSIT_EVENT)) {
+        let data = decode(
+            &[ParamType::Uint(256), ParamType::String, ParamType::String],
+            &log.data,
+        )?;
+
+        OracleEvent::Deposit {
+            from: topic_address(log, 1)?,
+            amount: token_uint(&data[0]),
+            fhe_pk: token_string(&data[1]),
+            fhe_balance_init: token_string(&data[2]),
+        }
+    } else if topic == H256::from(keccak256(SEND_EVENT)) {
+        let data = decode(
+            &[
+                ParamType::FixedBytes(32),
+                ParamType::String,
+                ParamType::String,
+                ParamType::String,
+                ParamType::Uint(256),
+                ParamType::Bytes,
+            ],
+            &log.data,
+        )?;
+        let signature = data[5].clone().into_bytes().unwrap_or_default();
+
+        OracleEvent::Send {
+            from: topic_address(log, 1)?,
+            to: topic_address(log, 2)?,
+            fhe_tx_hash: format!("0x{}", data[0]),
+            fhe_tx_sender: token_string(&data[1]),
+            fhe_tx_receiver: token_string(&data[2]),
+            fhe_proof: token_string(&data[3]),
+            nonce: data[4].clone().into_uint().unwrap_or_default().low_u64(),
+            fhe_signature: if signature.is_empty() {
+                String::new()
+            } else {
+                format!("0x{}", hex::encode(signature))
+            },
+        }
+    } else if topic == H256::from(keccak256(ROTATE_KEY_EVENT)) {
+        let data = decode(
+            &[
+                ParamType::FixedBytes(32),
+                ParamType::String,
+                ParamType::String,
+                ParamType::String,
+                ParamType::String,
+                ParamType::Uint(256),
+                ParamType::Bytes,
+            ],
+            &log.data,
+        )?;
+        let signature = data[6].clone().into_bytes().unwrap_or_default();
+
+        OracleEvent::RotateKey {
+            from: topic_address(log, 1)?,
+            fhe_tx_hash: format!("0x{}", data[0]),
+            fhe_pk: token_string(&data[1]),
+            fhe_tx_sender: token_string(&data[2]),
+            fhe_balance: token_string(&data[3]),
+            fhe_proof: token_string(&data[4]),
+            nonce: data[5].clone().into_uint().unwrap_or_default().low_u64(),
+            fhe_signature: if signature.is_empty() {
+                String::new()
+            } else {
+                format!("0x{}", hex::encode(signature))
+            },
+        }
+    } else if topic == H256::from(keccak256(WITHDRAW_REQUEST_EVENT)) {
+        let data = decode(
+            &[
+                ParamType::Uint(256),
+                ParamType::FixedBytes(32),
+                ParamType::String,
+                ParamType::String,
+            ],
+            &log.data,
+        )?;
+
+        OracleEvent::WithdrawRequest {
+            to: topic_address(log, 1)?,
+            amount: token_uint(&data[0]),
+            fhe_tx_hash: format!("0x{}", data[1]),
+            fhe_tx_sender: token_string(&data[2]),
+            fhe_proof: token_string(&data[3]),
+        }
+    } else if topic == H256::from(keccak256(WITHDRAW_APPROVED_EVENT)) {
+        let data = decode(
+            &[ParamType::Uint(256), ParamType::FixedBytes(32)],
+            &log.data,
+        )?;
+
+        OracleEvent::WithdrawApproved {
+            to: topic_address(log, 1)?,
+            amount: token_uint(&data[0]),
+            fhe_tx_hash: format!("0x{}", data[1]),
+        }
+    } else {
+        return Ok(None);
+    };
+
+    let block_number = log.block_number.ok_or("log is still pending")?.as_u64();
+    let log_index = log.log_index.ok_or("log is still pending")?.as_u64();
+
+    Ok(Some(OracleLog {
+        block_number,
+        log_index,
+        event,
+    }))
+}
+
+fn topic_address(log: &Log, index: usize) -> Result<String, Box<dyn std::error::Error>> {
+    let topic = log.topics.get(index).ok_or("missing indexed address")?;
+
+    Ok(to_checksum(&Address::from(*topic), None))
+}
+
+fn token_string(token: &Token) -> String {
+    token.clone().into_string().unwrap_or_default()
+}
+
+fn token_uint(token: &Token) -> String {
+    token.clone().into_uint().unwrap_or_default().to_string()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::fhe_account_handler::get_keys::get_keys;
+    use crate::fhe_account_handler::get_keys::tests::create_users;
+    use crate::fhe_account_handler::user::{decoded_user_balance, User};
+    use crate::fhe_node::fhe_assets::encode_balances;
+    use crate::fhe_node::fhe_attestation::tests::{attest_debit, attest_rotation};
+    use crate::fhe_node::fhe_execution::compute_fhe_tx_hash;
+    use crate::fhe_node::fhe_oracle::OracleError;
+    use crate::fhe_node::fhe_params::{encode_ciphertext, encode_pk};
+    use ethers::signers::LocalWallet;
+    use fhe_traits::FheEncrypter;
+
+    // an Oracle without accounts under the parameters of `fhe_oracle` that
+    // holds the proofs of `txs`, like the node they were sent to
+    pub fn fresh_node(fhe_oracle: &Oracle, txs: &[&Tx]) -> Oracle {
+        let mut node = Oracle::from_parameters(fhe_oracle.parameters.clone());
+        for tx in txs.iter() {
+            node.hold_proof(&tx.tx_proof).unwrap();
+        }
+        node
+    }
+
+    pub fn deposit_log(block_number: u64, user: &User, fhe_oracle: &Oracle) -> OracleLog {
+        OracleLog {
+            block_number,
+            log_index: 0,
+            event: OracleEvent::Deposit {
+                from: user.address.clone(),
+                amount: "0".to_string(),
+                fhe_pk: encode_pk(&fhe_oracle.parameters, &user.fhe_pk),
+                fhe_balance_init: encode_ciphertext(&fhe_oracle.parameters, &user.fhe_balance),
+            },
+        }
+    }
+
+    // the log `send_fhe_tx` would emit for `tx` mined in `block_number`
+    pub fn send_log(block_number: u64, tx: &Tx, fhe_oracle: &Oracle) -> OracleLog {
+        relayed_log(block_number, tx, &tx.sender, fhe_oracle)
+    }
+
+    // the same, with `relayer` calling the contract
+    pub fn relayed_log(
+        block_number: u64,
+        tx: &Tx,
+        relayer: &str,
+        fhe_oracle: &Oracle,
+    ) -> OracleLog {
+        let (tx_sender, tx_receiver) = tx.serialize_ct_tx_string(&fhe_oracle.parameters);
+        let fhe_tx_hash = compute_fhe_tx_hash(
+            &relayer.parse().unwrap(),
+            &tx_sender,
+            &tx_receiver,
+            block_number,
+        );
+
+        OracleLog {
+            block_number,
+            log_index: 0,
+            event: OracleEvent::Send {
+                from: relayer.to_string(),
+                to: tx.receiver.clone(),
+                fhe_tx_hash: format!("{:?}", fhe_tx_hash),
+                fhe_tx_sender: tx_sender,
+                fhe_tx_receiver: tx_receiver,
+                fhe_proof: tx.encoded_proof(&fhe_oracle.parameters),
+                nonce: tx.nonce,
+                fhe_signature: tx.encoded_signature(),
+            },
+        }
+    }
+
+    // the log `withdraw_ETH_request` would emit for `withdrawal` mined in
+    // `block_number`
+    pub fn withdraw_log(
+        block_number: u64,
+        withdrawal: &Withdrawal,
+        fhe_oracle: &Oracle,
+    ) -> OracleLog {
+        let fhe_tx_hash = withdrawal
+            .onchain_hash(&fhe_oracle.parameters, block_number)
+            .unwrap();
+
+        OracleLog {
+            block_number,
+            log_index: 0,
+            event: OracleEvent::WithdrawRequest {
+                to: withdrawal.sender.clone(),
+                amount: withdrawal.value.to_string(),
+                fhe_tx_hash: format!("{:?}", fhe_tx_hash),
+                fhe_tx_sender: encode_ciphertext(&fhe_oracle.parameters, &withdrawal.tx_sender),
+                fhe_proof: withdrawal.proof.clone(),
+            },
+        }
+    }
+
+    // the log `rotate_fhe_key` would emit for `rotation` mined in
+    // `block_number`
+    pub fn rotate_log(block_number: u64, rotation: &KeyRotation, fhe_oracle: &Oracle) -> OracleLog {
+        let fhe_tx_hash = rotation
+            .onchain_hash(&fhe_oracle.parameters, block_number)
+            .unwrap();
+
+        OracleLog {
+            block_number,
+            log_index: 0,
+            event: OracleEvent::RotateKey {
+                from: rotation.sender.clone(),
+                fhe_tx_hash: format!("{:?}", fhe_tx_hash),
+                fhe_pk: encode_pk(&fhe_oracle.parameters, &rotation.fhe_pk),
+                fhe_tx_sender: encode_ciphertext(&fhe_oracle.parameters, &rotation.tx_sender),
+                fhe_balance: encode_ciphertext(&fhe_oracle.parameters, &rotation.fhe_balance),
+                fhe_proof: rotation.encoded_proof(),
+                nonce: rotation.nonce,
+                fhe_signature: rotation.encoded_signature(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_replay_deposits_and_send() {
+        let (fhe_oracle, alice, bob, ..) = create_users(100, 50);
+
+        let tx = alice
+            .create_tx(fhe_oracle.users[&bob.address].clone(), &fhe_oracle, 10)
+            .unwrap();
+
+        // out of order on purpose, the replay sorts by chain position
+        let logs = vec![
+            send_log(3, &tx, &fhe_oracle),
+            deposit_log(2, &bob, &fhe_oracle),
+            deposit_log(1, &alice, &fhe_oracle),
+        ];
+
+        // a replay that was not given the proof cannot open the transfer
+        assert!(replay_logs(fresh_node(&fhe_oracle, &[]), logs.clone()).is_err());
+
+        let (replayed, skipped) = replay_logs(fresh_node(&fhe_oracle, &[&tx]), logs).unwrap();
+        assert_eq!(skipped, vec![]);
+
+        let alice = User {
+            fhe_balance: replayed
+                .return_user_fhe_balance(alice.address.clone())
+                .unwrap(),
+            ..alice
+        };
+        let bob = User {
+            fhe_balance: replayed
+                .return_user_fhe_balance(bob.address.clone())
+                .unwrap(),
+            ..bob
+        };
+
+        assert_eq!(decoded_user_balance(&alice, &replayed.parameters), 90);
+        assert_eq!(decoded_user_balance(&bob, &replayed.parameters), 60);
+    }
+
+    #[test]
+    fn test_replay_matches_live_node() {
+        let (fhe_oracle, alice, bob, ..) = create_users(100, 50);
+        let transfer = |value: u64| {
+            alice
+                .create_tx(fhe_oracle.users[&bob.address].clone(), &fhe_oracle, value)
+                .unwrap()
+        };
+        let (tx, unsent_tx) = (transfer(10), transfer(5));
+        let withdrawal = alice.create_withdrawal(&fhe_oracle, 0, 30).unwrap();
+
+        let logs = vec![
+            deposit_log(1, &alice, &fhe_oracle),
+            deposit_log(1, &bob, &fhe_oracle),
+            send_log(2, &tx, &fhe_oracle),
+            send_log(3, &unsent_tx, &fhe_oracle),
+            withdraw_log(4, &withdrawal, &fhe_oracle),
+        ];
+        // the live node was never sent the proof of the second transfer
+        let mut live = fresh_node(&fhe_oracle, &[&tx]);
+        let mut live_skipped = apply_logs(&mut live, &logs[..3]);
+        attest_debit(&mut live, &alice, &withdrawal.tx_sender);
+        live_skipped.extend(apply_logs(&mut live, &logs[3..]));
+        assert_eq!(live.refused_logs.iter().collect::<Vec<_>>(), vec![&(3, 0)]);
+
+        // given what the node was sent, a replay ends where the node did
+        let mut node = fresh_node(&fhe_oracle, &[&tx]);
+        node.attested_debits = live.attested_debits.clone();
+        node.refused_logs = live.refused_logs.clone();
+        let (replayed, skipped) = replay_logs(node.clone(), logs.clone()).unwrap();
+
+        let positions = |skipped: &[SkippedLog]| {
+            skipped
+                .iter()
+                .map(|log| (log.block_number, log.log_index))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(positions(&skipped), positions(&live_skipped));
+        assert_eq!(replayed.seen_txs, live.seen_txs);
+        assert_eq!(replayed.current_block, live.current_block);
+        for user in [&alice, &bob] {
+            assert_eq!(
+                user.user_balance(&replayed).unwrap(),
+                user.user_balance(&live).unwrap()
+            );
+        }
+        assert_eq!(alice.user_balance(&replayed).unwrap(), 60);
+        assert_eq!(bob.user_balance(&replayed).unwrap(), 60);
+
+        // one that does not know the node refused the transfer fails loudly
+        node.refused_logs.clear();
+        assert!(replay_logs(node, logs).is_err());
+    }
+
+    #[test]
+    fn test_replay_repeated_deposit_adds() {
+        let (fhe_oracle, alice, bob, ..) = create_users(100, 50);
+        let top_up = User {
+            fhe_balance: alice
+                .fhe_pk
+                .try_encrypt(
+                    &encode_balances(&fhe_oracle.parameters, &[5]).unwrap(),
+                    &mut rand::thread_rng(),
+                )
+                .unwrap(),
+            ..alice.clone()
+        };
+        let other_key = User {
+            fhe_pk: bob.fhe_pk.clone(),
+            ..top_up.clone()
+        };
+
+        let logs = vec![
+            deposit_log(1, &alice, &fhe_oracle),
+            deposit_log(2, &top_up, &fhe_oracle),
+            deposit_log(3, &other_key, &fhe_oracle),
+        ];
+        let (replayed, skipped) = replay_logs(fresh_node(&fhe_oracle, &[]), logs).unwrap();
+
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].block_number, 3);
+        assert_eq!(alice.user_balance(&replayed).unwrap(), 105);
+        assert_eq!(
+            replayed.return_user_pk(alice.address.clone()).unwrap(),
+            alice.fhe_pk
+        );
+    }
+
+    #[test]
+    fn test_logs_json_round_trip() {
+        let (fhe_oracle, alice, bob, ..) = create_users(100, 50);
+        let logs = vec![
+            deposit_log(1, &alice, &fhe_oracle),
+            deposit_log(2, &bob, &fhe_oracle),
+        ];
+
+        let path = std::env::temp_dir().join(format!("fhe_replay_{}.json", std::process::id()));
+        std::fs::write(&path, serde_json::to_vec(&logs).unwrap()).unwrap();
+
+        assert_eq!(load_logs_json(&path).unwrap(), logs);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_reorg_replaces_orphaned_send() {
+        let (fhe_oracle, alice, bob, ..) = create_users(100, 50);
+
+        let logs = vec![
+            deposit_log(1, &alice, &fhe_oracle),
+            deposit_log(1, &bob, &fhe_oracle),
+        ];
+        let (mut replayed, _) = replay_logs(fresh_node(&fhe_oracle, &[]), logs).unwrap();
+
+        let mut transfer = |block_number: u64, value: u64| {
+            let tx = alice
+                .create_tx(fhe_oracle.users[&bob.address].clone(), &fhe_oracle, value)
+                .unwrap();
+            replayed.hold_proof(&tx.tx_proof).unwrap();
+            send_log(block_number, &tx, &fhe_oracle)
+        };
+        let sends_30 = transfer(2, 30);
+        let sends_5 = transfer(2, 5);
+
+        // block 2 sends 30, then a reorg replaces it with a block 2 sending 5
+        apply_log(&mut replayed, &sends_30).unwrap();
+        assert_eq!(alice.user_balance(&replayed).unwrap(), 70);
+
+        assert_eq!(reorg_to(&mut replayed, 1, vec![sends_5]).unwrap(), vec![]);
+        assert_eq!(alice.user_balance(&replayed).unwrap(), 95);
+        assert_eq!(bob.user_balance(&replayed).unwrap(), 55);
+    }
+
+    #[test]
+    fn test_send_hash_must_match_contract() {
+        let (fhe_oracle, alice, bob, ..) = create_users(100, 50);
+        let mut replayed = fhe_oracle.clone();
+
+        let tx = alice
+            .create_tx(fhe_oracle.users[&bob.address].clone(), &fhe_oracle, 10)
+            .unwrap();
+        replayed.hold_proof(&tx.tx_proof).unwrap();
+        let log = send_log(4, &tx, &fhe_oracle);
+
+        // the same event claimed for another block hashes differently
+        let moved = OracleLog {
+            block_number: 5,
+            ..log.clone()
+        };
+        assert!(apply_log(&mut replayed, &moved).is_err());
+
+        apply_log(&mut replayed, &log).unwrap();
+        assert_eq!(
+            replayed.seen_txs.keys().copied().collect::<Vec<_>>(),
+            vec![tx.onchain_hash(&fhe_oracle.parameters, 4).unwrap()]
+        );
+        assert!(apply_log(&mut replayed, &log).is_err());
+        assert_eq!(alice.user_balance(&replayed).unwrap(), 90);
+    }
+
+    #[test]
+    fn test_replay_withdrawal() {
+        let (mut fhe_oracle, alice, ..) = create_users(100, 50);
+        let withdrawal = alice.create_withdrawal(&fhe_oracle, 0, 30).unwrap();
+
+        let logs = vec![
+            deposit_log(1, &alice, &fhe_oracle),
+            withdraw_log(2, &withdrawal, &fhe_oracle),
+        ];
+        // nothing is paid out for a request that was never attested, and a
+        // replay without the attestation cannot tell whether the node did
+        let mut live = fresh_node(&fhe_oracle, &[]);
+        assert_eq!(apply_logs(&mut live, &logs).len(), 1);
+        assert_eq!(alice.user_balance(&live).unwrap(), 100);
+        assert!(live.refused_logs.contains(&(2, 0)));
+        assert!(replay_logs(fresh_node(&fhe_oracle, &[]), logs.clone()).is_err());
+
+        attest_debit(&mut fhe_oracle, &alice, &withdrawal.tx_sender);
+        let mut node = fresh_node(&fhe_oracle, &[]);
+        node.attested_debits = fhe_oracle.attested_debits.clone();
+        let (replayed, skipped) = replay_logs(node, logs.clone()).unwrap();
+        assert_eq!(skipped, vec![]);
+        assert_eq!(alice.user_balance(&replayed).unwrap(), 70);
+
+        // a request claiming more than its debit opens to is refused
+        let mut inflated = logs;
+        if let OracleEvent::WithdrawRequest { amount, .. } = &mut inflated[1].event {
+            *amount = "60".to_string();
+        }
+        let (replayed, skipped) = replay_logs(fresh_node(&fhe_oracle, &[]), inflated).unwrap();
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].block_number, 2);
+        assert_eq!(alice.user_balance(&replayed).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_replay_skips_bad_logs() {
+        let (fhe_oracle, alice, bob, ..) = create_users(100, 50);
+        let transfer = |value: u64| {
+            alice
+                .create_tx(fhe_oracle.users[&bob.address].clone(), &fhe_oracle, value)
+                .unwrap()
+        };
+        let txs = [transfer(30), transfer(10), transfer(5)];
+
+        let mut bad = send_log(2, &txs[0], &fhe_oracle);
+        if let OracleEvent::Send { fhe_proof, .. } = &mut bad.event {
+            *fhe_proof = "garbage".to_string();
+        }
+        let good = OracleLog {
+            log_index: 1,
+            ..send_log(2, &txs[1], &fhe_oracle)
+        };
+        let later = send_log(3, &txs[2], &fhe_oracle);
+
+        let logs = vec![
+            deposit_log(1, &alice, &fhe_oracle),
+            deposit_log(1, &bob, &fhe_oracle),
+            bad,
+            good,
+            later,
+        ];
+        let node = fresh_node(&fhe_oracle, &txs.iter().collect::<Vec<_>>());
+        let (replayed, skipped) = replay_logs(node, logs).unwrap();
+
+        assert_eq!(skipped.len(), 1);
+        assert_eq!((skipped[0].block_number, skipped[0].log_index), (2, 0));
+        assert_eq!(alice.user_balance(&replayed).unwrap(), 85);
+        assert_eq!(bob.user_balance(&replayed).unwrap(), 65);
+    }
+
+    #[test]
+    fn test_replay_key_rotation() {
+        let (mut fhe_oracle, alice, ..) = create_users(100, 50);
+        let (rotated, rotation, _) = alice
+            .rotate_key(&fhe_oracle, &mut rand::thread_rng())
+            .unwrap();
+        attest_rotation(&mut fhe_oracle, &alice, &rotation);
+
+        let logs = vec![
+            deposit_log(1, &alice, &fhe_oracle),
+            rotate_log(2, &rotation, &fhe_oracle),
+        ];
+        // the balances in the proof never reach the chain, a replay that was
+        // not given it cannot check the rotation
+        match &logs[1].event {
+            OracleEvent::RotateKey { fhe_proof, .. } => assert!(!fhe_proof.contains("balances")),
+            _ => unreachable!(),
+        }
+        let mut node = fresh_node(&fhe_oracle, &[]);
+        node.attested_debits = fhe_oracle.attested_debits.clone();
+        assert!(replay_logs(node.clone(), logs.clone()).is_err());
+
+        node.hold_proof(&rotation.proof).unwrap();
+        let (replayed, skipped) = replay_logs(node, logs).unwrap();
+        assert_eq!(skipped, vec![]);
+        assert_eq!(
+            replayed.return_user_pk(alice.address.clone()).unwrap(),
+            rotated.fhe_pk
+        );
+        assert_eq!(rotated.user_balance(&replayed).unwrap(), 100);
+
+        // rolling the block back restores the old key with its balance
+        let mut rolled_back = replayed.clone();
+        rolled_back.rollback_to(1).unwrap();
+        assert_eq!(alice.user_balance(&rolled_back).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_relayed_send_is_signed_by_sender() {
+        let (fhe_oracle, alice, bob, owner) = create_users(100, 50);
+        let mut replayed = fhe_oracle.clone();
+        replayed.require_signatures = true;
+
+        let wallet =
+            |name: &str| -> LocalWallet { get_keys(name).unwrap().private_key.parse().unwrap() };
+        let tx = alice
+            .create_tx(fhe_oracle.users[&bob.address].clone(), &fhe_oracle, 10)
+            .unwrap();
+        replayed.hold_proof(&tx.tx_proof).unwrap();
+
+        assert!(apply_log(&mut replayed, &send_log(2, &tx, &fhe_oracle)).is_err());
+
+        // bob cannot sign alice's debit
+        let domain = replayed.signing_domain;
+        let forged = tx.clone().sign(0, &domain, &wallet("bob")).unwrap();
+        assert!(matches!(
+            forged.execute_tx(&mut replayed.clone()),
+            Err(OracleError::InvalidSignature(_))
+        ));
+
+        // a signature for another chain or over another proof is not alice's
+        let elsewhere = SigningDomain {
+            chain_id: 1,
+            ..domain
+        };
+        let replayed_elsewhere = tx.clone().sign(0, &elsewhere, &wallet("user")).unwrap();
+        assert!(matches!(
+            replayed_elsewhere.execute_tx(&mut replayed.clone()),
+            Err(OracleError::InvalidSignature(_))
+        ));
+        let signed = tx.clone().sign(0, &domain, &wallet("user")).unwrap();
+        let reproven = Tx {
+            tx_proof: "{}".to_string(),
+            ..signed.clone()
+        };
+        assert!(matches!(
+            reproven.execute_tx(&mut replayed.clone()),
+            Err(OracleError::InvalidSignature(_))
+        ));
+
+        // the owner relays alice's tx, alice is debited
+        apply_log(
+            &mut replayed,
+            &relayed_log(3, &signed, &owner.address, &fhe_oracle),
+        )
+        .unwrap();
+        assert_eq!(alice.user_balance(&replayed).unwrap(), 90);
+        assert_eq!(bob.user_balance(&replayed).unwrap(), 60);
+        assert_eq!(
+            replayed.return_user(alice.address.clone()).unwrap().nonce,
+            1
+        );
+
+        // relaying it again in a later block hashes differently, the nonce
+        // is what stops it
+        assert!(apply_log(
+            &mut replayed,
+            &relayed_log(4, &signed, &owner.address, &fhe_oracle),
+        )
+        .is_err());
+        assert_eq!(alice.user_balance(&replayed).unwrap(), 90);
+    }
+}
@@ -0,0 +1,194 @@
+use rand::rngs::OsRng;
+use rand::{CryptoRng, Error, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+const SEED_ENV: &str = "FHE_RNG_SEED";
+
+/// Randomness for key generation and encryption. `Os` draws from the
+/// operating system and is what the node uses unless told otherwise.
+/// `Seeded` replays one ChaCha20 stream per seed, so keys, ciphertexts and
+/// proofs come out byte for byte the same on every run. It is meant for
+/// tests and golden vectors, never for real funds.
+#[derive(Clone, Debug)]
+pub enum FheRng {
+    Os(OsRng),
+    Seeded(ChaCha20Rng),
+}
+
+impl Default for FheRng {
+    fn default() -> FheRng {
+        FheRng::Os(OsRng)
+    }
+}
+
+impl FheRng {
+    pub fn seeded(seed: u64) -> FheRng {
+        FheRng::Seeded(ChaCha20Rng::seed_from_u64(seed))
+    }
+
+    /// Seeds from `FHE_RNG_SEED` when it is set, OS randomness otherwise.
+    pub fn from_env() -> Result<FheRng, Box<dyn std::error::Error>> {
+        match std::env::var(SEED_ENV) {
+            Ok(seed) => Ok(FheRng::seeded(seed.parse()?)),
+            Err(_) => Ok(FheRng::default()),
+        }
+    }
+
+    pub fn is_seeded(&self) -> bool {
+        matches!(self, FheRng::Seeded(_))
+    }
+}
+
+impl RngCore for FheRng {
+    fn next_u32(&mut self) -> u32 {
+        match self {
+            FheRng::Os(rng) => rng.next_u32(),
+            FheRng::Seeded(rng) => rng.next_u32(),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        match self {
+            FheRng::Os(rng) => rng.next_u64(),
+            FheRng::Seeded(rng) => rng.next_u64(),
+        }
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        match self {
+            FheRng::Os(rng) => rng.fill_bytes(dest),
+            FheRng::Seeded(rng) => rng.fill_bytes(dest),
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        match self {
+            FheRng::Os(rng) => rng.try_fill_bytes(dest),
+            FheRng::Seeded(rng) => rng.try_fill_bytes(dest),
+        }
+    }
+}
+
+impl CryptoRng for FheRng {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhe_account_handler::keystore::random_secret_coeffs;
+    use crate::fhe_account_handler::user::{create_user_with_rng, User};
+    use crate::fhe_node::fhe_oracle::{Oracle, OracleUser};
+    use crate::fhe_node::fhe_params::{encode_ciphertext, encode_pk, ParameterSet};
+    use ethers::utils::{hex, keccak256};
+
+    // everything a seed determines, serialized the way it goes on-chain
+    fn golden_vectors(seed: u64) -> Vec<String> {
+        let parameters = ParameterSet::preset("bfv-simd-2048")
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut fhe_oracle = Oracle::from_parameters(parameters.clone());
+        let mut rng = FheRng::seeded(seed);
+
+        let users: Vec<User> = ["0xg0", "0xg1"]
+            .iter()
+            .map(|address| {
+                let user = create_user_with_rng(
+                    address.to_string(),
+                    parameters.clone(),
+                    None,
+                    &[100],
+                    &mut rng,
+                )
+                .unwrap();
+                fhe_oracle
+                    .add_user(user.address.clone(), OracleUser::from_user(user.clone()))
+                    .unwrap();
+                user
+            })
+            .collect();
+
+        let tx = users[0]
+            .create_tx_with_rng(
+                fhe_oracle.users[&users[1].address].clone(),
+                &fhe_oracle,
+                10,
+                &mut rng,
+            )
+            .unwrap();
+        let (tx_sender, tx_receiver) = tx.serialize_ct_tx_string(&parameters);
+
+        let mut vectors: Vec<String> = users
+            .iter()
+            .flat_map(|user| {
+                vec![
+                    encode_pk(&parameters, &user.fhe_pk),
+                    encode_ciphertext(&parameters, &user.fhe_balance),
+                ]
+            })
+            .collect();
+        vectors.extend([tx_sender, tx_receiver, tx.tx_proof]);
+
+        vectors
+    }
+
+    #[test]
+    fn test_seeded_rng_reproduces_keys_and_ciphertexts() {
+        assert_eq!(golden_vectors(7), golden_vectors(7));
+        assert_ne!(golden_vectors(7), golden_vectors(8));
+    }
+
+    // pinned so a change to the seeding or to the sampling shows up even
+    // when every run agrees with itself
+    #[test]
+    fn test_seeded_rng_matches_golden_vectors() {
+        let mut stream = [0u8; 16];
+        FheRng::seeded(7).fill_bytes(&mut stream);
+        assert_eq!(hex::encode(stream), "19454a27b752f905909507d6160ddc88");
+
+        let parameters = ParameterSet::preset("bfv-simd-2048")
+            .unwrap()
+            .build()
+            .unwrap();
+        let coeffs = random_secret_coeffs(&parameters, &mut FheRng::seeded(7));
+        assert_eq!(coeffs[..12], [-1, 0, -1, 0, 0, 1, 1, 0, 1, 0, 1, 1]);
+        let bytes: Vec<u8> = coeffs.iter().map(|coeff| *coeff as i8 as u8).collect();
+        assert_eq!(
+            hex::encode(keccak256(bytes)),
+            "6014a66c9d03bd4046903335468d8f9acbec1030fdfc05202b78426456daa726"
+        );
+    }
+
+    // keccak of every key and ciphertext seed 7 produces, recorded from a
+    // known good build. Run with FHE_BLESS_GOLDEN=1 to record them again
+    // after a deliberate change to the seeding or the sampling
+    const GOLDEN_FILE: &str = "src/fhe_node/testdata/rng_seed7.txt";
+
+    #[test]
+    fn test_seeded_keys_and_ciphertexts_match_golden_file() {
+        let hashes: Vec<String> = golden_vectors(7)
+            .iter()
+            .map(|vector| hex::encode(keccak256(vector.as_bytes())))
+            .collect();
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join(GOLDEN_FILE);
+
+        if std::env::var("FHE_BLESS_GOLDEN").is_ok() {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, hashes.join("\n") + "\n").unwrap();
+        }
+        let golden = std::fs::read_to_string(&path).unwrap_or_else(|_| {
+            panic!(
+                "{} is missing, record it with FHE_BLESS_GOLDEN=1",
+                GOLDEN_FILE
+            )
+        });
+
+        // one public key and one balance per user, then the transfer
+        assert_eq!(golden.lines().collect::<Vec<&str>>(), hashes);
+    }
+
+    #[test]
+    fn test_os_rng_is_the_default() {
+        assert!(!FheRng::default().is_seeded());
+        assert!(FheRng::seeded(1).is_seeded());
+    }
+}
@@ -0,0 +1,362 @@
+use crate::fhe_account_handler::user::User;
+use crate::fhe_account_handler::wallet::{Wallet, WalletAccount};
+use crate::fhe_node::fhe_execution::Tx;
+use crate::fhe_node::fhe_mempool::Mempool;
+use crate::fhe_node::fhe_oracle::{Oracle, OracleError};
+use crate::fhe_node::fhe_replay::{
+    apply_log, decode_send_log, lacks_off_chain_material, OracleEvent, OracleLog, SkippedLog,
+};
+use crate::fhe_node::fhe_rng::FheRng;
+use crate::fhe_node::fhe_store::OracleStore;
+use ethers::signers::LocalWallet;
+use fhe::bfv::{BfvParameters, Ciphertext, PublicKey};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// The Oracle shared between request handlers.
+///
+/// The `RwLock` only guards the map itself and is held for single lookups
+/// and writes. Anything that reads an account, does FHE work on it and writes
+/// it back runs under that account's lock via `with_accounts`, so updates to
+/// unrelated accounts proceed in parallel and updates to the same account
+/// are never lost.
+pub struct SharedOracle {
+    oracle: RwLock<Oracle>,
+    accounts: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+    saving: Mutex<()>,
+    syncing: Mutex<()>,
+}
+
+impl SharedOracle {
+    pub fn new(oracle: Oracle) -> SharedOracle {
+        SharedOracle {
+            oracle: RwLock::new(oracle),
+            accounts: Mutex::new(HashMap::new()),
+            saving: Mutex::new(()),
+            syncing: Mutex::new(()),
+        }
+    }
+
+    pub fn parameters(&self) -> Arc<BfvParameters> {
+        self.read().parameters.clone()
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<Oracle> {
+        self.oracle.read().unwrap()
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<Oracle> {
+        self.oracle.write().unwrap()
+    }
+
+    /// Runs `f` while holding the locks of every account in `addresses`.
+    /// Locks are taken in address order so two callers locking overlapping
+    /// sets cannot deadlock. `f` must not call `with_accounts` again.
+    pub fn with_accounts<R>(&self, addresses: &[&str], f: impl FnOnce(&SharedOracle) -> R) -> R {
+        let mut addresses = addresses.to_vec();
+        addresses.sort();
+        addresses.dedup();
+
+        let locks: Vec<Arc<Mutex<()>>> = {
+            let mut accounts = self.accounts.lock().unwrap();
+            addresses
+                .iter()
+                .map(|address| accounts.entry(address.to_string()).or_default().clone())
+                .collect()
+        };
+
+        // the account locks guard no data, a panic while holding one leaves
+        // nothing half-written behind it
+        let _guards: Vec<MutexGuard<()>> = locks
+            .iter()
+            .map(|lock| lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner()))
+            .collect();
+
+        f(self)
+    }
+
+    /// Same as `Tx::execute_tx`, but the homomorphic arithmetic runs outside
+    /// the map lock.
+    pub fn execute_tx(&self, tx: &Tx) -> Result<(), OracleError> {
+        self.with_accounts(&[&tx.sender, &tx.receiver], |shared| {
+            let prepared = tx.prepare(&shared.read())?;
+            tx.commit(&mut shared.write(), prepared)
+        })
+    }
+
+    /// Credits a deposit to `address` under its account lock, so it cannot
+    /// land between the read and the write of a transfer.
+    pub fn deposit(
+        &self,
+        address: String,
+        fhe_pk: PublicKey,
+        fhe_balance: Ciphertext,
+    ) -> Result<(), OracleError> {
+        self.with_accounts(&[&address], |shared| {
+            shared.write().deposit(address.clone(), fhe_pk, fhe_balance)
+        })
+    }
+
+    /// Applies chain logs the way `apply_logs` does, each transfer through
+    /// `execute_tx`. Logs of blocks already applied are left alone and calls
+    /// are serialized, so two handlers syncing at once never apply a log
+    /// twice. Returns the logs that were left out, recording those refused
+    /// for want of their proof or attestation.
+    pub fn apply_logs(&self, logs: &[OracleLog]) -> Vec<SkippedLog> {
+        let _syncing = self.syncing.lock().unwrap();
+
+        let current_block = self.read().current_block;
+        let mut logs: Vec<&OracleLog> = logs
+            .iter()
+            .filter(|log| log.block_number > current_block)
+            .collect();
+        logs.sort_by_key(|log| (log.block_number, log.log_index));
+
+        let mut skipped: Vec<SkippedLog> = Vec::new();
+        for log in logs {
+            let applied = match &log.event {
+                OracleEvent::Send { .. } => {
+                    let tx = {
+                        let mut oracle = self.write();
+                        if log.block_number != oracle.current_block {
+                            oracle.begin_block(log.block_number);
+                        }
+                        decode_send_log(&oracle.parameters, &oracle.signing_domain, log)
+                    };
+                    tx.and_then(|tx| Ok(self.execute_tx(&tx)?))
+                }
+                // deposits, withdrawals and rotations change one balance,
+                // which a transfer holding its lock may be about to write
+                event => self.with_accounts(&[event.account()], |shared| {
+                    apply_log(&mut shared.write(), log)
+                }),
+            };
+            if let Err(error) = applied {
+                if lacks_off_chain_material(error.as_ref()) {
+                    self.write()
+                        .refused_logs
+                        .insert((log.block_number, log.log_index));
+                }
+                skipped.push(SkippedLog::new(log, error));
+            }
+        }
+
+        skipped
+    }
+
+    /// Writes a snapshot to `store`. Saves are serialized so two handlers
+    /// never write the same temp file at once.
+    pub fn save(&self, store: &OracleStore) -> Result<(), Box<dyn std::error::Error>> {
+        let _saving = self.saving.lock().unwrap();
+
+        store.save(&self.read())
+    }
+}
+
+/// Everything the Rocket handlers share, handed to them through `.manage`.
+pub struct NodeState {
+    pub oracle: SharedOracle,
+    pub store: OracleStore,
+    /// The accounts this node holds keys for.
+    pub wallet: Mutex<Wallet>,
+    /// Address of the last depositor, whose wallet account `send_funds`,
+    /// `withdraw_funds` and `get_balance` act on behalf of.
+    pub session: Mutex<Option<String>>,
+    pub mempool: Mutex<Mempool>,
+    /// Where the handlers draw keys and encryption randomness from.
+    pub rng: Mutex<FheRng>,
+}
+
+impl NodeState {
+    pub fn new(oracle: Oracle, store: OracleStore, wallet: Wallet, rng: FheRng) -> NodeState {
+        NodeState {
+            oracle: SharedOracle::new(oracle),
+            store,
+            wallet: Mutex::new(wallet),
+            session: Mutex::new(None),
+            mempool: Mutex::new(Mempool::new()),
+            rng: Mutex::new(rng),
+        }
+    }
+
+    pub fn session_account(&self) -> Option<WalletAccount> {
+        let address = self.session.lock().unwrap().clone()?;
+
+        self.wallet.lock().unwrap().get(&address).cloned()
+    }
+
+    pub fn session_user(&self) -> Option<User> {
+        self.session_account().map(|account| account.user)
+    }
+
+    /// The wallet key that signs for `address`.
+    pub fn signer_for(&self, address: &str) -> Result<LocalWallet, OracleError> {
+        self.wallet
+            .lock()
+            .unwrap()
+            .get(address)
+            .map(|account| account.signer.clone())
+            .ok_or(OracleError::UnknownUser(address.to_string()))
+    }
+
+    /// Makes `address` the session account. It has to be in the wallet.
+    pub fn set_session(&self, address: &str) -> Result<(), OracleError> {
+        if self.wallet.lock().unwrap().get(address).is_none() {
+            return Err(OracleError::UnknownUser(address.to_string()));
+        }
+        *self.session.lock().unwrap() = Some(address.to_string());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhe_account_handler::get_keys::tests::create_users;
+    use crate::fhe_account_handler::user::create_user;
+    use crate::fhe_node::fhe_assets::encode_balances;
+    use crate::fhe_node::fhe_oracle::OracleUser;
+    use crate::fhe_node::fhe_replay::tests::{deposit_log, fresh_node, send_log};
+    use fhe_traits::FheEncrypter;
+    use rand::thread_rng;
+    use std::thread;
+
+    const ACCOUNTS: usize = 6;
+    const ROUNDS: u64 = 10;
+
+    #[test]
+    fn test_concurrent_deposits_and_sends() {
+        let mut fhe_oracle = Oracle::new();
+        let users: Vec<User> = (0..ACCOUNTS)
+            .map(|i| {
+                let user = create_user(
+                    format!("0xs{}", i),
+                    fhe_oracle.parameters.clone(),
+                    None,
+                    Some(100),
+                )
+                .unwrap();
+                fhe_oracle
+                    .add_user(user.address.clone(), OracleUser::from_user(user.clone()))
+                    .unwrap();
+                user
+            })
+            .collect();
+
+        let shared = Arc::new(SharedOracle::new(fhe_oracle));
+
+        // every account sends one unit to the next one and deposits one unit
+        // to itself each round, so both kinds of update race on every account
+        let handles: Vec<_> = (0..ACCOUNTS)
+            .map(|i| {
+                let shared = shared.clone();
+                let sender = users[i].clone();
+                let receiver = users[(i + 1) % ACCOUNTS].address.clone();
+
+                thread::spawn(move || {
+                    for _ in 0..ROUNDS {
+                        let receiver_user =
+                            shared.read().return_user(receiver.clone()).unwrap().clone();
+                        let tx = sender.create_tx(receiver_user, &shared.read(), 1).unwrap();
+                        shared.execute_tx(&tx).unwrap();
+
+                        let plaintext = encode_balances(&shared.parameters(), &[1]).unwrap();
+                        let fhe_balance = sender
+                            .fhe_pk
+                            .try_encrypt(&plaintext, &mut thread_rng())
+                            .unwrap();
+                        shared
+                            .deposit(sender.address.clone(), sender.fhe_pk.clone(), fhe_balance)
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let oracle = shared.read();
+        for user in users.iter() {
+            assert_eq!(user.user_balance(&oracle).unwrap(), 100 + ROUNDS);
+        }
+    }
+
+    #[test]
+    fn test_shared_execute_rejects_self_transfer() {
+        let (fhe_oracle, alice, bob, ..) = create_users(100, 50);
+        let bob_user = fhe_oracle.users[&bob.address].clone();
+        let mut tx = alice.create_tx(bob_user, &fhe_oracle, 10).unwrap();
+        tx.receiver = alice.address.clone();
+
+        let shared = SharedOracle::new(fhe_oracle);
+        assert_eq!(
+            shared.execute_tx(&tx).err(),
+            Some(OracleError::SelfTransfer(alice.address.clone()))
+        );
+        assert_eq!(alice.user_balance(&shared.read()).unwrap(), 100);
+        assert!(!shared.read().has_seen_tx(&tx.tx_hash));
+    }
+
+    #[test]
+    fn test_logs_apply_once() {
+        let (fhe_oracle, alice, bob, ..) = create_users(100, 50);
+        let tx = alice
+            .create_tx(fhe_oracle.users[&bob.address].clone(), &fhe_oracle, 10)
+            .unwrap();
+        let logs = vec![
+            deposit_log(1, &alice, &fhe_oracle),
+            deposit_log(1, &bob, &fhe_oracle),
+            send_log(2, &tx, &fhe_oracle),
+        ];
+
+        let shared = Arc::new(SharedOracle::new(fresh_node(&fhe_oracle, &[&tx])));
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let shared = shared.clone();
+                let logs = logs.clone();
+                thread::spawn(move || shared.apply_logs(&logs))
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), vec![]);
+        }
+
+        let oracle = shared.read();
+        assert_eq!(oracle.current_block, 2);
+        assert_eq!(alice.user_balance(&oracle).unwrap(), 90);
+        assert_eq!(bob.user_balance(&oracle).unwrap(), 60);
+    }
+
+    #[test]
+    fn test_overlapping_account_locks() {
+        let shared = Arc::new(SharedOracle::new(Oracle::new()));
+        let counter = Arc::new(Mutex::new(0u64));
+
+        // opposite lock orders would deadlock without the sorting
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let shared = shared.clone();
+                let counter = counter.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        let accounts = if i % 2 == 0 {
+                            ["0xa", "0xb"]
+                        } else {
+                            ["0xb", "0xa"]
+                        };
+                        shared.with_accounts(&accounts, |_| *counter.lock().unwrap() += 1);
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(*counter.lock().unwrap(), 400);
+    }
+}
@@ -0,0 +1,376 @@
+use crate::fhe_node::fhe_execution::TxHash;
+use crate::fhe_node::fhe_oracle::{Oracle, OracleUser};
+use ethers::types::H256;
+use ethers::utils::hex;
+use fhe::bfv::{BfvParameters, Ciphertext, PublicKey};
+use fhe_traits::{DeserializeParametrized, Serialize as FheSerialize};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+pub const DEFAULT_STORE_PATH: &str = "oracle_state";
+const PARAMETERS_FILE: &str = "parameters.bin";
+const USERS_FILE: &str = "users.json";
+
+#[derive(Deserialize, Serialize, Clone)]
+struct StoredUser {
+    address: String,
+    fhe_pk: String,
+    fhe_balance: String,
+    #[serde(default)]
+    nonce: u64,
+    #[serde(default)]
+    updates: u64,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+struct StoredTx {
+    tx_hash: TxHash,
+    block: u64,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+struct StoredProof {
+    commitment: H256,
+    proof: String,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+struct StoredAttestation {
+    debit: H256,
+    balance: H256,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+struct StoredOracle {
+    users: Vec<StoredUser>,
+    #[serde(default)]
+    current_block: u64,
+    #[serde(default)]
+    seen_txs: Vec<StoredTx>,
+    #[serde(default)]
+    auditor_pk: Option<String>,
+    #[serde(default)]
+    held_proofs: Vec<StoredProof>,
+    #[serde(default)]
+    attested_debits: Vec<StoredAttestation>,
+    #[serde(default)]
+    locked_accounts: Vec<String>,
+    #[serde(default)]
+    refused_logs: Vec<(u64, u64)>,
+}
+
+/// On-disk home of the Oracle state: the BFV parameters in `parameters.bin`
+/// and every `OracleUser` along with the executed tx hashes, the proofs held
+/// off-chain, the balance attestations and the logs refused for want of
+/// them in `users.json`, both written atomically.
+#[derive(Clone)]
+pub struct OracleStore {
+    pub path: PathBuf,
+}
+
+impl OracleStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> OracleStore {
+        OracleStore {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.join(PARAMETERS_FILE).exists() && self.path.join(USERS_FILE).exists()
+    }
+
+    pub fn save(&self, oracle: &Oracle) -> Result<(), Box<dyn std::error::Error>> {
+        fs::create_dir_all(&self.path)?;
+
+        let mut users: Vec<StoredUser> = oracle
+            .users
+            .values()
+            .map(|user| StoredUser {
+                address: user.address.clone(),
+                fhe_pk: hex::encode(user.fhe_pk.to_bytes()),
+                fhe_balance: hex::encode(user.fhe_balance.to_bytes()),
+                nonce: user.nonce,
+                updates: user.updates,
+            })
+            .collect();
+        // keep the file stable between saves of the same state
+        users.sort_by(|a, b| a.address.cmp(&b.address));
+
+        let mut seen_txs: Vec<StoredTx> = oracle
+            .seen_txs
+            .iter()
+            .map(|(tx_hash, block)| StoredTx {
+                tx_hash: *tx_hash,
+                block: *block,
+            })
+            .collect();
+        seen_txs.sort_by_key(|tx| (tx.block, tx.tx_hash));
+
+        let mut held_proofs: Vec<StoredProof> = oracle
+            .held_proofs
+            .iter()
+            .map(|(commitment, proof)| StoredProof {
+                commitment: *commitment,
+                proof: proof.clone(),
+            })
+            .collect();
+        held_proofs.sort_by_key(|proof| proof.commitment);
+
+        let mut attested_debits: Vec<StoredAttestation> = oracle
+            .attested_debits
+            .iter()
+            .map(|(debit, balance)| StoredAttestation {
+                debit: *debit,
+                balance: *balance,
+            })
+            .collect();
+        attested_debits.sort_by_key(|attestation| attestation.debit);
+
+        let mut locked_accounts: Vec<String> = oracle.locked_accounts.iter().cloned().collect();
+        locked_accounts.sort();
+
+        let users_json = serde_json::to_vec_pretty(&StoredOracle {
+            users,
+            current_block: oracle.current_block,
+            seen_txs,
+            auditor_pk: oracle
+                .auditor_pk
+                .as_ref()
+                .map(|auditor_pk| hex::encode(auditor_pk.to_bytes())),
+            held_proofs,
+            attested_debits,
+            locked_accounts,
+            refused_logs: oracle.refused_logs.iter().copied().collect(),
+        })?;
+
+        write_atomic(
+            &self.path.join(PARAMETERS_FILE),
+            &oracle.parameters.to_bytes(),
+        )?;
+        write_atomic(&self.path.join(USERS_FILE), &users_json)?;
+
+        Ok(())
+    }
+
+    /// Loads the stored users, refusing to do so if they were written under
+    /// parameters other than `parameters`.
+    pub fn load(
+        &self,
+        parameters: Arc<BfvParameters>,
+    ) -> Result<Oracle, Box<dyn std::error::Error>> {
+        let stored_parameters = fs::read(self.path.join(PARAMETERS_FILE))?;
+        if stored_parameters != parameters.to_bytes() {
+            return Err(format!(
+                "BFV parameters stored in {} do not match the node parameters",
+                self.path.display()
+            )
+            .into());
+        }
+
+        let users_json = fs::read(self.path.join(USERS_FILE))?;
+        let stored: StoredOracle = serde_json::from_slice(&users_json)?;
+
+        let mut oracle = Oracle::from_parameters(parameters.clone());
+        for user in stored.users {
+            let fhe_pk = PublicKey::from_bytes(&hex::decode(&user.fhe_pk)?, &parameters)?;
+            let fhe_balance =
+                Ciphertext::from_bytes(&hex::decode(&user.fhe_balance)?, &parameters)?;
+
+            oracle.add_user(
+                user.address.clone(),
+                OracleUser {
+                    nonce: user.nonce,
+                    updates: user.updates,
+                    ..OracleUser::new(user.address, fhe_pk, fhe_balance)
+                },
+            )?;
+        }
+
+        // the journal is not stored, so nothing before the snapshot can be
+        // rolled back
+        oracle.current_block = stored.current_block;
+        oracle.journal.clear();
+        oracle.journal_floor = stored.current_block;
+        oracle.seen_txs = stored
+            .seen_txs
+            .into_iter()
+            .map(|tx| (tx.tx_hash, tx.block))
+            .collect();
+        oracle.held_proofs = stored
+            .held_proofs
+            .into_iter()
+            .map(|proof| (proof.commitment, proof.proof))
+            .collect();
+        oracle.attested_debits = stored
+            .attested_debits
+            .into_iter()
+            .map(|attestation| (attestation.debit, attestation.balance))
+            .collect();
+        oracle.locked_accounts = stored.locked_accounts.into_iter().collect();
+        oracle.refused_logs = stored.refused_logs.into_iter().collect();
+        if let Some(auditor_pk) = stored.auditor_pk {
+            oracle.register_auditor(PublicKey::from_bytes(
+                &hex::decode(auditor_pk)?,
+                &parameters,
+            )?);
+        }
+
+        Ok(oracle)
+    }
+
+    /// Reloads the Oracle if the store has been written before, otherwise
+    /// starts an empty one with `parameters`.
+    pub fn load_or_new(
+        &self,
+        parameters: Arc<BfvParameters>,
+    ) -> Result<Oracle, Box<dyn std::error::Error>> {
+        if !self.exists() {
+            return Ok(Oracle::from_parameters(parameters));
+        }
+
+        self.load(parameters)
+    }
+}
+
+// write to a sibling temp file, fsync it and rename it over the target so a
+// crash mid-write never leaves a truncated file behind. The temp name is unique
+// per process and call, so concurrent saves never write into each other's file
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+
+    write_atomic_with(path, bytes, options)
+}
+
+/// Same as `write_atomic`, but the file is readable by its owner only, from
+/// the moment the temp file is created.
+pub(crate) fn write_private(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    write_atomic_with(path, bytes, options)
+}
+
+fn write_atomic_with(path: &Path, bytes: &[u8], options: OpenOptions) -> std::io::Result<()> {
+    static WRITES: AtomicU64 = AtomicU64::new(0);
+
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let tmp_path = path.with_file_name(format!(
+        ".{}.{}.{}.tmp",
+        file_name,
+        std::process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let written = options.open(&tmp_path).and_then(|mut file| {
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)
+    });
+    if let Err(err) = written {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err);
+    }
+
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhe_account_handler::get_keys::tests::create_users;
+    use crate::fhe_account_handler::user::{decoded_user_balance, User};
+    use fhe::bfv::BfvParametersBuilder;
+
+    fn temp_store(name: &str) -> OracleStore {
+        let path = std::env::temp_dir().join(format!("fhe_store_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        OracleStore::new(path)
+    }
+
+    #[test]
+    fn test_store_round_trip() {
+        let store = temp_store("round_trip");
+        let (mut fhe_oracle, alice, bob, ..) = create_users(100, 50);
+        fhe_oracle.begin_block(3);
+        fhe_oracle
+            .mark_tx_seen(&TxHash::from_low_u64_be(1))
+            .unwrap();
+        let tx = alice
+            .create_tx(fhe_oracle.users[&bob.address].clone(), &fhe_oracle, 10)
+            .unwrap();
+        let commitment = fhe_oracle.hold_proof(&tx.tx_proof).unwrap();
+        fhe_oracle.users.get_mut(&bob.address).unwrap().updates = 7;
+        fhe_oracle
+            .attested_debits
+            .insert(H256::from_low_u64_be(4), H256::from_low_u64_be(5));
+        fhe_oracle.locked_accounts.insert(bob.address.clone());
+        fhe_oracle.refused_logs.insert((2, 1));
+
+        store.save(&fhe_oracle).unwrap();
+        assert!(store.exists());
+
+        let reloaded = store.load(fhe_oracle.parameters.clone()).unwrap();
+        assert_eq!(reloaded.users.len(), fhe_oracle.users.len());
+
+        let alice_reloaded = User {
+            fhe_balance: reloaded
+                .return_user_fhe_balance(alice.address.clone())
+                .unwrap(),
+            ..alice.clone()
+        };
+        assert_eq!(
+            decoded_user_balance(&alice_reloaded, &reloaded.parameters),
+            100
+        );
+        assert_eq!(
+            reloaded.return_user_pk(bob.address.clone()).unwrap(),
+            fhe_oracle.return_user_pk(bob.address.clone()).unwrap()
+        );
+        assert_eq!(reloaded.current_block, 3);
+        assert!(reloaded.has_seen_tx(&TxHash::from_low_u64_be(1)));
+        assert_eq!(reloaded.held_proofs[&commitment], tx.tx_proof);
+        assert_eq!(reloaded.users[&bob.address].updates, 7);
+        assert_eq!(reloaded.attested_debits, fhe_oracle.attested_debits);
+        assert!(reloaded.locked_accounts.contains(&bob.address));
+        assert_eq!(reloaded.refused_logs, fhe_oracle.refused_logs);
+
+        fs::remove_dir_all(&store.path).unwrap();
+    }
+
+    #[test]
+    fn test_store_rejects_other_parameters() {
+        let store = temp_store("parameters");
+        let (fhe_oracle, ..) = create_users(100, 50);
+
+        store.save(&fhe_oracle).unwrap();
+
+        let other_parameters = Arc::new(
+            BfvParametersBuilder::new()
+                .set_degree(2048)
+                .set_moduli(&[0x3fffffff000001])
+                .set_plaintext_modulus(1 << 11)
+                .build()
+                .unwrap(),
+        );
+        assert!(store.load(other_parameters).is_err());
+
+        fs::remove_dir_all(&store.path).unwrap();
+    }
+}
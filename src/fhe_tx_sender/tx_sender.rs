@@ -1,27 +1,28 @@
 use ethers::abi::{decode, encode, Token};
-use fhe::bfv::{Ciphertext, Plaintext, PublicKey, SecretKey};
+use ethers::signers::LocalWallet;
+use fhe::bfv::{BfvParameters, Ciphertext, Plaintext, PublicKey, SecretKey};
 use fhe_traits::Serialize;
 use std::process::Output;
 use std::str;
 
+use crate::fhe_account_handler::key_provider::private_key_hex;
+use crate::fhe_node::fhe_params::{encode_ciphertext, encode_pk};
 use crate::fhe_tx_sender::contract_deployer::get_deployed_address;
 
 use tokio::io::AsyncReadExt;
 use tokio::process::Command;
 
 pub async fn deposit_tokens_tx_sender(
+    parameters: &BfvParameters,
     pk: &PublicKey,
-    priv_key: &String,
+    signer: &LocalWallet,
     fhe_balance: &Ciphertext,
     amount: &String,
 ) -> Result<Option<String>, Box<dyn std::error::Error>> {
     let deployed_address = get_deployed_address();
 
-    let pk_bytes = pk.to_bytes();
-    let pk_encoded = Token::Bytes(pk_bytes).to_string();
-
-    let fhe_balance_bytes = fhe_balance.to_bytes();
-    let fhe_balance_encoded = Token::Bytes(fhe_balance_bytes).to_string();
+    let pk_encoded = encode_pk(parameters, pk);
+    let fhe_balance_encoded = encode_ciphertext(parameters, fhe_balance);
 
     let output = Command::new("cast")
         .arg("send")
@@ -30,7 +31,7 @@ pub async fn deposit_tokens_tx_sender(
         .arg(pk_encoded)
         .arg(fhe_balance_encoded)
         .arg("--private-key")
-        .arg(priv_key)
+        .arg(private_key_hex(signer))
         .arg("--value")
         .arg(amount)
         .output()
@@ -46,23 +47,36 @@ pub async fn deposit_tokens_tx_sender(
 }
 
 pub async fn send_fhe_tx(
+    receiver: &str,
     fhe_tx_sender: &str,
     fhe_tx_receiver: &str,
     fhe_proof: &str,
-    priv_key: &String,
+    nonce: u64,
+    fhe_signature: &str,
+    signer: &LocalWallet,
     amount: &String,
 ) -> Result<Option<String>, Box<dyn std::error::Error>> {
     let deployed_address = get_deployed_address();
 
+    // cast wants empty bytes spelled out
+    let fhe_signature = if fhe_signature.is_empty() {
+        "0x"
+    } else {
+        fhe_signature
+    };
+
     let output = Command::new("cast")
         .arg("send")
         .arg(deployed_address)
-        .arg("send_fhe_tx(string,string,string)")
+        .arg("send_fhe_tx(address,string,string,string,uint256,bytes)")
+        .arg(receiver)
         .arg(fhe_tx_sender)
         .arg(fhe_tx_receiver)
         .arg(fhe_proof)
+        .arg(nonce.to_string())
+        .arg(fhe_signature)
         .arg("--private-key")
-        .arg(priv_key)
+        .arg(private_key_hex(signer))
         .arg("--value")
         .arg(amount)
         .output()
@@ -77,34 +91,72 @@ pub async fn send_fhe_tx(
     }
 }
 
-pub async fn withdraw_ETH_request(
-    amount: &String,
-    fhe_sk: &SecretKey,
-    fhe_new_pk: &PublicKey,
+pub async fn rotate_fhe_key(
+    parameters: &BfvParameters,
+    fhe_pk: &PublicKey,
+    fhe_tx_sender: &Ciphertext,
     fhe_balance: &Ciphertext,
-    priv_key: &String,
+    fhe_proof: &String,
+    nonce: u64,
+    fhe_signature: &str,
+    signer: &LocalWallet,
+    fee: &String,
 ) -> Result<Option<String>, Box<dyn std::error::Error>> {
     let deployed_address = get_deployed_address();
 
-    let sk_bytes = fhe_sk.to_bytes().to_vec();
-    let sk_encoded = Token::Bytes(sk_bytes).to_string();
+    // cast wants empty bytes spelled out
+    let fhe_signature = if fhe_signature.is_empty() {
+        "0x"
+    } else {
+        fhe_signature
+    };
 
-    let pk_bytes = fhe_new_pk.to_bytes();
-    let pk_new_encoded = Token::Bytes(pk_bytes).to_string();
+    let output = Command::new("cast")
+        .arg("send")
+        .arg(deployed_address)
+        .arg("rotate_fhe_key(string,string,string,string,uint256,bytes)")
+        .arg(encode_pk(parameters, fhe_pk))
+        .arg(encode_ciphertext(parameters, fhe_tx_sender))
+        .arg(encode_ciphertext(parameters, fhe_balance))
+        .arg(fhe_proof)
+        .arg(nonce.to_string())
+        .arg(fhe_signature)
+        .arg("--private-key")
+        .arg(private_key_hex(signer))
+        .arg("--value")
+        .arg(fee)
+        .output()
+        .await?;
 
-    let fhe_balance_bytes = fhe_balance.to_bytes();
-    let fhe_balance_encoded = Token::Bytes(fhe_balance_bytes).to_string();
+    match get_tx_hash(output).await {
+        Ok(tx_hash) => Ok(tx_hash),
+        Err(error) => {
+            eprintln!("Failed to execute script: {}", error);
+            Ok(None)
+        }
+    }
+}
+
+pub async fn withdraw_ETH_request(
+    parameters: &BfvParameters,
+    amount: &String,
+    fhe_tx_sender: &Ciphertext,
+    fhe_proof: &String,
+    signer: &LocalWallet,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let deployed_address = get_deployed_address();
+
+    let fhe_tx_sender_encoded = encode_ciphertext(parameters, fhe_tx_sender);
 
     let output = Command::new("cast")
         .arg("send")
         .arg(deployed_address)
-        .arg("withdraw_ETH_request(uint256,string,string,string)")
+        .arg("withdraw_ETH_request(uint256,string,string)")
         .arg(amount)
-        .arg(sk_encoded)
-        .arg(pk_new_encoded)
-        .arg(fhe_balance_encoded)
+        .arg(fhe_tx_sender_encoded)
+        .arg(fhe_proof)
         .arg("--private-key")
-        .arg(priv_key)
+        .arg(private_key_hex(signer))
         .arg("--value")
         .arg(amount)
         .output()
@@ -122,7 +174,7 @@ pub async fn withdraw_ETH_request(
 pub async fn withdraw_ETH_confirm(
     amount: &String,
     recv_address: &String,
-    priv_key: &String,
+    signer: &LocalWallet,
 ) -> Result<Option<String>, Box<dyn std::error::Error>> {
     let output = Command::new("cast")
         .arg("send")
@@ -130,7 +182,7 @@ pub async fn withdraw_ETH_confirm(
         .arg("--value")
         .arg(amount)
         .arg("--private-key")
-        .arg(priv_key)
+        .arg(private_key_hex(signer))
         .output()
         .await?;
 
@@ -178,11 +230,12 @@ mod tests {
     use rand::thread_rng;
     use std::sync::Arc;
 
+    use crate::fhe_account_handler::key_provider::KeySource;
     use crate::fhe_node::fhe_oracle::OracleUser;
     use crate::fhe_node::{fhe_execution::Tx, fhe_oracle::Oracle};
     use crate::{
         fhe_account_handler::{
-            get_keys::{get_keys, tests::create_users},
+            get_keys::tests::create_users,
             user::{create_user, User},
         },
         fhe_tx_sender::contract_deployer::FEE,
@@ -193,12 +246,18 @@ mod tests {
 
         let (fhe_oracle, alice, bob, owner) = create_users(100, 50);
 
-        let priv_key = get_keys("owner").unwrap().private_key.to_string();
+        let signer = KeySource::anvil(0).load().unwrap();
         let pk = owner.fhe_pk.clone();
         let fhe_balance = owner.fhe_balance.clone();
 
-        let tx_hash =
-            deposit_tokens_tx_sender(&pk, &priv_key, &fhe_balance, &FEE.to_string()).await;
+        let tx_hash = deposit_tokens_tx_sender(
+            &fhe_oracle.parameters,
+            &pk,
+            &signer,
+            &fhe_balance,
+            &FEE.to_string(),
+        )
+        .await;
 
         assert!(tx_hash.is_ok());
     }
@@ -209,23 +268,34 @@ mod tests {
 
         let (fhe_oracle, alice, bob, owner) = create_users(100, 50);
 
-        let priv_key = get_keys("owner").unwrap().private_key.to_string();
+        let signer = KeySource::anvil(0).load().unwrap();
         let pk = owner.fhe_pk.clone();
         let fhe_balance = owner.fhe_balance.clone();
 
-        let tx_hash =
-            deposit_tokens_tx_sender(&pk, &priv_key, &fhe_balance, &FEE.to_string()).await;
+        let tx_hash = deposit_tokens_tx_sender(
+            &fhe_oracle.parameters,
+            &pk,
+            &signer,
+            &fhe_balance,
+            &FEE.to_string(),
+        )
+        .await;
         let bob_as_oracleuser: OracleUser = OracleUser::from_user(bob.clone());
 
-        let tx = alice.create_tx(bob_as_oracleuser.clone(), &fhe_oracle, 10);
+        let tx = alice
+            .create_tx(bob_as_oracleuser.clone(), &fhe_oracle, 10)
+            .unwrap();
 
-        let (tx_sender, tx_receiver) = tx.serialize_ct_tx_string();
+        let (tx_sender, tx_receiver) = tx.serialize_ct_tx_string(&fhe_oracle.parameters);
 
         let tx_hash = send_fhe_tx(
+            &bob.address,
             &tx_sender,
             &tx_receiver,
-            &tx.tx_proof,
-            &priv_key,
+            &tx.encoded_proof(&fhe_oracle.parameters),
+            tx.nonce,
+            &tx.encoded_signature(),
+            &signer,
             &FEE.to_string(),
         )
         .await;
@@ -238,18 +308,30 @@ mod tests {
     async fn test_withdraw_ETH_request() {
         let rng = thread_rng();
 
-        let (fhe_oracle, alice, bob, owner) = create_users(100, 50);
+        let (fhe_oracle, alice, _, owner) = create_users(100, 50);
 
-        let priv_key = get_keys("owner").unwrap().private_key.to_string();
-        let fhe_sk = owner.fhe_sk.clone();
+        let signer = KeySource::anvil(0).load().unwrap();
         let fhe_pk = alice.fhe_pk.clone();
         let fhe_balance = owner.fhe_balance.clone();
+        let withdrawal = alice.create_withdrawal(&fhe_oracle, 0, 10).unwrap();
 
-        let tx_hash =
-            deposit_tokens_tx_sender(&fhe_pk, &priv_key, &fhe_balance, &FEE.to_string()).await;
+        let tx_hash = deposit_tokens_tx_sender(
+            &fhe_oracle.parameters,
+            &fhe_pk,
+            &signer,
+            &fhe_balance,
+            &FEE.to_string(),
+        )
+        .await;
 
-        let tx_hash =
-            withdraw_ETH_request(&FEE.to_string(), &fhe_sk, &fhe_pk, &fhe_balance, &priv_key).await;
+        let tx_hash = withdraw_ETH_request(
+            &fhe_oracle.parameters,
+            &FEE.to_string(),
+            &withdrawal.tx_sender,
+            &withdrawal.proof,
+            &signer,
+        )
+        .await;
         println!("{:?}", tx_hash);
 
         assert!(tx_hash.is_ok());
@@ -1,22 +1,45 @@
 #![allow(unused_imports, unused_variables, dead_code)]
-#![feature(proc_macro_hygiene, decl_macro)]
+#![feature(proc_macro_hygiene, decl_macro, test)]
 
 #[macro_use]
 extern crate rocket;
-
-use ethers::utils::hex;
-use fhe::bfv::{BfvParametersBuilder, Ciphertext, Encoding, Plaintext, PublicKey, SecretKey};
-use fhe_account_handler::get_keys;
+#[cfg(test)]
+extern crate test as libtest;
+
+use ethers::signers::{LocalWallet, Signer};
+use ethers::utils::{hex, to_checksum};
+use fhe::bfv::{
+    BfvParameters, BfvParametersBuilder, Ciphertext, Encoding, Plaintext, PublicKey, SecretKey,
+};
+use fhe_account_handler::derivation::derive_keys;
+use fhe_account_handler::key_provider::{KeySource, SIGNER_ENV};
 use fhe_account_handler::user::*;
+use fhe_account_handler::wallet::{Wallet, DEFAULT_WALLET_DIR};
+use fhe_node::fhe_assets::ETH;
+use fhe_node::fhe_attestation::{
+    challenge_debit, challenge_emptying_debit, DEFAULT_CHALLENGE_SIZE,
+};
+use fhe_node::fhe_execution::{SigningDomain, Tx, ANVIL_CHAIN_ID};
+use fhe_node::fhe_mempool::{MempoolEntry, TxStatus};
+use fhe_node::fhe_merkle::MerkleTree;
 use fhe_node::fhe_oracle::Oracle;
+use fhe_node::fhe_oracle::OracleError;
 use fhe_node::fhe_oracle::OracleUser;
+use fhe_node::fhe_params::{decode_ciphertext, decode_pk, ParameterError, ParameterSet};
+use fhe_node::fhe_proof::OpeningBackend;
+use fhe_node::fhe_replay::{catch_up, confirm_logs, fetch_logs, SkippedLog};
+use fhe_node::fhe_rng::FheRng;
+use fhe_node::fhe_state::NodeState;
+use fhe_node::fhe_store::{OracleStore, DEFAULT_STORE_PATH};
 use fhe_traits::Serialize;
 use fhe_traits::*;
+use fhe_tx_sender::contract_deployer::{get_deployed_address, DEPLOYED_BLOCK, FEE, URL};
 use fhe_tx_sender::tx_sender;
-use rand::rngs::OsRng;
+use rocket::State;
 use rocket_contrib::json::Json;
 use rocket_cors::{AllowedOrigins, CorsOptions};
 use rocket_helper::structs::*;
+use std::env;
 use std::sync::Arc;
 
 mod rocket_helper {
@@ -24,13 +47,30 @@ mod rocket_helper {
 }
 
 mod fhe_account_handler {
+    pub(crate) mod auditor;
+    pub(crate) mod derivation;
     pub(crate) mod get_keys;
+    pub(crate) mod key_provider;
+    pub(crate) mod keystore;
+    pub(crate) mod notes;
     pub(crate) mod user;
+    pub(crate) mod wallet;
 }
 
 mod fhe_node {
+    pub(crate) mod fhe_assets;
+    pub(crate) mod fhe_attestation;
+    pub(crate) mod fhe_block;
     pub(crate) mod fhe_execution;
+    pub(crate) mod fhe_mempool;
+    pub(crate) mod fhe_merkle;
     pub(crate) mod fhe_oracle;
+    pub(crate) mod fhe_params;
+    pub(crate) mod fhe_proof;
+    pub(crate) mod fhe_replay;
+    pub(crate) mod fhe_rng;
+    pub(crate) mod fhe_state;
+    pub(crate) mod fhe_store;
 }
 
 mod fhe_tx_sender {
@@ -38,8 +78,170 @@ mod fhe_tx_sender {
     pub(crate) mod tx_sender;
 }
 
-static mut ORACLE: Option<Oracle> = None;
-static mut USER: Option<User> = None;
+fn node_parameters() -> Result<Arc<BfvParameters>, Box<dyn std::error::Error>> {
+    ParameterSet::from_env()?.build()
+}
+
+// the fhe_pk and fhe_balance fields are optional, but when a client sends them
+// they must have been produced under the node's parameters
+fn check_api_material(
+    data: &OracleUserApi,
+    parameters: &Arc<BfvParameters>,
+) -> Result<(), ParameterError> {
+    if !data.fhe_pk.is_empty() {
+        decode_pk(parameters, &data.fhe_pk)?;
+    }
+    if !data.fhe_balance.is_empty() {
+        decode_ciphertext(parameters, &data.fhe_balance)?;
+    }
+
+    Ok(())
+}
+
+fn error_response(error: impl ToString) -> Json<ResponseApi> {
+    Json(ResponseApi {
+        res: error.to_string(),
+        res_status: "Error".to_string(),
+    })
+}
+
+// answers the request with an "Error" response instead of failing it
+macro_rules! try_response {
+    ($result:expr) => {
+        match $result {
+            Ok(value) => value,
+            Err(error) => return Ok(error_response(error)),
+        }
+    };
+}
+
+fn print_skipped(skipped: &[SkippedLog]) {
+    for log in skipped.iter() {
+        println!(
+            "skipped log {} of block {}: {}",
+            log.log_index, log.block_number, log.reason
+        );
+    }
+}
+
+// applies the logs emitted since the last block the Oracle has seen, saves
+// the result and submits the queued sends whose turn came. Transfers go
+// through `SharedOracle::execute_tx`
+fn sync_chain(state: &NodeState) -> Result<Vec<SkippedLog>, Box<dyn std::error::Error>> {
+    let from_block = state.oracle.read().current_block + 1;
+    let mut logs = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(fetch_logs(URL, get_deployed_address(), from_block))?;
+    logs.sort_by_key(|log| (log.block_number, log.log_index));
+
+    let mut skipped = state.oracle.apply_logs(&logs);
+    skipped.extend(confirm_logs(
+        &mut state.mempool.lock().unwrap(),
+        &state.oracle.read(),
+        &logs,
+    ));
+    print_skipped(&skipped);
+    state
+        .wallet
+        .lock()
+        .unwrap()
+        .refresh_balances(&state.oracle.read());
+    state.oracle.save(&state.store)?;
+
+    for (entry, submitted) in drain_mempool(state) {
+        if let Err(error) = submitted {
+            println!(
+                "dropped nonce {} of {} and the ones after it: {}",
+                entry.nonce, entry.tx.sender, error
+            );
+        }
+    }
+
+    Ok(skipped)
+}
+
+// has the wallet account behind `address` answer a balance challenge for
+// `debit` and stores the attestation, the executing Oracle refuses the debit
+// without it. Only the account's own key can answer, a wrong answer locks
+// the account out. A debit that `empties` has to take the whole balance
+fn attest_debit(
+    state: &NodeState,
+    address: &str,
+    debit: &Ciphertext,
+    empties: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let user = state
+        .wallet
+        .lock()
+        .unwrap()
+        .get(address)
+        .map(|account| account.user.clone())
+        .ok_or(OracleError::UnknownUser(address.to_string()))?;
+
+    let (challenge, pending) = {
+        let oracle = state.oracle.read();
+        let mut rng = state.rng.lock().unwrap();
+        if empties {
+            challenge_emptying_debit(&oracle, address, debit, DEFAULT_CHALLENGE_SIZE, &mut *rng)?
+        } else {
+            challenge_debit(&oracle, address, debit, DEFAULT_CHALLENGE_SIZE, &mut *rng)?
+        }
+    };
+    let response = user.answer_challenge(&challenge)?;
+    let checked = pending.check(&mut state.oracle.write(), &response);
+    // a lockout has to survive a restart as much as an attestation
+    state.oracle.save(&state.store)?;
+    checked?;
+
+    Ok(())
+}
+
+fn submit_tx(
+    state: &NodeState,
+    parameters: &BfvParameters,
+    tx: &Tx,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let signer = state.signer_for(&tx.sender)?;
+    // against the balance as it is once the txs ahead of this one executed
+    attest_debit(state, &tx.sender, &tx.tx_sender, false)?;
+    let (tx_sender, tx_receiver) = tx.serialize_ct_tx_string(parameters);
+    let tx_hash = tokio::runtime::Runtime::new()?.block_on(tx_sender::send_fhe_tx(
+        &tx.receiver,
+        &tx_sender,
+        &tx_receiver,
+        &tx.encoded_proof(parameters),
+        tx.nonce,
+        &tx.encoded_signature(),
+        &signer,
+        &FEE.to_string(),
+    ))?;
+
+    tx_hash.ok_or_else(|| "send_fhe_tx was not mined".into())
+}
+
+// submits the head of every sender's queue that has not gone on-chain yet.
+// A head the chain refuses is evicted together with the nonces queued
+// after it, so they can be signed again
+fn drain_mempool(state: &NodeState) -> Vec<(MempoolEntry, Result<String, String>)> {
+    let heads = state.mempool.lock().unwrap().submit_heads();
+    let parameters = state.oracle.parameters();
+
+    heads
+        .into_iter()
+        .map(|entry| {
+            let submitted =
+                submit_tx(state, &parameters, &entry.tx).map_err(|error| error.to_string());
+            if submitted.is_err() {
+                state
+                    .mempool
+                    .lock()
+                    .unwrap()
+                    .evict(&entry.tx.sender, entry.nonce);
+            }
+            (entry, submitted)
+        })
+        .collect()
+}
 
 #[get("/")]
 fn index() -> Json<MessageApi> {
@@ -51,220 +253,389 @@ fn index() -> Json<MessageApi> {
 #[post("/deposit_funds", format = "json", data = "<data>")]
 fn deposit_funds(
     data: Json<OracleUserApi>,
+    state: State<NodeState>,
 ) -> Result<Json<ResponseApi>, Box<dyn std::error::Error>> {
-    unsafe {
-        if ORACLE.is_none() {
-            ORACLE = Some(Oracle::new());
-        }
-        let mut toAdd = 0;
-        if USER.is_some(){
-            toAdd = USER.as_ref().unwrap().user_balance(&ORACLE.as_mut().unwrap());
+    let parameters = state.oracle.parameters();
+    if let Err(error) = check_api_material(&data, &parameters) {
+        return Ok(error_response(error));
+    }
+    let amount = try_response!(data.amount.parse::<u64>());
+
+    // the deposit is encrypted under the depositor's own key, derived from
+    // its wallet so losing the keystore does not lose the funds. The Oracle
+    // adds it to whatever the address already holds
+    let signer = try_response!(state.signer_for(&data.sender_address));
+    let user: User = try_response!(state.wallet.lock().unwrap().create_user(
+        &signer,
+        data.fhe_account,
+        &parameters,
+        &[amount],
+        &mut *state.rng.lock().unwrap(),
+    ));
+
+    // the Oracle would skip a deposit under another key and the ETH with it
+    if let Ok(fhe_pk) = state.oracle.read().return_user_pk(user.address.clone()) {
+        if fhe_pk != user.fhe_pk {
+            return Ok(error_response(OracleError::KeyMismatch(user.address)));
         }
-        
-        let user: User = create_user(
-            data.sender_address.clone(),
-            ORACLE.as_ref().unwrap().parameters.clone(),
-            Some(data.der_key.clone()),
-            // TODO make balance add onto itself
-            Some(data.amount.clone().parse::<u64>().unwrap()+toAdd),
-        );
-
-        USER = Some(user.clone());
-
-        let user_as_oracle_user: OracleUser = OracleUser {
-            address: user.address.clone(),
-            fhe_pk: user.fhe_pk.clone(),
-            fhe_balance: user.fhe_balance.clone(),
-        };
-
-        ORACLE
-            .as_mut()
-            .unwrap()
-            .add_user(data.sender_address.clone(), user_as_oracle_user.clone());
-        let priv_key = get_keys::get_keys("user").unwrap().private_key.to_string();
-        let pk = user.fhe_pk.clone();
-        let fhe_balance = user.fhe_balance.clone();
-
-        let result = tokio::runtime::Runtime::new().unwrap().block_on(async {
-            let tx_hash =
-                tx_sender::deposit_tokens_tx_sender(&pk, &priv_key, &fhe_balance, &data.amount)
-                    .await;
-
-            let tx_hash = tx_hash.unwrap();
-            let response: ResponseApi = ResponseApi {
-                res: tx_hash.unwrap(),
-                res_status: "Success".to_string(),
-            };
-            USER = Some(user.clone());
-            Json(response)
-        });
-
-        Ok(result)
     }
+
+    let tx_hash = try_response!(tokio::runtime::Runtime::new().unwrap().block_on(
+        tx_sender::deposit_tokens_tx_sender(
+            &parameters,
+            &user.fhe_pk,
+            &signer,
+            &user.fhe_balance,
+            &data.amount,
+        )
+    ));
+    let tx_hash = match tx_hash {
+        Some(tx_hash) => tx_hash,
+        None => return Ok(error_response("deposit_fETH was not mined")),
+    };
+
+    // nothing is kept before the deposit is on-chain, and the balance only
+    // changes once its log is applied
+    try_response!(state
+        .wallet
+        .lock()
+        .unwrap()
+        .insert(signer, data.fhe_account, user.clone()));
+    try_response!(state.set_session(&user.address));
+    try_response!(sync_chain(&state));
+    state
+        .wallet
+        .lock()
+        .unwrap()
+        .save(&mut *state.rng.lock().unwrap())?;
+
+    Ok(Json(ResponseApi {
+        res: tx_hash,
+        res_status: "Success".to_string(),
+    }))
 }
 
 #[post("/send_funds", format = "json", data = "<data>")]
-fn send_funds(data: Json<OracleUserApi>) -> Result<Json<ResponseApi>, Box<dyn std::error::Error>> {
-    unsafe {
-        //println!("108 data: {:?}", data);
-        if ORACLE.is_none() {
-            return Ok(Json(ResponseApi {
-                res: "Deposit first".to_string(),
-                res_status: "Error".to_string(),
-            }));
+fn send_funds(
+    data: Json<OracleUserApi>,
+    state: State<NodeState>,
+) -> Result<Json<ResponseApi>, Box<dyn std::error::Error>> {
+    //println!("108 data: {:?}", data);
+    // picks up the confirmations of earlier sends and the balance they left
+    try_response!(sync_chain(&state));
+    let account = match state.session_account() {
+        Some(account) => account,
+        None => return Ok(error_response("Deposit first")),
+    };
+    let user = account.user.clone();
+    let parameters = state.oracle.parameters();
+    if let Err(error) = check_api_material(&data, &parameters) {
+        return Ok(error_response(error));
+    }
+    let amount = try_response!(data.amount.parse::<u64>());
+    //println!("115 data: {:?}", data);
+
+    //println!("119 data: {:?}", data);
+    let user_as_oracle_user: OracleUser = OracleUser::new(
+        user.address.clone(),
+        user.fhe_pk.clone(),
+        user.fhe_balance.clone(),
+    );
+    //println!("125 data: {:?}", data);
+
+    let oracle = state.oracle.read().clone();
+    let receiver_fhe_balance =
+        try_response!(oracle.return_user_fhe_balance(data.receiver_address.clone()));
+    let receiver_fhe_pk = try_response!(oracle.return_user_pk(data.receiver_address.clone()));
+
+    //println!("131 data: {:?}", data);
+    let receiver_as_oracle_user: OracleUser = OracleUser::new(
+        data.receiver_address.clone(),
+        receiver_fhe_pk.clone(),
+        receiver_fhe_balance.clone(),
+    );
+
+    //println!("138 data: {:?}", data);
+    let tx = try_response!(user.create_proven_tx(
+        receiver_as_oracle_user.clone(),
+        &oracle,
+        data.asset,
+        amount,
+        &OpeningBackend,
+        &mut *state.rng.lock().unwrap(),
+    ));
+
+    // queue this one under the sender's next nonce. Only the head of the
+    // queue goes on-chain, the rest wait for it so no two debits race on
+    // the same balance
+    let nonce = {
+        let mut mempool = state.mempool.lock().unwrap();
+        let signed_nonce = try_response!(state.oracle.read().return_user(tx.sender.clone())).nonce;
+        mempool.resume_from(&tx.sender, signed_nonce);
+
+        // signed with the wallet key so the tx can be relayed by anyone
+        let nonce = mempool.next_nonce(&tx.sender);
+        let tx = try_response!(tx.sign(nonce, &oracle.signing_domain, &account.signer));
+        try_response!(mempool.insert(nonce, tx.clone()));
+
+        // the opening stays with the node, the chain only sees a commitment
+        // to it, so it has to be on disk before the tx can be mined
+        try_response!(state.oracle.write().hold_proof(&tx.tx_proof));
+        state.oracle.save(&state.store)?;
+        nonce
+    };
+
+    let submitted = drain_mempool(&state)
+        .into_iter()
+        .find(|(entry, _)| entry.tx.sender == tx.sender);
+    let response = match submitted {
+        Some((entry, Ok(tx_hash))) if entry.nonce == nonce => ResponseApi {
+            res: tx_hash,
+            res_status: "Success".to_string(),
+        },
+        Some((entry, Err(error))) if entry.nonce == nonce => return Ok(error_response(error)),
+        Some((entry, Err(error))) => {
+            return Ok(error_response(format!(
+                "nonce {} was dropped with nonce {}: {}",
+                nonce, entry.nonce, error
+            )))
         }
-        //println!("115 data: {:?}", data);
-
-        let user: User = USER.as_ref().unwrap().clone();
-
-        //println!("119 data: {:?}", data);
-        let user_as_oracle_user: OracleUser = OracleUser {
-            address: user.address.clone(),
-            fhe_pk: user.fhe_pk.clone(),
-            fhe_balance: user.fhe_balance.clone(),
-        };
-        //println!("125 data: {:?}", data);
-
-        let oracle = ORACLE.as_mut().unwrap().clone();
-        let receiver_fhe_balance = oracle.return_user_fhe_balance(data.receiver_address.clone());
-        let receiver_fhe_pk = oracle.return_user_pk(data.receiver_address.clone());
-
-        //println!("131 data: {:?}", data);
-        let receiver_as_oracle_user: OracleUser = OracleUser {
-            address: data.receiver_address.clone(),
-            fhe_pk: receiver_fhe_pk.clone(),
-            fhe_balance: receiver_fhe_balance.clone(),
-        };
-
-        //println!("138 data: {:?}", data);
-        let user_pk = get_keys::get_keys("user").unwrap().private_key;
-
-        let tx = user.create_tx(
-            receiver_as_oracle_user.clone(),
-            &ORACLE.as_mut().unwrap().clone(),
-            data.amount.parse::<u64>().unwrap(),
-        );
+        _ => ResponseApi {
+            res: format!("queued at nonce {}", nonce),
+            res_status: "Pending".to_string(),
+        },
+    };
 
-        let (tx_sender, tx_receiver) = tx.serialize_ct_tx_string();
-
-        let result = tokio::runtime::Runtime::new().unwrap().block_on(async {
-            let tx_hash = tx_sender::send_fhe_tx(
-                &tx_sender,
-                &tx_receiver,
-                &"0".to_string().as_str(),
-                &user_pk.to_string(),
-                &data.amount.clone(),
-            )
-            .await;
-            let tx_hash = tx_hash.unwrap();
-            let response: ResponseApi = ResponseApi {
-                res: tx_hash.unwrap(),
-                res_status: "Success".to_string(),
-            };
-            USER = Some(user.clone());
-            Json(response)
-        });
-
-        Ok(result)
-    }
+    Ok(Json(response))
 }
 
 #[post("/withdraw_funds", format = "json", data = "<data>")]
 fn withdraw_funds(
     data: Json<OracleUserApi>,
+    state: State<NodeState>,
 ) -> Result<Json<ResponseApi>, Box<dyn std::error::Error>> {
-    unsafe {
-        if ORACLE.is_none() {
-            return Ok(Json(ResponseApi {
-                res: "Deposit first".to_string(),
-                res_status: "Error".to_string(),
-            }));
-        }
-
-        let user: User = USER.as_ref().unwrap().clone();
-
-        let user_balance = user.user_balance(&ORACLE.as_mut().unwrap());
-
-        let user_new: User = create_user(
-            data.sender_address.clone(),
-            ORACLE.as_ref().unwrap().parameters.clone(),
-            Some(data.der_key.clone()),
-            Some(0),
-        );
+    // the attestation is made against the balance the request will meet
+    try_response!(sync_chain(&state));
+    let account = match state.session_account() {
+        Some(account) => account,
+        None => return Ok(error_response("Deposit first")),
+    };
+    let user = account.user.clone();
+    let amount = try_response!(data.amount.parse::<u64>());
+    let oracle = state.oracle.read().clone();
+
+    // the request carries the debit and its opening, fhe_sk stays here
+    let withdrawal = try_response!(user.create_withdrawal_with_rng(
+        &oracle,
+        ETH,
+        amount,
+        &mut *state.rng.lock().unwrap()
+    ));
+
+    try_response!(attest_debit(
+        &state,
+        &withdrawal.sender,
+        &withdrawal.tx_sender,
+        false
+    ));
+
+    let parameters = oracle.parameters.clone();
+    let synced_from = state.oracle.read().current_block;
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let tx_hash = try_response!(runtime.block_on(tx_sender::withdraw_ETH_request(
+        &parameters,
+        &data.amount.clone(),
+        &withdrawal.tx_sender,
+        &withdrawal.proof,
+        &account.signer,
+    )));
+    let tx_hash = match tx_hash {
+        Some(tx_hash) => tx_hash,
+        None => return Ok(error_response("withdraw_ETH_request was not mined")),
+    };
+
+    // pay out only once the Oracle has debited the request, a request it
+    // refused leaves the balance as it was
+    try_response!(sync_chain(&state));
+    let applied = {
+        let oracle = state.oracle.read();
+        (synced_from + 1..=oracle.current_block).any(|block| {
+            withdrawal
+                .onchain_hash(&parameters, block)
+                .map_or(false, |hash| oracle.has_seen_tx(&hash))
+        })
+    };
+    if !applied {
+        return Ok(error_response(format!(
+            "the Oracle did not apply withdrawal request {}, nothing is paid out",
+            tx_hash
+        )));
+    }
+    try_response!(runtime.block_on(tx_sender::withdraw_ETH_confirm(
+        &data.amount.clone(),
+        &withdrawal.sender,
+        &account.signer,
+    )));
+
+    Ok(Json(ResponseApi {
+        res: tx_hash,
+        res_status: "Success".to_string(),
+    }))
+}
 
-        let user_as_oracle_user: OracleUser = OracleUser {
-            address: user.address.clone(),
-            fhe_pk: user.fhe_pk.clone(),
-            fhe_balance: user.fhe_balance.clone(),
-        };
+#[post("/rotate_key", format = "json", data = "<data>")]
+fn rotate_key(
+    data: Json<OracleUserApi>,
+    state: State<NodeState>,
+) -> Result<Json<ResponseApi>, Box<dyn std::error::Error>> {
+    // the rotation takes the account's next nonce, after the sends that
+    // already went through
+    try_response!(sync_chain(&state));
+    let account = match state.session_account() {
+        Some(account) => account,
+        None => return Ok(error_response("Deposit first")),
+    };
+    let user = account.user;
+    if state.mempool.lock().unwrap().head(&user.address).is_some() {
+        return Ok(error_response(format!(
+            "{} has sends waiting to be confirmed, rotate once they are",
+            user.address
+        )));
+    }
+    let oracle = state.oracle.read().clone();
+
+    // the new key is the next account derived from the wallet, so it can
+    // be recovered like the first one and never comes back to the old one
+    let fhe_account = match account.fhe_account.checked_add(1) {
+        Some(fhe_account) => fhe_account,
+        None => return Ok(error_response("no FHE account left to rotate to")),
+    };
+    let keys = try_response!(derive_keys(
+        &account.signer,
+        &oracle.parameters,
+        fhe_account
+    ));
+    let (rotated, rotation, keystore) =
+        try_response!(user.rotate_key_to(&oracle, &keys.coeffs, &mut *state.rng.lock().unwrap()));
+    let nonce = try_response!(oracle.return_user(user.address.clone())).nonce;
+    let rotation = try_response!(rotation.sign(nonce, &oracle.signing_domain, &account.signer));
+
+    try_response!(attest_debit(
+        &state,
+        &rotation.sender,
+        &rotation.tx_sender,
+        true
+    ));
+
+    // the proof opens the whole balance, only its commitment goes on-chain
+    try_response!(state.oracle.write().hold_proof(&rotation.proof));
+    state.oracle.save(&state.store)?;
+
+    let parameters = oracle.parameters.clone();
+    let tx_hash = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(tx_sender::rotate_fhe_key(
+            &parameters,
+            &rotation.fhe_pk,
+            &rotation.tx_sender,
+            &rotation.fhe_balance,
+            &rotation.encoded_proof(),
+            rotation.nonce,
+            &rotation.encoded_signature(),
+            &account.signer,
+            &data.amount,
+        ))?;
+    let tx_hash = match tx_hash {
+        Some(tx_hash) => tx_hash,
+        None => return Ok(error_response("rotate_fhe_key was not mined")),
+    };
+    // sends signed from here on come after the rotation
+    try_response!(state
+        .mempool
+        .lock()
+        .unwrap()
+        .skip_nonce(&rotation.sender, rotation.nonce));
+
+    // the old keystore is only overwritten once the rotation is on-chain
+    try_response!(keystore.save(&rotated.key_path));
+    {
+        let mut wallet = state.wallet.lock().unwrap();
+        try_response!(wallet.insert(account.signer, fhe_account, rotated));
+        try_response!(wallet.save(&mut *state.rng.lock().unwrap()));
+    }
 
-        let new_user_as_oracle_user: OracleUser = OracleUser {
-            address: user_new.address.clone(),
-            fhe_pk: user_new.fhe_pk.clone(),
-            fhe_balance: user_new.fhe_balance.clone(),
-        };
+    Ok(Json(ResponseApi {
+        res: tx_hash,
+        res_status: "Success".to_string(),
+    }))
+}
 
-        let oracle = ORACLE.as_mut().unwrap().clone();
-        let receiver_fhe_balance = oracle.return_user_fhe_balance(data.receiver_address.clone());
-        let receiver_fhe_pk = oracle.return_user_pk(data.receiver_address.clone());
+#[get("/get_balance")]
+fn get_balance(state: State<NodeState>) -> Result<Json<ResponseApi>, Box<dyn std::error::Error>> {
+    let user: User = match state.session_user() {
+        Some(user) => user,
+        None => return Ok(error_response("Deposit first")),
+    };
 
-        let user_pk = get_keys::get_keys("user").unwrap().private_key;
+    let user_balance = try_response!(user.user_balance(&state.oracle.read())).to_string();
 
-        let tx = user.create_tx(
-            user_as_oracle_user.clone(),
-            &ORACLE.as_mut().unwrap(),
-            user_balance - data.amount.parse::<u64>().unwrap(),
-        );
+    let response: ResponseApi = ResponseApi {
+        res: user_balance,
+        res_status: "Success".to_string(),
+    };
 
-        let (tx_sender, tx_receiver) = tx.serialize_ct_tx_string();
-        let user_fhe_sk = user.fhe_sk.clone();
-        let result = tokio::runtime::Runtime::new().unwrap().block_on(async {
-            let tx_hash = tx_sender::withdraw_ETH_request(
-                &data.amount.clone(),
-                &user.fhe_sk.clone(),
-                &new_user_as_oracle_user.fhe_pk.clone(),
-                &new_user_as_oracle_user.fhe_balance.clone(),
-                &user_pk.to_string(),
-            )
-            .await;
-            let tx_hash = tx_hash.unwrap();
-            let response: ResponseApi = ResponseApi {
-                res: tx_hash.unwrap(),
-                res_status: "Success".to_string(),
-            };
-            let tx_hash = tx_sender::withdraw_ETH_confirm(
-                &data.amount.clone(),
-                &data.sender_address.clone(),
-                &get_keys::get_keys("user").unwrap().private_key.to_string(),
-            )
-            .await;
-            Json(response)
-        });
-
-        Ok(result)
-    }
+    Ok(Json(response))
 }
 
-#[get("/get_balance")]
-fn get_balance() -> Result<Json<ResponseApi>, Box<dyn std::error::Error>> {
-    unsafe {
-        if ORACLE.is_none() {
-            return Ok(Json(ResponseApi {
-                res: "Deposit first".to_string(),
-                res_status: "Error".to_string(),
-            }));
+#[get("/tx_status/<address>/<nonce>")]
+fn tx_status(
+    address: String,
+    nonce: u64,
+    state: State<NodeState>,
+) -> Result<Json<ResponseApi>, Box<dyn std::error::Error>> {
+    let status = match state.mempool.lock().unwrap().status(&address, nonce) {
+        Some(TxStatus::Pending) => "pending".to_string(),
+        Some(TxStatus::Submitted) => "submitted".to_string(),
+        Some(TxStatus::Confirmed { block, .. }) => format!("confirmed in block {}", block),
+        None => {
+            return Ok(error_response(format!(
+                "unknown nonce {} of {}",
+                nonce, address
+            )))
         }
-        let user: User = USER.as_ref().unwrap().clone();
+    };
 
-        let user_balance = user.user_balance(&ORACLE.as_mut().unwrap()).to_string();
+    Ok(Json(ResponseApi {
+        res: status,
+        res_status: "Success".to_string(),
+    }))
+}
 
-        let response: ResponseApi = ResponseApi {
-            res: user_balance,
-            res_status: "Success".to_string(),
-        };
+#[get("/state_root")]
+fn state_root(state: State<NodeState>) -> Result<Json<ResponseApi>, Box<dyn std::error::Error>> {
+    let root = MerkleTree::from_oracle(&state.oracle.read()).root();
 
-        Ok(Json(response))
-    }
+    Ok(Json(ResponseApi {
+        res: format!("{:?}", root),
+        res_status: "Success".to_string(),
+    }))
+}
+
+#[get("/state_proof/<address>")]
+fn state_proof(
+    address: String,
+    state: State<NodeState>,
+) -> Result<Json<ResponseApi>, Box<dyn std::error::Error>> {
+    let tree = MerkleTree::from_oracle(&state.oracle.read());
+    let proof = match tree.proof(&address) {
+        Some(proof) => proof,
+        None => return Ok(error_response("Unknown address")),
+    };
+
+    Ok(Json(ResponseApi {
+        res: serde_json::to_string(&proof)?,
+        res_status: "Success".to_string(),
+    }))
 }
 
 fn make_cors() -> rocket_cors::Cors {
@@ -286,6 +657,68 @@ fn make_cors() -> rocket_cors::Cors {
 }
 
 fn main() {
+    // the wallet holds the signers' keys, it has no password to fall back on
+    let wallet_password = env::var("FHE_WALLET_PASSWORD").expect("FHE_WALLET_PASSWORD must be set");
+    let store = OracleStore::new(DEFAULT_STORE_PATH);
+    let parameters = node_parameters().expect("Failed to build the BFV parameters");
+    let mut oracle = store
+        .load_or_new(parameters)
+        .expect("Failed to load the Oracle state");
+    oracle.require_attestation = true;
+    oracle.require_signatures = true;
+    oracle.signing_domain = SigningDomain {
+        chain_id: match env::var("FHE_CHAIN_ID") {
+            Ok(chain_id) => chain_id.parse().expect("FHE_CHAIN_ID must be a number"),
+            Err(_) => ANVIL_CHAIN_ID,
+        },
+        contract: get_deployed_address()
+            .parse()
+            .expect("The deployed contract address is malformed"),
+    };
+    if let Ok(auditor_pk) = env::var("FHE_AUDITOR_PK") {
+        oracle.register_auditor(
+            decode_pk(&oracle.parameters, &auditor_pk)
+                .expect("FHE_AUDITOR_PK is not a public key under the node parameters"),
+        );
+    }
+
+    // the chain is the record, the snapshot only saves replaying all of it
+    let skipped = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(catch_up(&mut oracle, URL, get_deployed_address()))
+        .expect("Failed to catch up with the chain");
+    print_skipped(&skipped);
+    store
+        .save(&oracle)
+        .expect("Failed to save the Oracle state");
+
+    let mut rng = FheRng::from_env().expect("FHE_RNG_SEED must be a number");
+    if rng.is_seeded() {
+        println!("FHE_RNG_SEED is set, keys and ciphertexts are deterministic");
+    }
+    let wallet_dir = env::var("FHE_WALLET_DIR").unwrap_or(DEFAULT_WALLET_DIR.to_string());
+    let mut wallet = Wallet::open(&wallet_dir, &wallet_password, &oracle, &mut rng)
+        .expect("Failed to open the wallet");
+
+    // the key deposits are signed with, unless the wallet already holds it
+    let signer = match KeySource::from_env(SIGNER_ENV).expect("Invalid signer settings") {
+        Some(source) => source.load().expect("Failed to load the signer key"),
+        // Anvil's accounts are public, they only ever sign on a dev chain
+        None if env::var("FHE_DEV_ANVIL_SIGNER").is_ok() => {
+            println!("FHE_DEV_ANVIL_SIGNER is set, signing with Anvil's second account");
+            KeySource::anvil(1).load().unwrap()
+        }
+        None => panic!(
+            "{}_* must be set, or FHE_DEV_ANVIL_SIGNER to sign with Anvil's second account",
+            SIGNER_ENV
+        ),
+    };
+    if wallet.get(&to_checksum(&signer.address(), None)).is_none() {
+        wallet
+            .add_account(signer, 0, &oracle, &mut rng)
+            .expect("Failed to add the signer to the wallet");
+    }
+
     rocket::ignite()
         .mount(
             "/",
@@ -294,10 +727,15 @@ fn main() {
                 deposit_funds,
                 send_funds,
                 withdraw_funds,
-                get_balance
+                rotate_key,
+                get_balance,
+                tx_status,
+                state_root,
+                state_proof
             ],
         )
         .attach(make_cors())
+        .manage(NodeState::new(oracle, store, wallet, rng))
         .launch();
 }
 
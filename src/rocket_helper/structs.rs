@@ -13,6 +13,11 @@ pub struct OracleUserApi {
     pub der_key: String,
     pub fhe_pk: String,
     pub fhe_balance: String,
+    #[serde(default)]
+    pub asset: usize,
+    /// Which FHE account of the sender's wallet the deposit goes to.
+    #[serde(default)]
+    pub fhe_account: u32,
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -39,7 +44,6 @@ pub struct SendFundsApi {
 #[derive(Deserialize, Serialize, Clone)]
 pub struct WithdrawFundsApi {
     pub amount: String,
-    pub fhe_sk: String,
-    pub fhe_pk_new: String,
-    pub fhe_balance_new: String,
+    pub fhe_tx_sender: String,
+    pub fhe_proof: String,
 }
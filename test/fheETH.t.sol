@@ -70,25 +70,71 @@ contract fheETHTest is Test {
         string memory fhe_tx_sender = "alice";
         string memory fhe_tx_receiver = "bob";
         string memory fhe_proof = "proof";
+        bytes memory fhe_signature = "";
 
         vm.prank(alice);
 
         (bool sent, ) = address(fheToken).call{value: FEE}(
             abi.encodeWithSignature(
-                "send_fhe_tx(string,string,string)",
+                "send_fhe_tx(address,string,string,string,uint256,bytes)",
+                bob,
                 fhe_tx_sender,
                 fhe_tx_receiver,
-                fhe_proof
+                fhe_proof,
+                0,
+                fhe_signature
             )
         );
 
         assertEq(sent, true);
     }
 
+    function test_rotate_fhe_key() public {
+        string memory fhe_pk = "alice_new_pk";
+        string memory fhe_tx_sender = "alice_debit";
+        string memory fhe_balance = "alice_new_balance";
+        string memory fhe_proof = "alice_proof";
+        bytes memory fhe_signature = "";
+
+        vm.prank(alice);
+        (bool sent, ) = address(fheToken).call{value: FEE}(
+            abi.encodeWithSignature(
+                "rotate_fhe_key(string,string,string,string,uint256,bytes)",
+                fhe_pk,
+                fhe_tx_sender,
+                fhe_balance,
+                fhe_proof,
+                0,
+                fhe_signature
+            )
+        );
+
+        assertEq(sent, true);
+
+        // only users can rotate
+        deal(mallory, FEE);
+        vm.prank(mallory);
+        (sent, ) = address(fheToken).call{value: FEE}(
+            abi.encodeWithSignature(
+                "rotate_fhe_key(string,string,string,string,uint256,bytes)",
+                fhe_pk,
+                fhe_tx_sender,
+                fhe_balance,
+                fhe_proof,
+                0,
+                fhe_signature
+            )
+        );
+
+        assertEq(sent, false);
+    }
+
     function test_withdraw_ETH() public {
-        string memory fhe_sk_old = "alice_sk";
-        string memory fhe_pk_new = "alice_pk";
-        string memory fhe_new_balance = "alice_balance";
+        string memory fhe_tx_sender = "alice_debit";
+        string memory fhe_proof = "alice_proof";
+        bytes32 fhe_tx_hash = keccak256(
+            abi.encodePacked(alice, fhe_tx_sender, block.number)
+        );
 
         uint256 aliceBalance = address(alice).balance;
 
@@ -96,11 +142,10 @@ contract fheETHTest is Test {
         vm.prank(alice);
         (bool sent, ) = address(fheToken).call{value: FEE}(
             abi.encodeWithSignature(
-                "withdraw_ETH_request(uint256,string,string,string)",
+                "withdraw_ETH_request(uint256,string,string)",
                 FEE,
-                fhe_pk_new,
-                fhe_sk_old,
-                fhe_new_balance
+                fhe_tx_sender,
+                fhe_proof
             )
         );
 
@@ -110,11 +155,10 @@ contract fheETHTest is Test {
         // Approve the withdrawal as the owner
         (sent, ) = address(fheToken).call(
             abi.encodeWithSignature(
-                "withdraw_ETH_approved(address,uint256,string,string)",
+                "withdraw_ETH_approved(address,uint256,bytes32)",
                 alice,
                 FEE,
-                fhe_pk_new,
-                fhe_new_balance
+                fhe_tx_hash
             )
         );
 